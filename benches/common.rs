@@ -17,14 +17,14 @@ pub fn run(storage: &mut impl Storage, ops: impl IntoIterator<Item = Op>) {
 
     let mut iter = |op| match op {
         Op::Write(name, payload) => {
-            storage.push(&name, payload);
+            storage.push(&name, payload).unwrap();
         }
         Op::Read(name) => {
             let name = Rc::new(name);
 
             let batch = batches
                 .entry(name.clone())
-                .or_insert_with(|| storage.batch(&name, 100));
+                .or_insert_with(|| storage.batch(&name, 100).unwrap());
 
             let inflight = inflights.entry(name.clone()).or_default();
 
@@ -44,7 +44,7 @@ pub fn run(storage: &mut impl Storage, ops: impl IntoIterator<Item = Op>) {
 
             let inflight = inflights.entry(name.clone()).or_default();
             if let Some(key) = inflight.pop() {
-                storage.remove(&name, key);
+                storage.remove(&name, key).unwrap();
             }
         }
     };
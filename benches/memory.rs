@@ -13,7 +13,7 @@ criterion_main!(basic);
 fn random(c: &mut Criterion) {
     c.bench_function("memory", |b| {
         b.iter_custom(|iters| {
-            let mut storage = Memory::new("q", 10);
+            let mut storage = Memory::tree("q", 10);
             let start = Instant::now();
             run(&mut storage, ops(iters, "q", 10));
             start.elapsed()
@@ -14,7 +14,7 @@ criterion_main!(basic);
 fn random(c: &mut Criterion) {
     c.bench_function("sled", |b| {
         b.iter_custom(|iters| {
-            let mut storage = Sled::new(TempDir::new().unwrap(), "q", 10);
+            let mut storage = Sled::new(TempDir::new().unwrap(), "q", 10).unwrap();
             let start = Instant::now();
             run(&mut storage, ops(iters, "q", 10));
             start.elapsed()
@@ -1,51 +1,139 @@
-use std::{collections::HashMap, num::NonZeroU16, ops::Add, sync::Arc, time::Duration};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    num::NonZeroU16,
+    ops::Add,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
-use futures::{future, try_join};
+use futures::{
+    future::{self, Either},
+    try_join, FutureExt,
+};
 use rand::{distributions::Standard, prelude::ThreadRng, Rng};
 use tokio::{
-    sync::oneshot::{self, error::TryRecvError, Receiver},
+    sync::{
+        oneshot::{self, error::TryRecvError, Receiver},
+        watch,
+    },
+    task::JoinHandle,
     time,
 };
 
-use crate::Storage;
+use crate::{
+    async_storage, lease, AsyncStorage, Payload, Properties, PushOptions, QoS, RetainedStore,
+    Storage, StorageError, StoredMessage, SubscriptionOptions, SubscriptionStore,
+};
+
+// size of the simulated device fleet publishing telemetry
+const DEVICES: u32 = 10_000;
 
 thread_local! {
     static RNG : std::cell::RefCell<ThreadRng> = std::cell::RefCell::new(rand::thread_rng());
 }
 
-pub async fn run<S>(
-    storage: S,
-    secs: u64,
-    parallel: NonZeroU16,
-) -> Result<(IngressStats, EgressStats)>
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub secs: u64,
+    pub parallel: NonZeroU16,
+    pub priorities: u16,
+    // messages each ingress worker stores per `push_many`, 1 pushes them one
+    // by one
+    pub batch: usize,
+    // keys each egress worker acknowledges per `remove_many`, 1 removes them
+    // one by one
+    pub acks: usize,
+    // egress checks messages out with this lease instead of tracking
+    // inflight keys itself
+    pub lease: Option<Duration>,
+    // egress parks on drained queues until a push signals them instead of
+    // polling them again right away
+    pub wait: bool,
+}
+
+pub async fn run<S>(storage: S, workload: Workload) -> Result<(IngressStats, EgressStats)>
 where
-    S: Storage + Send + Sync + 'static,
+    S: AsyncStorage,
+{
+    let (priorities, batch) = (workload.priorities, workload.batch);
+    drive(Arc::new(storage), workload, move |storage, rx| {
+        tokio::spawn(ingress(storage, rx, priorities, batch))
+    })
+    .await
+}
+
+// clients subscribe to a share of the device fleet each, so every publish
+// fans in to one of them, and every fourth one to battery readings of all
+// devices on top, so those fan out; ingress pushes each message into the
+// queues its topic routes to
+pub async fn routed<S>(storage: S, workload: Workload) -> Result<(IngressStats, EgressStats)>
+where
+    S: AsyncStorage,
+    S::Storage: SubscriptionStore,
 {
     let storage = Arc::new(storage);
+    let names = storage.names().await?;
+    storage
+        .execute(move |storage| subscribe_fleet(storage, &names))
+        .await?;
+
+    let priorities = workload.priorities;
+    drive(storage, workload, move |storage, rx| {
+        tokio::spawn(routed_ingress(storage, rx, priorities))
+    })
+    .await
+}
+
+async fn drive<S, F>(
+    storage: Arc<S>,
+    workload: Workload,
+    spawn_ingress: F,
+) -> Result<(IngressStats, EgressStats)>
+where
+    S: AsyncStorage,
+    F: Fn(Arc<S>, Receiver<()>) -> JoinHandle<Result<IngressStats, StorageError>>,
+{
+    let Workload {
+        secs,
+        parallel,
+        acks,
+        lease,
+        wait,
+        ..
+    } = workload;
 
     let (ingress_send, ingress): (Vec<_>, Vec<_>) = (0..parallel.get())
         .map(|_| {
             let (tx, rx) = oneshot::channel();
-            let join = tokio::spawn(ingress(storage.clone(), rx));
-            (tx, join)
+            (tx, spawn_ingress(storage.clone(), rx))
         })
         .unzip();
 
     let (egress_send, egress): (Vec<_>, Vec<_>) = (0..parallel.get())
         .map(|_| {
             let (tx, rx) = oneshot::channel();
-            let join = tokio::spawn(egress(storage.clone(), rx));
+            let join = match lease {
+                Some(lease) => tokio::spawn(leased_egress(storage.clone(), rx, lease, acks, wait)),
+                None => tokio::spawn(egress(storage.clone(), rx, acks, wait)),
+            };
             (tx, join)
         })
         .unzip();
 
     time::sleep(Duration::from_secs(secs)).await;
 
-    ingress_send.into_iter().for_each(|tx| tx.send(()).unwrap());
-    egress_send.into_iter().for_each(|tx| tx.send(()).unwrap());
+    // a worker that failed has already dropped its receiver
+    ingress_send.into_iter().for_each(|tx| {
+        let _ = tx.send(());
+    });
+    egress_send.into_iter().for_each(|tx| {
+        let _ = tx.send(());
+    });
 
     let (ingress, egress) = try_join!(future::try_join_all(ingress), future::try_join_all(egress))?;
+    let ingress = ingress.into_iter().collect::<Result<Vec<_>, _>>()?;
+    let egress = egress.into_iter().collect::<Result<Vec<_>, _>>()?;
 
     Ok((
         ingress.into_iter().fold(IngressStats::default(), Add::add),
@@ -53,43 +141,326 @@ where
     ))
 }
 
-async fn ingress<S>(storage: Arc<S>, mut ingress_recv: Receiver<()>) -> IngressStats
+// a broker restoring retained messages for new subscriptions: writers keep
+// replacing the retained message of device topics while readers look them up
+// by filter; only the duration and parallelism of the workload apply
+pub async fn retained<S>(storage: S, workload: Workload) -> Result<RetainedStats>
+where
+    S: AsyncStorage,
+    S::Storage: RetainedStore,
+{
+    let storage = Arc::new(storage);
+
+    let (stop, workers): (Vec<_>, Vec<_>) = (0..workload.parallel.get())
+        .flat_map(|_| {
+            let (writer_tx, writer_rx) = oneshot::channel();
+            let (reader_tx, reader_rx) = oneshot::channel();
+            let writer = tokio::spawn(retained_writer(storage.clone(), writer_rx));
+            let reader = tokio::spawn(retained_reader(storage.clone(), reader_rx));
+            vec![(writer_tx, writer), (reader_tx, reader)]
+        })
+        .unzip();
+
+    time::sleep(Duration::from_secs(workload.secs)).await;
+
+    stop.into_iter().for_each(|tx| {
+        let _ = tx.send(());
+    });
+
+    let stats = future::try_join_all(workers).await?;
+    let stats = stats.into_iter().collect::<Result<Vec<_>, _>>()?;
+    Ok(stats.into_iter().fold(RetainedStats::default(), Add::add))
+}
+
+async fn retained_writer<S>(
+    storage: Arc<S>,
+    mut stop: Receiver<()>,
+) -> Result<RetainedStats, StorageError>
+where
+    S: AsyncStorage,
+    S::Storage: RetainedStore,
+{
+    let mut stats = RetainedStats::default();
+
+    while let Err(TryRecvError::Empty) = stop.try_recv() {
+        let mut message = random_message();
+        message.retain = true;
+        // a few publishes clear the retained message of their topic
+        if RNG.with(|rng| rng.borrow_mut().gen_bool(0.02)) {
+            message.payload = Payload::new();
+        }
+
+        stats.sets += 1;
+        stats.total_bytes += message.encoded_len() as u64;
+        storage
+            .execute(move |storage| storage.set_retained(&message))
+            .await?;
+
+        if stats.sets % 100 == 0 {
+            async_storage::yield_now().await;
+        }
+    }
+
+    Ok(stats)
+}
+
+// most subscriptions are for a single device, a few span the whole fleet
+async fn retained_reader<S>(
+    storage: Arc<S>,
+    mut stop: Receiver<()>,
+) -> Result<RetainedStats, StorageError>
 where
-    S: Storage + Send,
+    S: AsyncStorage,
+    S::Storage: RetainedStore,
 {
-    let names = storage.names();
+    const SENSORS: [&str; 4] = ["temperature", "humidity", "pressure", "battery"];
+
+    let mut stats = RetainedStats::default();
+
+    while let Err(TryRecvError::Empty) = stop.try_recv() {
+        let filter = RNG.with(|rng| {
+            let mut rng = rng.borrow_mut();
+            let device = rng.gen_range(0, DEVICES);
+            let sensor = SENSORS[rng.gen_range(0, SENSORS.len())];
+            match rng.gen_range(0, 100) {
+                0 => format!("devices/+/telemetry/{}", sensor),
+                1..=29 => format!("devices/{}/telemetry/{}", device, sensor),
+                _ => format!("devices/{}/#", device),
+            }
+        });
+
+        let messages = storage
+            .execute(move |storage| storage.matching(&filter))
+            .await?;
+        stats.lookups += 1;
+        stats.matched += messages.len() as u64;
 
+        if stats.lookups % 100 == 0 {
+            async_storage::yield_now().await;
+        }
+    }
+
+    Ok(stats)
+}
+
+fn subscribe_fleet<S>(storage: &S, names: &[String]) -> Result<(), StorageError>
+where
+    S: SubscriptionStore,
+{
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    for device in 0..DEVICES {
+        let name = &names[device as usize % names.len()];
+        let options = SubscriptionOptions {
+            qos: QoS::AtLeastOnce,
+            ..SubscriptionOptions::default()
+        };
+        storage.add_subscription(name, &format!("devices/{}/#", device), options)?;
+    }
+    for name in names.iter().step_by(4) {
+        let filter = "devices/+/telemetry/battery";
+        storage.add_subscription(name, filter, SubscriptionOptions::default())?;
+    }
+
+    Ok(())
+}
+
+async fn routed_ingress<S>(
+    storage: Arc<S>,
+    mut ingress_recv: Receiver<()>,
+    priorities: u16,
+) -> Result<IngressStats, StorageError>
+where
+    S: AsyncStorage,
+    S::Storage: SubscriptionStore,
+{
     let mut stats = IngressStats::default();
 
     while let Err(TryRecvError::Empty) = ingress_recv.try_recv() {
-        let size = RNG.with(|rng| rng.borrow_mut().gen_range(0, 100));
-        let payload = RNG.with(|rng| {
-            rng.borrow_mut()
-                .sample_iter::<u8, Standard>(Standard)
-                .take(size)
-                .collect()
-        });
+        let message = random_message();
+        let size = message.encoded_len();
+
+        // routing only reads the in-memory trie, it never blocks
+        let routes = storage.storage().route(&message.topic);
+        let priority = random_priority(priorities);
+        let pushed = match &routes[..] {
+            [] => continue,
+            [(name, _)] => {
+                let options = PushOptions {
+                    priority,
+                    ..PushOptions::default()
+                };
+                storage.push_message(name, message, options).await.map(drop)
+            }
+            // a fanned out message lands in all of its queues or none
+            routes => {
+                let bytes = message.to_bytes()?;
+                let items = routes
+                    .iter()
+                    .map(|(name, _)| (name.clone(), priority, bytes.clone()))
+                    .collect();
+                storage.push_many(items).await.map(drop)
+            }
+        };
+
+        match pushed {
+            Ok(()) => {
+                stats.total_items += routes.len() as u64;
+                stats.total_bytes += (size * routes.len()) as u64;
+            }
+            Err(StorageError::Full) => stats.full += 1,
+            Err(e) => return Err(e),
+        }
 
-        let name = RNG.with(|rng| rng.borrow_mut().gen_range(0, names.len()));
-        let name = &names[name];
-        storage.push(name, payload);
+        if stats.total_bytes % 1000 == 0 {
+            async_storage::yield_now().await;
+        }
+    }
+
+    Ok(stats)
+}
 
-        stats.total_items += 1;
-        stats.total_bytes += size as u64;
+async fn ingress<S>(
+    storage: Arc<S>,
+    mut ingress_recv: Receiver<()>,
+    priorities: u16,
+    batch: usize,
+) -> Result<IngressStats, StorageError>
+where
+    S: AsyncStorage,
+{
+    let names = storage.names().await?;
+
+    let mut stats = IngressStats::default();
+
+    while let Err(TryRecvError::Empty) = ingress_recv.try_recv() {
+        if batch > 1 {
+            let items = (0..batch)
+                .map(|_| {
+                    let payload = random_message().to_bytes()?;
+                    Ok((
+                        random_name(&names).clone(),
+                        random_priority(priorities),
+                        payload,
+                    ))
+                })
+                .collect::<Result<Vec<_>, StorageError>>()?;
+            let size: usize = items.iter().map(|(_, _, payload)| payload.len()).sum();
+
+            // a push that timed out on the storage budget is counted and
+            // given up, the worker goes on with the next one
+            match storage.push_many(items).await {
+                Ok(_) => {
+                    stats.total_items += batch as u64;
+                    stats.total_bytes += size as u64;
+                }
+                Err(StorageError::Full) => stats.full += 1,
+                Err(e) => return Err(e),
+            }
+
+            // a batch is a coarse unit of work, so other workers get a turn
+            // after each one
+            async_storage::yield_now().await;
+            continue;
+        }
+
+        let message = random_message();
+        let size = message.encoded_len();
+
+        let options = PushOptions {
+            priority: random_priority(priorities),
+            ..PushOptions::default()
+        };
+        match storage
+            .push_message(random_name(&names), message, options)
+            .await
+        {
+            Ok(_) => {
+                stats.total_items += 1;
+                stats.total_bytes += size as u64;
+            }
+            Err(StorageError::Full) => stats.full += 1,
+            Err(e) => return Err(e),
+        }
 
         if stats.total_bytes % 1000 == 0 {
-            tokio::task::yield_now().await;
+            async_storage::yield_now().await;
         }
     }
 
-    stats
+    Ok(stats)
+}
+
+// publishes of a fleet of devices reporting sensor readings, some with the
+// MQTT 5 properties a request/response or JSON payload would carry
+fn random_message() -> StoredMessage {
+    const SENSORS: [&str; 4] = ["temperature", "humidity", "pressure", "battery"];
+
+    let payload = random_payload();
+    RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        let device = rng.gen_range(0, DEVICES);
+        let sensor = SENSORS[rng.gen_range(0, SENSORS.len())];
+
+        let mut properties = Properties::default();
+        if rng.gen_bool(0.5) {
+            properties.payload_format_indicator = Some(1);
+            properties.content_type = Some("application/json".into());
+        }
+        if rng.gen_bool(0.1) {
+            properties.response_topic = Some(format!("devices/{}/responses", device));
+            properties.correlation_data = Some(rng.gen::<u128>().to_be_bytes().to_vec().into());
+        }
+        if rng.gen_bool(0.2) {
+            properties.user_properties = vec![("firmware".into(), "2.4.1".into())];
+        }
+
+        StoredMessage {
+            topic: format!("devices/{}/telemetry/{}", device, sensor),
+            qos: match rng.gen_range(0, 3) {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => QoS::ExactlyOnce,
+            },
+            retain: rng.gen_bool(0.05),
+            properties,
+            received_at: lease::now(),
+            payload,
+        }
+    })
+}
+
+fn random_payload() -> Payload {
+    let size = RNG.with(|rng| rng.borrow_mut().gen_range(0, 100));
+    RNG.with(|rng| {
+        rng.borrow_mut()
+            .sample_iter::<u8, Standard>(Standard)
+            .take(size)
+            .collect()
+    })
+}
+
+fn random_priority(priorities: u16) -> u16 {
+    RNG.with(|rng| rng.borrow_mut().gen_range(0, priorities.max(1)))
 }
 
-async fn egress<S>(storage: Arc<S>, mut egress_recv: Receiver<()>) -> EgressStats
+fn random_name(names: &[String]) -> &String {
+    let name = RNG.with(|rng| rng.borrow_mut().gen_range(0, names.len()));
+    &names[name]
+}
+
+async fn egress<S>(
+    storage: Arc<S>,
+    mut egress_recv: Receiver<()>,
+    acks: usize,
+    wait: bool,
+) -> Result<EgressStats, StorageError>
 where
-    S: Storage + Send,
+    S: AsyncStorage,
 {
-    let names = storage.names();
+    let mut ready = storage.subscribe_ready();
 
     let mut batches = HashMap::new();
     let mut inflights = HashMap::new();
@@ -99,22 +470,36 @@ where
     while let Err(TryRecvError::Empty) = egress_recv.try_recv() {
         stats.loop_iter += 1;
 
-        let name = RNG.with(|rng| rng.borrow_mut().gen_range(0, names.len()));
-        let name = Arc::new(format!("q{}", name));
-
-        let batch = batches
-            .entry(name.clone())
-            .or_insert_with(|| storage.batch(&name, 100));
-
-        if batch.is_empty() {
+        // only queues with pending messages are visited, nothing ready counts
+        // as an empty iteration
+        let name = match storage.next_ready().await? {
+            Some(name) => Arc::new(name),
+            None => {
+                stats.empty += 1;
+                if !idle(storage.as_ref(), &mut ready, &mut egress_recv, wait).await {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let batch = match batches.entry(name.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(storage.batch_messages(&name, 100).await?),
+        };
+
+        // nothing to read leaves `total_bytes` as is, so yield explicitly to
+        // let ingress catch up instead of spinning on drained queues
+        let empty = batch.is_empty();
+        if empty {
             stats.empty += 1;
         }
 
         let inflight = inflights.entry(name.clone()).or_insert_with(Vec::default);
 
-        if let Some((k, v)) = batch.pop_front() {
+        if let Some((k, message)) = batch.pop_front() {
             stats.total_items += 1;
-            stats.total_bytes += v.len() as u64;
+            stats.total_bytes += message.encoded_len() as u64;
 
             let index = RNG.with(|rng| rng.borrow_mut().gen_range(0, inflight.len() + 1));
             inflight.insert(index, k);
@@ -124,16 +509,113 @@ where
             batches.remove(&name);
         }
 
-        if let Some(key) = inflight.pop() {
-            storage.remove(&name, key);
+        if acks > 1 {
+            // pending acks are flushed before the queue is read again,
+            // otherwise the next batch would deliver them once more
+            if inflight.len() >= acks || (!batches.contains_key(&name) && !inflight.is_empty()) {
+                let keys = std::mem::take(inflight);
+                storage.remove_many(&name, keys).await?;
+            }
+        } else if let Some(key) = inflight.pop() {
+            storage.remove(&name, key).await?;
         }
 
-        if stats.total_bytes % 1000 == 0 {
-            tokio::task::yield_now().await;
+        if empty || stats.total_bytes % 1000 == 0 {
+            async_storage::yield_now().await;
         }
     }
 
-    stats
+    Ok(stats)
+}
+
+// inflight messages are tracked by the storage, so every checkout can be
+// acknowledged right away in any order
+async fn leased_egress<S>(
+    storage: Arc<S>,
+    mut egress_recv: Receiver<()>,
+    lease: Duration,
+    acks: usize,
+    wait: bool,
+) -> Result<EgressStats, StorageError>
+where
+    S: AsyncStorage,
+{
+    let mut ready = storage.subscribe_ready();
+
+    let mut stats = EgressStats::default();
+
+    while let Err(TryRecvError::Empty) = egress_recv.try_recv() {
+        stats.loop_iter += 1;
+
+        let name = match storage.next_ready().await? {
+            Some(name) => name,
+            None => {
+                stats.empty += 1;
+                if !idle(storage.as_ref(), &mut ready, &mut egress_recv, wait).await {
+                    break;
+                }
+                continue;
+            }
+        };
+        let batch = storage.checkout_messages(&name, 100, lease).await?;
+
+        // everything left in the queue is leased by other workers
+        if batch.is_empty() {
+            stats.empty += 1;
+        }
+
+        let mut keys = Vec::with_capacity(batch.len());
+        for (key, message) in batch {
+            stats.total_items += 1;
+            stats.total_bytes += message.encoded_len() as u64;
+            keys.push(key);
+        }
+
+        if acks > 1 {
+            for keys in keys.chunks(acks) {
+                storage.remove_many(&name, keys.to_vec()).await?;
+            }
+        } else {
+            for key in keys {
+                storage.remove(&name, key).await?;
+            }
+        }
+
+        async_storage::yield_now().await;
+    }
+
+    Ok(stats)
+}
+
+// parks an egress worker with nothing ready until a queue joins the ready set
+// or the leases of a parked one run out, or just lets ingress catch up without
+// `wait`; false means it was told to stop instead and must not poll the
+// receiver again
+async fn idle<S>(
+    storage: &S,
+    ready: &mut watch::Receiver<()>,
+    egress_recv: &mut Receiver<()>,
+    wait: bool,
+) -> bool
+where
+    S: AsyncStorage,
+{
+    if !wait {
+        async_storage::yield_now().await;
+        return true;
+    }
+
+    let joined = ready.changed().map(|_| ()).boxed();
+    let unparked = match storage.storage().ready().parked_until() {
+        Some(until) => {
+            time::sleep(Duration::from_millis(until.saturating_sub(lease::now()))).boxed()
+        }
+        None => future::pending().boxed(),
+    };
+    match future::select(egress_recv, future::select(joined, unparked)).await {
+        Either::Left(_) => false,
+        Either::Right(_) => true,
+    }
 }
 
 #[derive(Debug, Default)]
@@ -161,6 +643,8 @@ impl Add<Self> for EgressStats {
 pub struct IngressStats {
     pub total_bytes: u64,
     pub total_items: u64,
+    // pushes that timed out waiting for the storage budget
+    pub full: u64,
 }
 
 impl Add<Self> for IngressStats {
@@ -170,6 +654,29 @@ impl Add<Self> for IngressStats {
         Self {
             total_bytes: self.total_bytes + rhs.total_bytes,
             total_items: self.total_items + rhs.total_items,
+            full: self.full + rhs.full,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RetainedStats {
+    pub sets: u64,
+    pub total_bytes: u64,
+    pub lookups: u64,
+    // retained messages returned by all lookups together
+    pub matched: u64,
+}
+
+impl Add<Self> for RetainedStats {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            sets: self.sets + rhs.sets,
+            total_bytes: self.total_bytes + rhs.total_bytes,
+            lookups: self.lookups + rhs.lookups,
+            matched: self.matched + rhs.matched,
         }
     }
 }
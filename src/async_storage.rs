@@ -0,0 +1,572 @@
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use tokio::{
+    sync::{oneshot, watch},
+    time::{self, Instant},
+};
+
+use crate::{Delivered, Key, Payload, PushOptions, Pushed, Storage, StorageError, StoredMessage};
+
+pub trait AsyncStorage: Send + Sync + 'static {
+    type Storage: Storage + Send + Sync + 'static;
+
+    // an operation that panics fails with `StorageError::Backend`
+    fn execute<F, R>(&self, f: F) -> BoxFuture<'static, Result<R, StorageError>>
+    where
+        F: FnOnce(&Self::Storage) -> Result<R, StorageError> + Send + 'static,
+        R: Send + 'static;
+
+    // only for state that is safe to touch from the executor, like the ready
+    // set and the budget; operations go through `execute`
+    fn storage(&self) -> &Self::Storage;
+
+    fn names(&self) -> BoxFuture<'static, Result<Vec<String>, StorageError>> {
+        self.execute(|storage| Ok(storage.names()))
+    }
+
+    fn next_ready(&self) -> BoxFuture<'static, Result<Option<String>, StorageError>> {
+        self.execute(|storage| storage.next_ready())
+    }
+
+    // see `ReadySet::subscribe`
+    fn subscribe_ready(&self) -> watch::Receiver<()> {
+        self.storage().ready().subscribe()
+    }
+
+    // pushes into a storage whose budget is exhausted wait for space to be
+    // freed, see `Budget`
+    fn push<'a>(
+        &'a self,
+        name: &str,
+        payload: Payload,
+    ) -> BoxFuture<'a, Result<Key, StorageError>> {
+        let name = name.to_string();
+        self.with_backpressure(move || {
+            let (name, payload) = (name.clone(), payload.clone());
+            self.execute(move |storage| storage.push(&name, payload))
+        })
+    }
+
+    fn push_with_priority<'a>(
+        &'a self,
+        name: &str,
+        priority: u16,
+        payload: Payload,
+    ) -> BoxFuture<'a, Result<Key, StorageError>> {
+        let name = name.to_string();
+        self.with_backpressure(move || {
+            let (name, payload) = (name.clone(), payload.clone());
+            self.execute(move |storage| storage.push_with_priority(&name, priority, payload))
+        })
+    }
+
+    fn push_with<'a>(
+        &'a self,
+        name: &str,
+        payload: Payload,
+        options: PushOptions,
+    ) -> BoxFuture<'a, Result<Pushed, StorageError>> {
+        let name = name.to_string();
+        self.with_backpressure(move || {
+            let (name, payload) = (name.clone(), payload.clone());
+            self.execute(move |storage| storage.push_with(&name, payload, options))
+        })
+    }
+
+    fn push_with_expiry<'a>(
+        &'a self,
+        name: &str,
+        payload: Payload,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<Key, StorageError>> {
+        let name = name.to_string();
+        self.with_backpressure(move || {
+            let (name, payload) = (name.clone(), payload.clone());
+            self.execute(move |storage| storage.push_with_expiry(&name, payload, ttl))
+        })
+    }
+
+    fn push_message<'a>(
+        &'a self,
+        name: &str,
+        message: StoredMessage,
+        options: PushOptions,
+    ) -> BoxFuture<'a, Result<Pushed, StorageError>> {
+        let name = name.to_string();
+        self.with_backpressure(move || {
+            let (name, message) = (name.clone(), message.clone());
+            self.execute(move |storage| storage.push_message(&name, &message, options))
+        })
+    }
+
+    // items are a queue, a priority and a payload each
+    fn push_many<'a>(
+        &'a self,
+        items: Vec<(String, u16, Payload)>,
+    ) -> BoxFuture<'a, Result<Vec<Key>, StorageError>> {
+        self.with_backpressure(move || {
+            let items = items.clone();
+            self.execute(move |storage| {
+                let items = items
+                    .iter()
+                    .map(|(name, priority, payload)| (name.as_str(), *priority, payload.clone()))
+                    .collect();
+                storage.push_many_with_priority(items)
+            })
+        })
+    }
+
+    // retries `push` while the budget is exhausted until the budget timeout
+    // runs out; every release wakes one waiting push, which passes the
+    // wakeup on once it got through so that the next one tries as well
+    fn with_backpressure<'a, F, R>(&'a self, push: F) -> BoxFuture<'a, Result<R, StorageError>>
+    where
+        F: Fn() -> BoxFuture<'static, Result<R, StorageError>> + Send + 'a,
+        R: Send + 'static,
+    {
+        async move {
+            let budget = self.storage().budget();
+            let deadline = Instant::now() + budget.timeout();
+            let mut waited = false;
+
+            loop {
+                match push().await {
+                    Err(StorageError::Full) if budget.limit().is_some() => {
+                        if time::timeout_at(deadline, budget.freed()).await.is_err() {
+                            return Err(StorageError::Full);
+                        }
+                        waited = true;
+                    }
+                    pushed => {
+                        if waited && pushed.is_ok() {
+                            budget.pass_on();
+                        }
+                        return pushed;
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn batch(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> BoxFuture<'static, Result<VecDeque<(Key, Payload)>, StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.batch(&name, size))
+    }
+
+    fn batch_messages(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> BoxFuture<'static, Result<VecDeque<(Key, StoredMessage)>, StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.batch_messages(&name, size))
+    }
+
+    fn batch_with_delivery(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> BoxFuture<'static, Result<VecDeque<Delivered>, StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.batch_with_delivery(&name, size))
+    }
+
+    fn mark_delivered(&self, name: &str, key: Key) -> BoxFuture<'static, Result<(), StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.mark_delivered(&name, key))
+    }
+
+    fn batch_after(
+        &self,
+        name: &str,
+        after: Key,
+        size: usize,
+    ) -> BoxFuture<'static, Result<VecDeque<(Key, Payload)>, StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.batch_after(&name, after, size))
+    }
+
+    fn checkout(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> BoxFuture<'static, Result<VecDeque<(Key, Payload)>, StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.checkout(&name, size, lease))
+    }
+
+    fn checkout_messages(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> BoxFuture<'static, Result<VecDeque<(Key, StoredMessage)>, StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.checkout_messages(&name, size, lease))
+    }
+
+    fn remove(&self, name: &str, key: Key) -> BoxFuture<'static, Result<(), StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.remove(&name, key))
+    }
+
+    fn remove_many(
+        &self,
+        name: &str,
+        keys: Vec<Key>,
+    ) -> BoxFuture<'static, Result<(), StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.remove_many(&name, &keys))
+    }
+
+    fn remove_up_to(&self, name: &str, key: Key) -> BoxFuture<'static, Result<(), StorageError>> {
+        let name = name.to_string();
+        self.execute(move |storage| storage.remove_up_to(&name, key))
+    }
+
+    fn move_to(
+        &self,
+        from: &str,
+        key: Key,
+        to: &str,
+    ) -> BoxFuture<'static, Result<Option<Key>, StorageError>> {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.execute(move |storage| storage.move_to(&from, key, &to))
+    }
+
+    fn purge_all_expired(&self) -> BoxFuture<'static, Result<usize, StorageError>> {
+        self.execute(|storage| storage.purge_all_expired())
+    }
+
+    // subscribing before the first read means a push racing with it leaves
+    // a permit behind, so the wakeup cannot be missed
+    fn wait_non_empty<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), StorageError>> {
+        async move {
+            let owned = name.to_string();
+            let notify = self
+                .execute(move |storage| storage.subscribe(&owned))
+                .await?;
+
+            loop {
+                match self.batch(name, 1).await {
+                    Ok(batch) if !batch.is_empty() => {
+                        // a push wakes a single waiter, the queue may hold
+                        // enough for the others as well
+                        notify.notify_one();
+                        return Ok(());
+                    }
+                    Ok(_) => notify.notified().await,
+                    Err(e) => {
+                        // other waiters on a dropped queue get their turn
+                        notify.notify_one();
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+// calls a synchronous storage right on the executor thread
+pub struct Inline<S>(S);
+
+impl<S> Inline<S> {
+    pub fn new(storage: S) -> Self {
+        Self(storage)
+    }
+}
+
+impl<S> AsyncStorage for Inline<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    type Storage = S;
+
+    fn execute<F, R>(&self, f: F) -> BoxFuture<'static, Result<R, StorageError>>
+    where
+        F: FnOnce(&Self::Storage) -> Result<R, StorageError> + Send + 'static,
+        R: Send + 'static,
+    {
+        future::ready(f(&self.0)).boxed()
+    }
+
+    fn storage(&self) -> &Self::Storage {
+        &self.0
+    }
+}
+
+// runs every operation of a synchronous storage on a dedicated thread pool
+pub struct Offload<S> {
+    storage: Arc<S>,
+    pool: Pool,
+}
+
+impl<S> Offload<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    pub fn new(storage: S, threads: usize) -> Self {
+        Self {
+            storage: Arc::new(storage),
+            pool: Pool::new(threads),
+        }
+    }
+}
+
+impl<S> AsyncStorage for Offload<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    type Storage = S;
+
+    fn execute<F, R>(&self, f: F) -> BoxFuture<'static, Result<R, StorageError>>
+    where
+        F: FnOnce(&Self::Storage) -> Result<R, StorageError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let storage = self.storage.clone();
+
+        self.pool.spawn(move || {
+            let _ = tx.send(f(&storage));
+        });
+
+        // the sender is dropped without a result if the operation panicked
+        rx.map(|res| {
+            res.unwrap_or_else(|_| Err(StorageError::Backend("storage operation panicked".into())))
+        })
+        .boxed()
+    }
+
+    fn storage(&self) -> &Self::Storage {
+        &self.storage
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Pool {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl Pool {
+    fn new(threads: usize) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("storage-{}", i))
+                .spawn(move || work(&rx))
+                .expect("cannot spawn storage pool worker");
+        }
+
+        Self {
+            jobs: Mutex::new(tx),
+        }
+    }
+
+    fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        let jobs = self.jobs.lock().unwrap_or_else(PoisonError::into_inner);
+        // workers live as long as the sender, so this cannot fail
+        let _ = jobs.send(Box::new(job));
+    }
+}
+
+// workers exit once the pool and its sender are dropped; a job that panics
+// does not take its worker down
+fn work(jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+        match job {
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+// tokio 0.3 marks `yield_now` as must use, which current compilers apply to
+// the `()` it resolves to
+pub(crate) async fn yield_now() {
+    let _ = tokio::task::yield_now().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Memory;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn offload_runs_storage_operations() {
+        let storage = Offload::new(Memory::tree("q", 1), 2);
+
+        let key = storage
+            .push("q0", Payload::from(vec![1, 2, 3]))
+            .await
+            .unwrap();
+        let batch = storage.batch("q0", 10).await.unwrap();
+        assert_eq!(batch, vec![(key, Payload::from(vec![1, 2, 3]))]);
+
+        storage.remove("q0", key).await.unwrap();
+        assert!(storage.batch("q0", 10).await.unwrap().is_empty());
+        assert!(matches!(
+            storage.push("q1", Payload::from(vec![1])).await,
+            Err(StorageError::UnknownQueue(_))
+        ));
+    }
+
+    // memory storages are wrapped like the others, so their methods never
+    // clash with those of `AsyncStorage`
+    #[tokio::test]
+    async fn inline_runs_memory_storage_operations() {
+        let memory = Memory::tree("q", 1);
+        let key = memory.push("q0", Payload::from(vec![1])).unwrap();
+        memory.remove_up_to("q0", key).unwrap();
+
+        let storage = Inline::new(memory);
+        let key = storage.push("q0", Payload::from(vec![2])).await.unwrap();
+        storage.remove_up_to("q0", key).await.unwrap();
+        assert!(storage.batch("q0", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn offload_reports_panicking_operations() {
+        let storage = Offload::new(Memory::tree("q", 1), 1);
+
+        let panicked = storage
+            .execute(|_| -> Result<(), StorageError> { panic!("broken backend") })
+            .await;
+        assert!(matches!(panicked, Err(StorageError::Backend(_))));
+
+        // the only worker is still there
+        assert_eq!(storage.names().await.unwrap(), vec!["q0".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn wait_non_empty_resolves_after_push() {
+        let storage = Arc::new(Offload::new(Memory::tree("q", 2), 2));
+
+        let waiter = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.wait_non_empty("q0").await })
+        };
+        yield_now().await;
+
+        storage.push("q1", Payload::from(vec![1])).await.unwrap();
+        storage.push("q0", Payload::from(vec![2])).await.unwrap();
+        waiter.await.unwrap().unwrap();
+
+        // already non-empty, nothing to wait for
+        storage.wait_non_empty("q1").await.unwrap();
+        assert!(matches!(
+            storage.wait_non_empty("q2").await,
+            Err(StorageError::UnknownQueue(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn wait_non_empty_wakes_every_waiter() {
+        let storage = Arc::new(Offload::new(Memory::tree("q", 1), 2));
+
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.wait_non_empty("q0").await })
+            })
+            .collect();
+        yield_now().await;
+
+        storage.push("q0", Payload::from(vec![1])).await.unwrap();
+        for waiter in waiters {
+            waiter.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_wake_when_a_queue_gets_ready() {
+        let storage = Arc::new(Offload::new(Memory::tree("q", 2), 2));
+
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let mut ready = storage.subscribe_ready();
+                tokio::spawn(async move { ready.changed().await })
+            })
+            .collect();
+        yield_now().await;
+
+        storage.push("q1", Payload::from(vec![1])).await.unwrap();
+        for waiter in waiters {
+            waiter.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_non_empty_fails_once_queue_is_dropped() {
+        let storage = Arc::new(Offload::new(Memory::tree("q", 1), 2));
+
+        let waiter = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.wait_non_empty("q0").await })
+        };
+        yield_now().await;
+
+        storage
+            .execute(|storage| storage.drop_queue("q0"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            waiter.await.unwrap(),
+            Err(StorageError::UnknownQueue(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn push_waits_for_budget_to_free_up() {
+        let storage = Arc::new(Offload::new(Memory::tree("q", 1), 2));
+        let budget = storage.storage().budget();
+        budget.set_limit(Some(4));
+        budget.set_timeout(Duration::from_millis(50));
+
+        let key = storage.push("q0", Payload::from(vec![1; 3])).await.unwrap();
+        assert!(matches!(
+            storage.push("q0", Payload::from(vec![2; 3])).await,
+            Err(StorageError::Full)
+        ));
+
+        budget.set_timeout(Duration::from_secs(5));
+        let waiter = {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.push("q0", Payload::from(vec![2; 3])).await })
+        };
+        yield_now().await;
+
+        storage.remove("q0", key).await.unwrap();
+        let key = waiter.await.unwrap().unwrap();
+        assert_eq!(
+            storage.batch("q0", 10).await.unwrap(),
+            vec![(key, Payload::from(vec![2; 3]))]
+        );
+        assert_eq!(storage.storage().budget().used(), 3);
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::sync::Notify;
+
+// bytes all queues of a storage may hold together. The budget is soft: a
+// push is admitted as long as it fits into what is left, so concurrent pushes
+// may overshoot it by a message each. A full storage rejects synchronous
+// pushes with `StorageError::Full`, while `AsyncStorage` pushes wait for
+// consumers to free space for up to `timeout`
+#[derive(Debug)]
+pub struct Budget {
+    // `u64::MAX` means unlimited
+    limit: AtomicU64,
+    used: AtomicU64,
+    timeout_ms: AtomicU64,
+    freed: Notify,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            limit: AtomicU64::new(u64::MAX),
+            used: AtomicU64::default(),
+            timeout_ms: AtomicU64::new(1000),
+            freed: Notify::new(),
+        }
+    }
+}
+
+impl Budget {
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit
+            .store(limit.unwrap_or(u64::MAX), Ordering::SeqCst);
+        // a raised limit may let waiting pushes through
+        self.freed.notify_one();
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        match self.limit.load(Ordering::SeqCst) {
+            u64::MAX => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.timeout_ms
+            .store(timeout.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.load(Ordering::SeqCst))
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn admits(&self, bytes: u64) -> bool {
+        self.used().saturating_add(bytes) <= self.limit.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn charge(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub(crate) fn release(&self, bytes: u64) {
+        if bytes > 0 {
+            self.used.fetch_sub(bytes, Ordering::SeqCst);
+            self.freed.notify_one();
+        }
+    }
+
+    // every release wakes a single waiter, which passes the wakeup on if it
+    // still does not fit
+    pub(crate) async fn freed(&self) {
+        self.freed.notified().await
+    }
+
+    pub(crate) fn pass_on(&self) {
+        self.freed.notify_one();
+    }
+}
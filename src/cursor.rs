@@ -0,0 +1,110 @@
+use std::collections::VecDeque;
+
+use crate::{Key, Payload, Storage, StorageError};
+
+// pages forward through a queue with `Storage::batch_after`, so every
+// message is read once no matter how deep the backlog is
+pub struct Cursor<'a, S: ?Sized> {
+    storage: &'a S,
+    name: String,
+    page_size: usize,
+    last: Option<Key>,
+    page: VecDeque<(Key, Payload)>,
+    done: bool,
+}
+
+impl<'a, S> Cursor<'a, S>
+where
+    S: Storage + ?Sized,
+{
+    pub fn new(storage: &'a S, name: &str, page_size: usize) -> Self {
+        Self {
+            storage,
+            name: name.into(),
+            page_size: page_size.max(1),
+            last: None,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    // continues after `key` instead of the head of the queue
+    pub fn after(mut self, key: Key) -> Self {
+        self.last = Some(key);
+        self
+    }
+
+    fn fetch(&self) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        match self.last {
+            Some(last) => self.storage.batch_after(&self.name, last, self.page_size),
+            None => self.storage.batch(&self.name, self.page_size),
+        }
+    }
+}
+
+// messages pushed behind the cursor with a higher priority are not seen, it
+// only ever moves forward in delivery order
+impl<'a, S> Iterator for Cursor<'a, S>
+where
+    S: Storage + ?Sized,
+{
+    type Item = Result<(Key, Payload), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            match self.fetch() {
+                Ok(page) => {
+                    self.done = page.len() < self.page_size;
+                    self.page = page;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        let (key, payload) = self.page.pop_front()?;
+        self.last = Some(key);
+        Some(Ok((key, payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Memory;
+
+    use super::*;
+
+    #[test]
+    fn cursor_pages_through_queue_in_delivery_order() {
+        let storage = Memory::vec("q", 1);
+        for i in 0..10 {
+            storage
+                .push_with_priority("q0", i % 3, Payload::from(vec![i as u8]))
+                .unwrap();
+        }
+
+        let payloads: Vec<_> = storage
+            .cursor("q0", 3)
+            .map(|item| item.unwrap().1[0])
+            .collect();
+        assert_eq!(payloads, vec![2, 5, 8, 1, 4, 7, 0, 3, 6, 9]);
+    }
+
+    #[test]
+    fn cursor_continues_after_removed_key() {
+        let storage = Memory::tree("q", 1);
+        let keys: Vec<_> = (0..5)
+            .map(|i| storage.push("q0", Payload::from(vec![i])).unwrap())
+            .collect();
+        storage.remove_up_to("q0", keys[2]).unwrap();
+
+        let storage: &dyn Storage = &storage;
+        let rest: Vec<_> = Cursor::new(storage, "q0", 2)
+            .after(keys[1])
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(rest, vec![keys[3], keys[4]]);
+    }
+}
@@ -0,0 +1,740 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{self, Read, Write},
+};
+
+use crc32fast::Hasher;
+
+use crate::{
+    lease::Lease, session, Inflight, Key, Limits, MessageState, Payload, Storage, StorageError,
+    StoredMessage, SubscriptionOptions,
+};
+
+// a dump starts with the magic and the format version, every record after
+// it is framed by the length of its body and a crc32 of the body
+const MAGIC: &[u8; 8] = b"MQSDUMP\0";
+const VERSION: u16 = 2;
+
+// retained messages and subscriptions come first, then every queue with its
+// limits, followed by its messages and its inflight window; the last record
+// holds the number of records before it, so a dump cut short is noticed
+const QUEUE: u8 = 0;
+const MESSAGE: u8 = 1;
+const END: u8 = 2;
+const RETAINED: u8 = 3;
+const SUBSCRIPTION: u8 = 4;
+const INFLIGHT: u8 = 5;
+
+// the push time, a byte of flags telling which of the expiry deadline and
+// the lease are set, then both of them, zeroed when unset
+const STATE_LEN: usize = 8 + 1 + 8 + Lease::ENCODED_LEN;
+const EXPIRES: u8 = 1;
+const LEASED: u8 = 2;
+
+// messages are read from the storage a page at a time
+const PAGE_SIZE: usize = 256;
+
+// what an export wrote or an import read. The checksum covers the bodies of
+// all records in dump order, so storages export the same one exactly when
+// they hold the same messages with the same state under the same keys, and
+// the same retained messages, subscriptions and inflight windows
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Summary {
+    pub queues: u64,
+    pub messages: u64,
+    pub inflight: u64,
+    pub retained: u64,
+    pub subscriptions: u64,
+    pub checksum: u32,
+}
+
+impl Summary {
+    fn records(&self) -> u64 {
+        self.queues + self.messages + self.inflight + self.retained + self.subscriptions
+    }
+}
+
+// writes everything a storage holds to `writer`, see `walk` for the order.
+// Only a page of messages is held at a time. Messages keep their push time,
+// expiry and deliveries, expired ones are written like the others
+pub fn export(storage: &dyn Storage, mut writer: impl Write) -> Result<Summary, StorageError> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+
+    let mut digest = Digest::default();
+    let mut body = Vec::new();
+    walk(storage, |record| {
+        body.clear();
+        encode(&record, &mut body)?;
+        digest.add(&record, &body);
+        write_record(&mut writer, &body)
+    })?;
+
+    let summary = digest.finish();
+    let mut end = vec![END];
+    end.extend_from_slice(&summary.records().to_be_bytes());
+    write_record(&mut writer, &end)?;
+    writer.flush()?;
+
+    Ok(summary)
+}
+
+// copies everything from one storage into another like an export imported
+// right away, without writing a dump. Nothing is written to `from`. A target
+// that cannot hold something `from` does, like retained messages in queue
+// files, fails the copy with `StorageError::Unsupported` when it comes up.
+// `copied` is called with the number of messages copied so far
+pub fn copy(
+    from: &dyn Storage,
+    to: &dyn Storage,
+    mut copied: impl FnMut(u64),
+) -> Result<Summary, StorageError> {
+    let mut digest = Digest::default();
+    let mut body = Vec::new();
+    walk(from, |record| {
+        body.clear();
+        encode(&record, &mut body)?;
+        digest.add(&record, &body);
+        let message = matches!(record, Record::Message(..));
+        apply(to, record)?;
+        if message {
+            copied(digest.summary.messages);
+        }
+        Ok(())
+    })?;
+
+    Ok(digest.finish())
+}
+
+// reads a dump written by `export` into `storage` one record at a time.
+// Queues are created with the limits they were exported with and messages
+// keep their keys where the backend can hold them, see `Storage::restore`. A
+// dump that fails a checksum or is cut short is an error, the records before
+// that point stay imported
+pub fn import(storage: &dyn Storage, mut reader: impl Read) -> Result<Summary, StorageError> {
+    let mut header = [0; MAGIC.len() + 2];
+    read_exact(&mut reader, &mut header)?;
+    if header[..MAGIC.len()] != MAGIC[..] {
+        return Err(StorageError::Corruption("not a dump".into()));
+    }
+    let version = u16::from_be_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]);
+    if version != VERSION {
+        return Err(StorageError::Corruption(format!(
+            "unsupported dump version: {}",
+            version
+        )));
+    }
+
+    let mut digest = Digest::default();
+    loop {
+        let body = Payload::from(read_record(&mut reader)?);
+        if body.first() == Some(&END) {
+            let count = match body[1..] {
+                [a, b, c, d, e, f, g, h] => u64::from_be_bytes([a, b, c, d, e, f, g, h]),
+                _ => return Err(invalid("end", body.len())),
+            };
+            let summary = digest.finish();
+            if count != summary.records() {
+                return Err(StorageError::Corruption(format!(
+                    "dump has {} records, its end says {}",
+                    summary.records(),
+                    count
+                )));
+            }
+
+            return Ok(summary);
+        }
+
+        let record = decode(&body)?;
+        digest.add(&record, &body);
+        apply(storage, record)?;
+    }
+}
+
+enum Record<'a> {
+    Retained(StoredMessage),
+    Subscription(&'a str, &'a str, SubscriptionOptions),
+    Queue(&'a str, Limits),
+    Message(&'a str, Key, Payload, MessageState),
+    Inflight(&'a str, u16, Inflight),
+}
+
+// retained messages by topic and subscriptions by client id and filter
+// first, then queues in name order, each followed by its messages in
+// delivery order, read a page at a time, and its inflight window
+fn walk(
+    storage: &dyn Storage,
+    mut f: impl FnMut(Record<'_>) -> Result<(), StorageError>,
+) -> Result<(), StorageError> {
+    for message in storage.retained_messages()? {
+        f(Record::Retained(message))?;
+    }
+    for (client_id, filter, options) in storage.all_subscriptions()? {
+        f(Record::Subscription(&client_id, &filter, options))?;
+    }
+
+    let mut names = storage.names();
+    names.sort();
+    for name in names {
+        f(Record::Queue(&name, storage.limits(&name)?))?;
+
+        let mut page = storage.peek(&name, None, PAGE_SIZE)?;
+        while let Some((last, _, _)) = page.back() {
+            let (last, full) = (*last, page.len() == PAGE_SIZE);
+            for (key, payload, state) in page {
+                f(Record::Message(&name, key, payload, state))?;
+            }
+
+            if !full {
+                break;
+            }
+            page = storage.peek(&name, Some(last), PAGE_SIZE)?;
+        }
+
+        for (packet_id, inflight) in storage.inflight_window(&name)? {
+            f(Record::Inflight(&name, packet_id, inflight))?;
+        }
+    }
+
+    Ok(())
+}
+
+// writes a record into `storage` the way it was read from another one
+fn apply(storage: &dyn Storage, record: Record<'_>) -> Result<(), StorageError> {
+    match record {
+        Record::Retained(message) => storage.restore_retained(&message),
+        Record::Subscription(client_id, filter, options) => {
+            storage.restore_subscription(client_id, filter, options)
+        }
+        Record::Queue(name, limits) => storage.create_queue_with(name, limits),
+        Record::Message(name, key, payload, state) => {
+            storage.restore(name, key, payload, state)?;
+            Ok(())
+        }
+        Record::Inflight(name, packet_id, inflight) => {
+            storage.restore_inflight(name, packet_id, inflight)
+        }
+    }
+}
+
+// counts records by type and hashes their bodies, see `Summary`
+#[derive(Default)]
+struct Digest {
+    summary: Summary,
+    hasher: Hasher,
+}
+
+impl Digest {
+    fn add(&mut self, record: &Record<'_>, body: &[u8]) {
+        let count = match record {
+            Record::Retained(_) => &mut self.summary.retained,
+            Record::Subscription(..) => &mut self.summary.subscriptions,
+            Record::Queue(..) => &mut self.summary.queues,
+            Record::Message(..) => &mut self.summary.messages,
+            Record::Inflight(..) => &mut self.summary.inflight,
+        };
+        *count += 1;
+        self.hasher.update(body);
+    }
+
+    fn finish(self) -> Summary {
+        Summary {
+            checksum: self.hasher.finalize(),
+            ..self.summary
+        }
+    }
+}
+
+fn encode(record: &Record<'_>, body: &mut Vec<u8>) -> Result<(), StorageError> {
+    match record {
+        Record::Retained(message) => {
+            body.push(RETAINED);
+            body.extend_from_slice(&message.to_bytes()?);
+        }
+        Record::Subscription(client_id, filter, options) => {
+            body.push(SUBSCRIPTION);
+            put_str(body, "client id", client_id)?;
+            put_str(body, "topic filter", filter)?;
+            body.push(options.to_byte());
+        }
+        Record::Queue(name, limits) => {
+            body.push(QUEUE);
+            put_str(body, "queue name", name)?;
+            body.extend_from_slice(&limits.to_bytes());
+        }
+        Record::Message(name, key, payload, state) => {
+            body.push(MESSAGE);
+            put_str(body, "queue name", name)?;
+            body.extend_from_slice(&key.to_bytes());
+            put_state(body, state);
+            body.extend_from_slice(payload);
+        }
+        Record::Inflight(name, packet_id, inflight) => {
+            body.push(INFLIGHT);
+            put_str(body, "queue name", name)?;
+            body.extend_from_slice(&packet_id.to_be_bytes());
+            body.extend_from_slice(&inflight.to_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+// payloads and retained messages are slices of `body`, they are not copied
+fn decode(body: &Payload) -> Result<Record<'_>, StorageError> {
+    let (kind, rest) = match body.split_first() {
+        Some((kind, rest)) => (*kind, rest),
+        None => return Err(invalid("empty", 0)),
+    };
+    let what = match kind {
+        RETAINED => "retained",
+        SUBSCRIPTION => "subscription",
+        QUEUE => "queue",
+        MESSAGE => "message",
+        INFLIGHT => "inflight",
+        kind => return Err(invalid(&format!("type {}", kind), body.len())),
+    };
+    let invalid = || invalid(what, body.len());
+
+    let mut fields = Fields(rest);
+    let record = match kind {
+        RETAINED => Record::Retained(StoredMessage::try_from(body.slice(1..))?),
+        SUBSCRIPTION => {
+            let client_id = fields.str().ok_or_else(invalid)?;
+            let filter = fields.str().ok_or_else(invalid)?;
+            let options = match fields.0 {
+                [options] => SubscriptionOptions::try_from(*options)?,
+                _ => return Err(invalid()),
+            };
+            Record::Subscription(client_id, filter, options)
+        }
+        QUEUE => {
+            let name = fields.str().ok_or_else(invalid)?;
+            Record::Queue(name, Limits::try_from(fields.0)?)
+        }
+        MESSAGE => {
+            let name = fields.str().ok_or_else(invalid)?;
+            let key = Key::try_from(fields.take(Key::ENCODED_LEN).ok_or_else(invalid)?)?;
+            let state = take_state(fields.take(STATE_LEN).ok_or_else(invalid)?)?;
+            let payload = body.slice(body.len() - fields.0.len()..);
+            Record::Message(name, key, payload, state)
+        }
+        _ => {
+            let name = fields.str().ok_or_else(invalid)?;
+            let packet_id = session::packet_id(fields.take(2).ok_or_else(invalid)?)?;
+            Record::Inflight(name, packet_id, Inflight::try_from(fields.0)?)
+        }
+    };
+
+    Ok(record)
+}
+
+// the fields of a record body are taken off its front
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(field)
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = match *self.take(2)? {
+            [high, low] => u16::from_be_bytes([high, low]) as usize,
+            _ => return None,
+        };
+        std::str::from_utf8(self.take(len)?).ok()
+    }
+}
+
+fn put_str(body: &mut Vec<u8>, what: &str, s: &str) -> Result<(), StorageError> {
+    let len = u16::try_from(s.len())
+        .map_err(|_| StorageError::InvalidMessage(format!("{} too long: {}", what, s)))?;
+    body.extend_from_slice(&len.to_be_bytes());
+    body.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn put_state(body: &mut Vec<u8>, state: &MessageState) {
+    let mut flags = 0;
+    if state.expires_at.is_some() {
+        flags |= EXPIRES;
+    }
+    if state.lease.is_some() {
+        flags |= LEASED;
+    }
+
+    body.extend_from_slice(&state.pushed_at.to_be_bytes());
+    body.push(flags);
+    body.extend_from_slice(&state.expires_at.unwrap_or_default().to_be_bytes());
+    body.extend_from_slice(&state.lease.unwrap_or_default().to_bytes());
+}
+
+fn take_state(bytes: &[u8]) -> Result<MessageState, StorageError> {
+    let u64_at = |at: usize| {
+        let bytes: [u8; 8] = bytes[at..at + 8].try_into().expect("eight bytes");
+        u64::from_be_bytes(bytes)
+    };
+    let flags = bytes[8];
+    if flags & !(EXPIRES | LEASED) != 0 {
+        return Err(StorageError::Corruption(format!(
+            "invalid message state flags in dump: {}",
+            flags
+        )));
+    }
+
+    let lease = match flags & LEASED {
+        0 => None,
+        _ => Some(Lease::try_from(&bytes[17..])?),
+    };
+    Ok(MessageState {
+        pushed_at: u64_at(0),
+        expires_at: Some(u64_at(9)).filter(|_| flags & EXPIRES != 0),
+        lease,
+    })
+}
+
+fn write_record(writer: &mut impl Write, body: &[u8]) -> Result<(), StorageError> {
+    let len = u32::try_from(body.len())
+        .map_err(|_| StorageError::InvalidMessage("record too large for a dump".into()))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&checksum(body).to_be_bytes())?;
+    Ok(())
+}
+
+// the body is read up to its length instead of allocated up front, so a
+// corrupted length cannot ask for more memory than the dump holds
+fn read_record(reader: &mut impl Read) -> Result<Vec<u8>, StorageError> {
+    let mut len = [0; 4];
+    read_exact(reader, &mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+
+    let mut body = Vec::new();
+    reader.take(len as u64).read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(cut_short());
+    }
+
+    let mut expected = [0; 4];
+    read_exact(reader, &mut expected)?;
+    if u32::from_be_bytes(expected) != checksum(&body) {
+        return Err(StorageError::Corruption(
+            "dump record fails its checksum".into(),
+        ));
+    }
+
+    Ok(body)
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), StorageError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => cut_short(),
+        _ => e.into(),
+    })
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+// records can be large, so only what is wrong with one is reported
+fn invalid(what: &str, len: usize) -> StorageError {
+    StorageError::Corruption(format!("invalid {} record of {} bytes in dump", what, len))
+}
+
+fn cut_short() -> StorageError {
+    StorageError::Corruption("dump ends before its last record".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        Memory, Overflow, QoS, QueueFile, RetainedStore, SessionStateStore, Sled, SubscriptionStore,
+    };
+
+    use super::*;
+
+    type Contents = Vec<(String, Limits, Vec<(Key, Payload, MessageState)>)>;
+
+    // queues with gaps left by acks, several priorities and an empty one;
+    // some messages expire, one of them already, and some were handed out
+    fn fill(storage: &dyn Storage) {
+        let limits = Limits {
+            max_len: Some(1000),
+            max_bytes: None,
+            overflow: Overflow::DropOldest,
+            max_deliveries: Some(3),
+        };
+        storage.create_queue_with("a", limits).unwrap();
+        storage.create_queue("b").unwrap();
+        storage.create_queue("empty").unwrap();
+
+        for i in 0..600u32 {
+            let payload = Payload::from(i.to_be_bytes().to_vec());
+            let key = storage
+                .push_with_priority("a", (i % 3) as u16, payload)
+                .unwrap();
+            if i % 7 == 0 {
+                storage.remove("a", key).unwrap();
+            }
+        }
+        storage.push("b", Payload::new()).unwrap();
+        storage
+            .push("b", Payload::from_static(b"a payload"))
+            .unwrap();
+        storage
+            .push_with_expiry(
+                "b",
+                Payload::from_static(b"later"),
+                Duration::from_secs(3600),
+            )
+            .unwrap();
+        storage
+            .push_with_expiry(
+                "b",
+                Payload::from_static(b"expired"),
+                Duration::from_secs(0),
+            )
+            .unwrap();
+
+        storage.checkout("a", 5, Duration::from_secs(60)).unwrap();
+        let key = storage.oldest_key("b").unwrap().unwrap();
+        storage.mark_delivered("b", key).unwrap();
+    }
+
+    // read without purging, so expired messages show up as well
+    fn contents(storage: &dyn Storage) -> Contents {
+        let mut names = storage.names();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let limits = storage.limits(&name).unwrap();
+                let messages = storage.peek(&name, None, 10_000).unwrap();
+                (name, limits, messages.into_iter().collect())
+            })
+            .collect()
+    }
+
+    fn round_trip(from: &dyn Storage, to: &dyn Storage) -> Summary {
+        let mut dump = Vec::new();
+        let exported = export(from, &mut dump).unwrap();
+        let imported = import(to, &dump[..]).unwrap();
+        assert_eq!(imported, exported);
+        imported
+    }
+
+    fn retained(topic: &str) -> StoredMessage {
+        StoredMessage {
+            topic: topic.into(),
+            payload: Payload::from_static(b"retained"),
+            ..StoredMessage::default()
+        }
+    }
+
+    #[test]
+    fn dumps_round_trip_between_backends() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let memory = Memory::tree("q", 0);
+        fill(&memory);
+        let expected = contents(&memory);
+
+        let sled = Sled::new(dir.path().join("sled"), "q", 0).unwrap();
+        let summary = round_trip(&memory, &sled);
+        assert_eq!((summary.queues, summary.messages), (3, 518));
+        assert_eq!(contents(&sled), expected);
+
+        assert_eq!(export(&sled, io::sink()).unwrap(), summary);
+
+        #[cfg(feature = "rocksdb")]
+        {
+            let rocksdb = crate::Rocksdb::new(dir.path().join("rocksdb"), "q", 0).unwrap();
+            round_trip(&sled, &rocksdb);
+            assert_eq!(export(&rocksdb, io::sink()).unwrap(), summary);
+            assert_eq!(contents(&rocksdb), expected);
+        }
+
+        let queue_file = QueueFile::new(dir.path().join("queue_file"), "q", 0).unwrap();
+        round_trip(&sled, &queue_file);
+        assert_eq!(export(&queue_file, io::sink()).unwrap(), summary);
+        assert_eq!(contents(&queue_file), expected);
+
+        // keys go on after the restored ones
+        let back = Memory::vec("q", 0);
+        round_trip(&queue_file, &back);
+        assert_eq!(export(&back, io::sink()).unwrap(), summary);
+        assert_eq!(contents(&back), expected);
+        let pushed = back.push("b", Payload::new()).unwrap();
+        assert_eq!(back.batch("b", 10).unwrap().back().unwrap().0, pushed);
+
+        // expired messages only go once they are read
+        assert_eq!(back.batch("b", 10).unwrap().len(), 4);
+        assert_eq!(back.expired("b").unwrap(), 1);
+    }
+
+    #[test]
+    fn dumps_keep_retained_messages_subscriptions_and_sessions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sled = Sled::new(dir.path().join("from"), "q", 0).unwrap();
+        sled.set_retained(&retained("a/b")).unwrap();
+        sled.set_retained(&retained("$SYS/uptime")).unwrap();
+        let options = SubscriptionOptions {
+            qos: QoS::AtLeastOnce,
+            no_local: true,
+            ..SubscriptionOptions::default()
+        };
+        sled.add_subscription("c1", "a/#", options).unwrap();
+        sled.add_subscription("c2", "a/b", SubscriptionOptions::default())
+            .unwrap();
+
+        sled.create_queue("c1").unwrap();
+        let keys: Vec<_> = (0..3u8)
+            .map(|i| sled.push("c1", Payload::from(vec![i])).unwrap())
+            .collect();
+        let first = sled.publish("c1", keys[0]).unwrap();
+        let second = sled.publish("c1", keys[1]).unwrap();
+        sled.pubrec("c1", second).unwrap();
+
+        let target = Sled::new(dir.path().join("to"), "q", 0).unwrap();
+        let summary = round_trip(&sled, &target);
+        assert_eq!(
+            (summary.retained, summary.subscriptions, summary.inflight),
+            (2, 2, 2)
+        );
+        assert_eq!(export(&target, io::sink()).unwrap(), summary);
+        assert_eq!(
+            target.retained("$SYS/uptime").unwrap(),
+            Some(retained("$SYS/uptime"))
+        );
+        assert_eq!(target.subscriptions("c1"), [("a/#".to_string(), options)]);
+        assert_eq!(target.route("a/b"), sled.route("a/b"));
+        assert_eq!(target.inflight("c1").unwrap(), sled.inflight("c1").unwrap());
+
+        // allocation goes on after the restored window
+        assert!(matches!(
+            target.publish("c1", keys[0]),
+            Err(StorageError::AlreadyInflight(id)) if id == first
+        ));
+        assert_eq!(target.publish("c1", keys[2]).unwrap(), second + 1);
+        target.complete("c1", first).unwrap();
+        assert_eq!(target.len("c1").unwrap(), 1);
+
+        #[cfg(feature = "rocksdb")]
+        {
+            let rocksdb = crate::Rocksdb::new(dir.path().join("rocksdb"), "q", 0).unwrap();
+            round_trip(&sled, &rocksdb);
+            assert_eq!(export(&rocksdb, io::sink()).unwrap(), summary);
+            assert_eq!(
+                rocksdb.inflight("c1").unwrap(),
+                sled.inflight("c1").unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn copies_match_the_source() {
+        let memory = Memory::tree("q", 0);
+        fill(&memory);
+        memory.set_retained(&retained("a/b")).unwrap();
+        memory
+            .add_subscription("c", "a/+", SubscriptionOptions::default())
+            .unwrap();
+
+        let target = Memory::vec("q", 0);
+        let mut progress = 0;
+        let copied = copy(&memory, &target, |n| progress = n).unwrap();
+        assert_eq!(progress, copied.messages);
+        assert_eq!(copied, export(&memory, io::sink()).unwrap());
+        assert_eq!(copied, export(&target, io::sink()).unwrap());
+        assert_eq!(contents(&target), contents(&memory));
+        assert_eq!(target.route("a/b"), [("c".to_string(), QoS::AtMostOnce)]);
+    }
+
+    #[test]
+    fn copies_keep_what_target_limits_would_drop() {
+        let memory = Memory::tree("q", 0);
+        fill(&memory);
+
+        let target = Memory::tree("q", 0);
+        target.budget().set_limit(Some(1));
+        let limits = Limits {
+            max_len: Some(1),
+            ..Limits::default()
+        };
+        target.create_queue_with("a", limits).unwrap();
+        copy(&memory, &target, |_| ()).unwrap();
+        assert_eq!(target.len("a").unwrap(), memory.len("a").unwrap());
+        assert_eq!(target.len("b").unwrap(), 4);
+    }
+
+    #[test]
+    fn copies_fail_on_what_the_target_cannot_hold() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let memory = Memory::tree("q", 0);
+        memory.set_retained(&retained("a/b")).unwrap();
+        let queue_file = QueueFile::new(dir.path().join("queue_file"), "q", 0).unwrap();
+        assert!(matches!(
+            copy(&memory, &queue_file, |_| ()),
+            Err(StorageError::Unsupported(what)) if what == "retained messages"
+        ));
+
+        let sled = Sled::new(dir.path().join("sled"), "q", 0).unwrap();
+        sled.create_queue("c").unwrap();
+        let key = sled.push("c", Payload::new()).unwrap();
+        sled.publish("c", key).unwrap();
+        let memory = Memory::tree("q", 0);
+        assert!(matches!(
+            copy(&sled, &memory, |_| ()),
+            Err(StorageError::Unsupported(what)) if what == "inflight windows"
+        ));
+    }
+
+    #[test]
+    fn damaged_dumps_are_rejected() {
+        let memory = Memory::tree("q", 0);
+        fill(&memory);
+        let mut dump = Vec::new();
+        export(&memory, &mut dump).unwrap();
+
+        let mut flipped = dump.clone();
+        flipped[100] ^= 1;
+        let target = Memory::tree("q", 0);
+        assert!(matches!(
+            import(&target, &flipped[..]),
+            Err(StorageError::Corruption(_))
+        ));
+
+        let target = Memory::tree("q", 0);
+        assert!(matches!(
+            import(&target, &dump[..dump.len() - 20]),
+            Err(StorageError::Corruption(_))
+        ));
+
+        let mut version = dump;
+        version[MAGIC.len() + 1] = 1;
+        assert!(matches!(
+            import(&target, &version[..]),
+            Err(StorageError::Corruption(_))
+        ));
+
+        // a well framed record of an unknown type is named, not printed
+        let mut unknown = MAGIC.to_vec();
+        unknown.extend_from_slice(&VERSION.to_be_bytes());
+        write_record(&mut unknown, &[9; 64]).unwrap();
+        let target = Memory::tree("q", 0);
+        assert!(matches!(
+            import(&target, &unknown[..]),
+            Err(StorageError::Corruption(msg)) if msg == "invalid type 9 record of 64 bytes in dump"
+        ));
+    }
+}
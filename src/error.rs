@@ -0,0 +1,57 @@
+use std::{error::Error, fmt, io};
+
+use crate::Key;
+
+#[derive(Debug)]
+pub enum StorageError {
+    UnknownQueue(String),
+    InvalidQueueName(String),
+    Io(io::Error),
+    Corruption(String),
+    Full,
+    QueueFull(String),
+    InvalidMessage(String),
+    InvalidTopic(String),
+    UnknownPacketId(u16),
+    InflightFull(String),
+    NotQueued(Key),
+    AlreadyInflight(u16),
+    Unsupported(String),
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownQueue(name) => write!(f, "unknown queue: {}", name),
+            Self::InvalidQueueName(name) => write!(f, "invalid queue name: {}", name),
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Corruption(msg) => write!(f, "data corruption: {}", msg),
+            Self::Full => write!(f, "storage is full"),
+            Self::QueueFull(name) => write!(f, "queue is full: {}", name),
+            Self::InvalidMessage(msg) => write!(f, "invalid message: {}", msg),
+            Self::InvalidTopic(filter) => write!(f, "invalid topic filter: {}", filter),
+            Self::UnknownPacketId(id) => write!(f, "unknown packet id: {}", id),
+            Self::InflightFull(name) => write!(f, "no packet id left for session: {}", name),
+            Self::NotQueued(key) => write!(f, "no message queued under key: {}", key),
+            Self::AlreadyInflight(id) => write!(f, "message already inflight as packet: {}", id),
+            Self::Unsupported(what) => write!(f, "storage keeps no {}", what),
+            Self::Backend(msg) => write!(f, "backend error: {}", msg),
+        }
+    }
+}
+
+impl Error for StorageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::{self, Either, FutureExt};
+use tokio::{sync::oneshot, time};
+
+use crate::{AsyncStorage, Key, StorageError};
+
+// expiry records are keyed by deadline first, so that everything due is a
+// prefix of the table; values are empty
+pub(crate) const RECORD_LEN: usize = 8 + Key::ENCODED_LEN;
+
+// deadlines are wall clock milliseconds like lease deadlines
+pub(crate) fn deadline(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(ttl.as_millis() as u64)
+}
+
+pub(crate) fn record(deadline: u64, key: Key) -> [u8; RECORD_LEN] {
+    let mut bytes = [0; RECORD_LEN];
+    bytes[..8].copy_from_slice(&deadline.to_be_bytes());
+    bytes[8..].copy_from_slice(&key.to_bytes());
+    bytes
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<(u64, Key), StorageError> {
+    let bytes: [u8; RECORD_LEN] = bytes
+        .try_into()
+        .map_err(|_| StorageError::Corruption(format!("invalid expiry record: {:?}", bytes)))?;
+
+    let mut deadline = [0; 8];
+    deadline.copy_from_slice(&bytes[..8]);
+    Ok((u64::from_be_bytes(deadline), Key::try_from(&bytes[8..])?))
+}
+
+// purges expired messages of every queue in the background. Reads never hand
+// out expired messages either way, the purger reclaims the space of queues
+// nobody reads. It stops once dropped
+pub struct Purger {
+    _stop: oneshot::Sender<()>,
+}
+
+impl Purger {
+    pub fn spawn<S: AsyncStorage>(storage: Arc<S>, every: Duration) -> Self {
+        let (stop, mut stopped) = oneshot::channel();
+
+        tokio::spawn(async move {
+            loop {
+                match future::select(stopped, time::sleep(every).boxed()).await {
+                    Either::Left(_) => break,
+                    Either::Right((_, pending)) => stopped = pending,
+                }
+
+                // a failed round is retried with the next one
+                let _ = storage.purge_all_expired().await;
+            }
+        });
+
+        Self { _stop: stop }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Memory, Offload, Payload, Storage};
+
+    #[tokio::test]
+    async fn purger_reclaims_expired_messages() {
+        let storage = Arc::new(Offload::new(Memory::tree("q", 2), 1));
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_secs(0))
+            .await
+            .unwrap();
+        storage
+            .push_with_expiry("q1", Payload::from_static(b"b"), Duration::from_secs(3600))
+            .await
+            .unwrap();
+
+        let purger = Purger::spawn(storage.clone(), Duration::from_millis(10));
+        time::sleep(Duration::from_millis(50)).await;
+        drop(purger);
+
+        let memory = storage.storage();
+        assert_eq!(memory.stats("q0").unwrap().expired, 1);
+        assert_eq!(memory.len("q0").unwrap(), 0);
+        assert_eq!(memory.len("q1").unwrap(), 1);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::StorageError;
+
+// deadlines are wall clock milliseconds, so that a lease taken before a
+// restart still expires at the same moment afterwards. Checkouts are counted
+// apart from deliveries marked by readers, only they count towards
+// `Limits::max_deliveries`
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Lease {
+    pub(crate) deadline: u64,
+    pub(crate) attempts: u16,
+    pub(crate) delivered: u16,
+}
+
+impl Lease {
+    pub(crate) const ENCODED_LEN: usize = 12;
+
+    // a message checked out again keeps counting its delivery attempts
+    pub(crate) fn renew(previous: Option<Lease>, now: u64, duration: Duration) -> Self {
+        let previous = previous.unwrap_or_default();
+        Self {
+            deadline: now.saturating_add(duration.as_millis() as u64),
+            attempts: previous.attempts.saturating_add(1),
+            delivered: previous.delivered,
+        }
+    }
+
+    // a delivery outside of a checkout is counted without taking a lease,
+    // one that is held already is kept
+    pub(crate) fn delivered(previous: Option<Lease>) -> Self {
+        let previous = previous.unwrap_or_default();
+        Self {
+            delivered: previous.delivered.saturating_add(1),
+            ..previous
+        }
+    }
+
+    pub(crate) fn is_active(self, now: u64) -> bool {
+        self.deadline > now
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[..8].copy_from_slice(&self.deadline.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.delivered.to_be_bytes());
+        bytes[10..].copy_from_slice(&self.attempts.to_be_bytes());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for Lease {
+    type Error = StorageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: [u8; Self::ENCODED_LEN] = bytes
+            .try_into()
+            .map_err(|_| StorageError::Corruption(format!("invalid lease: {:?}", bytes)))?;
+
+        let mut deadline = [0; 8];
+        deadline.copy_from_slice(&bytes[..8]);
+        let mut delivered = [0; 2];
+        delivered.copy_from_slice(&bytes[8..10]);
+        let mut attempts = [0; 2];
+        attempts.copy_from_slice(&bytes[10..]);
+
+        Ok(Self {
+            deadline: u64::from_be_bytes(deadline),
+            attempts: u16::from_be_bytes(attempts),
+            delivered: u16::from_be_bytes(delivered),
+        })
+    }
+}
+
+// see `Storage::available_at`, `leases` are the ones of the `len` messages
+// in a queue, of which only the active ones hold a message back
+pub(crate) fn available_at(
+    len: usize,
+    leases: impl IntoIterator<Item = Result<Lease, StorageError>>,
+    now: u64,
+) -> Result<Option<u64>, StorageError> {
+    if len == 0 {
+        return Ok(None);
+    }
+
+    let mut active = 0;
+    let mut first_expiry = u64::MAX;
+    for lease in leases {
+        let lease = lease?;
+        if lease.is_active(now) {
+            active += 1;
+            first_expiry = first_expiry.min(lease.deadline);
+        }
+    }
+
+    Ok(Some(if active < len { now } else { first_expiry }))
+}
+
+// how often a message was handed out, by checkouts and by deliveries
+// marked with `Storage::mark_delivered`. It is kept in the lease record of
+// the message, so counting a delivery never rewrites the payload
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Delivery {
+    pub count: u32,
+}
+
+impl Delivery {
+    pub(crate) fn of(lease: Option<Lease>) -> Self {
+        Self {
+            count: lease.map_or(0, |lease| {
+                u32::from(lease.attempts) + u32::from(lease.delivered)
+            }),
+        }
+    }
+
+    // a message handed out before is sent again with the DUP flag set
+    pub fn is_dup(&self) -> bool {
+        self.count > 0
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
@@ -1,31 +1,509 @@
-use std::{collections::VecDeque, fmt::Display, ops::Deref};
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    convert::{TryFrom, TryInto},
+    fmt::Display,
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::sync::Notify;
+
+use crate::{lease::Lease, stats::Entry};
 
 pub mod app;
+mod async_storage;
+mod budget;
+mod cursor;
+mod dump;
+mod error;
+mod expiry;
+mod lease;
+mod limits;
 mod memory;
+mod message;
+mod offset;
 mod queue_file;
+mod ready;
+mod retained;
 #[cfg(feature = "rocksdb")]
 mod rocksdb;
+mod session;
 mod sled;
+mod stats;
+mod subscription;
+mod topic;
 
+pub use crate::async_storage::{AsyncStorage, Inline, Offload};
+pub use crate::budget::Budget;
+pub use crate::cursor::Cursor;
+pub use crate::dump::{copy, export, import, Summary};
+pub use crate::error::StorageError;
+pub use crate::expiry::Purger;
+pub use crate::lease::Delivery;
+pub use crate::limits::{dead_letter_queue, Limits, Overflow};
 pub use crate::memory::Memory;
+pub use crate::message::{Properties, QoS, StoredMessage};
 pub use crate::queue_file::QueueFile;
+pub use crate::ready::ReadySet;
+pub use crate::retained::RetainedStore;
 #[cfg(feature = "rocksdb")]
 pub use crate::rocksdb::Rocksdb;
+pub use crate::session::{Inflight, PacketState, SessionStateStore};
 pub use crate::sled::Sled;
+pub use crate::stats::QueueStats;
+pub use crate::subscription::{RetainHandling, SubscriptionOptions, SubscriptionStore};
 
 pub trait Storage {
     fn names(&self) -> Vec<String>;
-    fn push(&self, name: &str, payload: Payload) -> Key;
-    fn batch(&self, name: &str, size: usize) -> VecDeque<(Key, Payload)>;
-    fn remove(&self, name: &str, key: Key);
+    fn contains(&self, name: &str) -> bool;
+    fn create_queue(&self, name: &str) -> Result<(), StorageError> {
+        self.create_queue_with(name, Limits::default())
+    }
+
+    // creating a queue that exists already keeps the limits it was created
+    // with, they are stored alongside the queue; names starting with
+    // `SYSTEM_PREFIX`, empty names, `.`, `..` and names with a `/`, `\` or NUL
+    // are rejected with `StorageError::InvalidQueueName`
+    fn create_queue_with(&self, name: &str, limits: Limits) -> Result<(), StorageError>;
+    fn limits(&self, name: &str) -> Result<Limits, StorageError>;
+    fn drop_queue(&self, name: &str) -> Result<(), StorageError>;
+
+    // signalled after every push into the queue and once more when the queue
+    // is dropped, a wakeup only means the queue may have something to read
+    fn subscribe(&self, name: &str) -> Result<Arc<Notify>, StorageError>;
+
+    // queues marked by pushes, see `next_ready`
+    fn ready(&self) -> &ReadySet;
+
+    // bytes held by all queues together, pushes beyond it fail with
+    // `StorageError::Full`
+    fn budget(&self) -> &Budget;
+
+    // the next queue in the ready set with a message that is not leased,
+    // empty and dropped queues found on the way are left out of the set until
+    // their next push, fully leased ones until their first lease runs out
+    fn next_ready(&self) -> Result<Option<String>, StorageError> {
+        let ready = self.ready();
+        ready.unpark(lease::now());
+        while let Some((name, picks)) = ready.take() {
+            match self.available_at(&name) {
+                Ok(Some(at)) if at <= lease::now() => {
+                    ready.restore(name.clone(), picks);
+                    return Ok(Some(name));
+                }
+                Ok(Some(at)) => ready.park(name, at),
+                Ok(None) | Err(StorageError::UnknownQueue(_)) => {}
+                Err(e) => {
+                    ready.restore(name, picks);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn push(&self, name: &str, payload: Payload) -> Result<Key, StorageError> {
+        Ok(self.push_with(name, payload, PushOptions::default())?.key)
+    }
+
+    fn push_with_priority(
+        &self,
+        name: &str,
+        priority: u16,
+        payload: Payload,
+    ) -> Result<Key, StorageError> {
+        let options = PushOptions {
+            priority,
+            ..PushOptions::default()
+        };
+        Ok(self.push_with(name, payload, options)?.key)
+    }
+
+    fn push_with_expiry(
+        &self,
+        name: &str,
+        payload: Payload,
+        ttl: Duration,
+    ) -> Result<Key, StorageError> {
+        let options = PushOptions {
+            ttl: Some(ttl),
+            ..PushOptions::default()
+        };
+        Ok(self.push_with(name, payload, options)?.key)
+    }
+
+    // a queue at its limits either rejects the push with
+    // `StorageError::QueueFull` or drops messages to make room, which is
+    // reported back
+    fn push_with(
+        &self,
+        name: &str,
+        payload: Payload,
+        options: PushOptions,
+    ) -> Result<Pushed, StorageError>;
+
+    // stores a message under the key and with the state it was exported
+    // with, replacing one stored under it already, and later pushes follow
+    // it. Neither the limits of the queue nor the budget apply, nothing is
+    // dropped or rejected for room. Returns the key it ended up under, see
+    // `dump::import`
+    fn restore(
+        &self,
+        name: &str,
+        key: Key,
+        payload: Payload,
+        state: MessageState,
+    ) -> Result<Key, StorageError>;
+
+    // a message expiry interval becomes the ttl unless `options` has one
+    fn push_message(
+        &self,
+        name: &str,
+        message: &StoredMessage,
+        options: PushOptions,
+    ) -> Result<Pushed, StorageError> {
+        let options = PushOptions {
+            ttl: options.ttl.or_else(|| message.ttl()),
+            ..options
+        };
+        self.push_with(name, message.to_bytes()?, options)
+    }
+
+    // either every item is stored or none of them is, keys are returned in
+    // the order of `items`; a queue may appear once for fan-out or many
+    // times for a burst of messages. Limits are checked for the whole batch
+    // up front, messages dropped to make room are not reported
+    fn push_many(&self, items: Vec<(&str, Payload)>) -> Result<Vec<Key>, StorageError> {
+        let items = items
+            .into_iter()
+            .map(|(name, payload)| (name, 0, payload))
+            .collect();
+        self.push_many_with_priority(items)
+    }
+
+    fn push_many_with_priority(
+        &self,
+        items: Vec<(&str, u16, Payload)>,
+    ) -> Result<Vec<Key>, StorageError>;
+
+    // reads purge expired messages of the queue first, so they never hand
+    // out a message past its expiry
+    fn batch(&self, name: &str, size: usize) -> Result<VecDeque<(Key, Payload)>, StorageError>;
+
+    // like `batch`, for queues written with `push_message`
+    fn batch_messages(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> Result<VecDeque<(Key, StoredMessage)>, StorageError> {
+        decode(self.batch(name, size)?)
+    }
+
+    // like `batch`, with the delivery record of every message read along
+    // with it, so that no mark or checkout comes in between
+    fn batch_with_delivery(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> Result<VecDeque<Delivered>, StorageError>;
+
+    // a message that is gone has no deliveries
+    fn delivery(&self, name: &str, key: Key) -> Result<Delivery, StorageError>;
+
+    // counts a delivery of a message read with `batch`, checkouts count
+    // their own; marking a message that is gone does nothing
+    fn mark_delivered(&self, name: &str, key: Key) -> Result<(), StorageError>;
+
+    // when `checkout` finds a message of the queue to hand out: now unless
+    // every message is leased, then once the first lease runs out; `None`
+    // while the queue is empty. Expired messages are purged first
+    fn available_at(&self, name: &str) -> Result<Option<u64>, StorageError>;
+
+    // messages delivered after `after`, which does not have to exist anymore
+    fn batch_after(
+        &self,
+        name: &str,
+        after: Key,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError>;
+
+    // messages in delivery order like `batch` with their state, after
+    // `after` if given, without purging expired ones first; it never writes
+    // to the storage, see `dump::copy`
+    fn peek(
+        &self,
+        name: &str,
+        after: Option<Key>,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload, MessageState)>, StorageError>;
+
+    fn cursor(&self, name: &str, page_size: usize) -> Cursor<'_, Self>
+    where
+        Self: Sized,
+    {
+        Cursor::new(self, name, page_size)
+    }
+
+    // unlike `batch`, hands out messages that are not leased yet or whose
+    // lease has expired, and leases them for `lease`; acknowledging a
+    // message with any of the `remove` methods ends its lease. A message
+    // that ran out of `Limits::max_deliveries` is moved to the dead letter
+    // queue instead
+    fn checkout(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError>;
+
+    fn checkout_messages(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> Result<VecDeque<(Key, StoredMessage)>, StorageError> {
+        decode(self.checkout(name, size, lease)?)
+    }
+
+    fn remove(&self, name: &str, key: Key) -> Result<(), StorageError>;
+    fn remove_many(&self, name: &str, keys: &[Key]) -> Result<(), StorageError>;
+
+    // removes `key` and every message delivered before it
+    fn remove_up_to(&self, name: &str, key: Key) -> Result<(), StorageError>;
+
+    // moves a message to the back of its priority in `to` with the expiry it
+    // has left, in one write where the backend has transactions; returns the
+    // new key, or `None` if the message is gone already. Room is made in `to`
+    // like for a push
+    fn move_to(&self, from: &str, key: Key, to: &str) -> Result<Option<Key>, StorageError>;
+
+    // removes messages of the queue whose expiry has passed and returns how
+    // many there were
+    fn purge_expired(&self, name: &str) -> Result<usize, StorageError>;
+
+    // queues dropped while they are purged are skipped
+    fn purge_all_expired(&self) -> Result<usize, StorageError> {
+        let mut purged = 0;
+        for name in self.names() {
+            match self.purge_expired(&name) {
+                Ok(count) => purged += count,
+                Err(StorageError::UnknownQueue(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(purged)
+    }
+
+    // answered from counters every backend keeps next to the queue, leased
+    // messages count until they are acknowledged
+    fn stats(&self, name: &str) -> Result<QueueStats, StorageError>;
+
+    fn len(&self, name: &str) -> Result<usize, StorageError> {
+        Ok(self.stats(name)?.len)
+    }
+
+    fn size_bytes(&self, name: &str) -> Result<u64, StorageError> {
+        Ok(self.stats(name)?.size_bytes)
+    }
+
+    fn oldest_key(&self, name: &str) -> Result<Option<Key>, StorageError> {
+        Ok(self.stats(name)?.oldest)
+    }
+
+    fn newest_key(&self, name: &str) -> Result<Option<Key>, StorageError> {
+        Ok(self.stats(name)?.newest)
+    }
+
+    fn oldest_age(&self, name: &str) -> Result<Option<Duration>, StorageError> {
+        Ok(self.stats(name)?.oldest_age)
+    }
+
+    fn expired(&self, name: &str) -> Result<u64, StorageError> {
+        Ok(self.stats(name)?.expired)
+    }
+
+    // every retained message by topic, expired ones included, see
+    // `RetainedStore`; a backend without the store has none and refuses to
+    // restore any with `StorageError::Unsupported`
+    fn retained_messages(&self) -> Result<Vec<StoredMessage>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn restore_retained(&self, _message: &StoredMessage) -> Result<(), StorageError> {
+        Err(StorageError::Unsupported("retained messages".into()))
+    }
+
+    // client id, filter and options of every subscription by client id and
+    // filter, see `SubscriptionStore`
+    fn all_subscriptions(
+        &self,
+    ) -> Result<Vec<(String, String, SubscriptionOptions)>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn restore_subscription(
+        &self,
+        _client_id: &str,
+        _filter: &str,
+        _options: SubscriptionOptions,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::Unsupported("subscriptions".into()))
+    }
+
+    // the inflight window of the queue like `SessionStateStore::inflight`
+    fn inflight_window(&self, _name: &str) -> Result<Vec<(u16, Inflight)>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    // records an exchange as it was exported, the message it refers to is
+    // not looked up, see `SessionStateStore`
+    fn restore_inflight(
+        &self,
+        _name: &str,
+        _packet_id: u16,
+        _inflight: Inflight,
+    ) -> Result<(), StorageError> {
+        Err(StorageError::Unsupported("inflight windows".into()))
+    }
+}
+
+// cheap to clone, so re-reads and fan-out of a message share one buffer
+pub type Payload = Bytes;
+
+// a message read with `batch_with_delivery`
+pub type Delivered = (Key, Payload, Delivery);
+
+// a leased batch, or nothing leased and the messages out of deliveries that
+// were found in its way
+type Checkout = (VecDeque<(Key, Payload)>, Vec<Key>);
+
+// messages out of deliveries are moved to the dead letter queue before
+// anything is leased, so a failed move leaves no batch leased that is never
+// handed out; `lease` is repeated once they are gone
+fn checkout<S: Storage + ?Sized>(
+    storage: &S,
+    name: &str,
+    mut lease: impl FnMut() -> Result<Checkout, StorageError>,
+) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+    loop {
+        let (batch, exhausted) = lease()?;
+        if exhausted.is_empty() {
+            return Ok(batch);
+        }
+        dead_letter(storage, name, &exhausted)?;
+    }
 }
 
-pub type Payload = Vec<u8>;
+// moves messages out of deliveries to the dead letter queue of `name`
+fn dead_letter<S: Storage + ?Sized>(
+    storage: &S,
+    name: &str,
+    keys: &[Key],
+) -> Result<(), StorageError> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let queue = dead_letter_queue(name);
+    storage.create_queue(&queue)?;
+    for key in keys {
+        storage.move_to(name, *key, &queue)?;
+    }
+    Ok(())
+}
+
+// trees, column families and files starting with this prefix belong to
+// the backends themselves or keep side data of queues, so no queue may be
+// named like them
+const SYSTEM_PREFIX: &str = "__";
+
+// queue files keep every queue in a directory named like it, so a name must
+// stay a single path component on every platform
+fn check_queue_name(name: &str) -> Result<(), StorageError> {
+    let invalid = matches!(name, "" | "." | "..")
+        || name.starts_with(SYSTEM_PREFIX)
+        || name.contains(&['/', '\\', '\0'][..]);
+    if invalid {
+        return Err(StorageError::InvalidQueueName(name.into()));
+    }
+    Ok(())
+}
+
+fn decode(batch: VecDeque<(Key, Payload)>) -> Result<VecDeque<(Key, StoredMessage)>, StorageError> {
+    batch
+        .into_iter()
+        .map(|(key, payload)| Ok((key, StoredMessage::try_from(payload)?)))
+        .collect()
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PushOptions {
+    pub priority: u16,
+    // the message expires this long after the push, MQTT 5 message expiry
+    pub ttl: Option<Duration>,
+}
+
+// what a message carries next to its payload, read with `peek` and put back
+// with `restore`; times are wall clock milliseconds like lease deadlines
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MessageState {
+    pub pushed_at: u64,
+    pub expires_at: Option<u64>,
+    pub(crate) lease: Option<Lease>,
+}
+
+impl MessageState {
+    // a message acked while it is read is not in the tally anymore, it keeps
+    // no push time
+    pub(crate) fn new(entry: Option<&Entry>, lease: Option<Lease>) -> Self {
+        Self {
+            pushed_at: entry.map_or(0, |entry| entry.pushed_at),
+            expires_at: entry.and_then(|entry| entry.expires_at),
+            lease,
+        }
+    }
+
+    pub fn delivery(&self) -> Delivery {
+        Delivery::of(self.lease)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Pushed {
+    pub key: Key,
+    // the policy that made room for the message, if the queue was full
+    pub overflow: Option<Overflow>,
+    pub dropped: Vec<Key>,
+}
+
+impl Pushed {
+    pub(crate) fn new(key: Key, limits: &Limits, dropped: Vec<Key>) -> Self {
+        Self {
+            key,
+            overflow: Some(limits.overflow).filter(|_| !dropped.is_empty()),
+            dropped,
+        }
+    }
+}
 
-#[derive(Debug, Default, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct Key(u16, u64);
 
 impl Key {
+    pub const ENCODED_LEN: usize = 10;
+
+    pub fn with_priority(priority: u16, offset: u64) -> Self {
+        Self(priority, offset)
+    }
+
+    pub fn priority(&self) -> u16 {
+        self.0
+    }
+
     pub fn next(&self) -> Key {
         Key(self.0, self.1 + 1)
     }
@@ -37,6 +515,38 @@ impl Key {
     pub fn offset(&self, other: Self) -> u64 {
         self.1 - other.1
     }
+
+    // the priority is stored inverted, so that byte-wise order of encoded
+    // keys matches `Ord` for `Key`
+    pub fn to_bytes(&self) -> [u8; Self::ENCODED_LEN] {
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[..2].copy_from_slice(&(!self.0).to_be_bytes());
+        bytes[2..].copy_from_slice(&self.1.to_be_bytes());
+        bytes
+    }
+
+    // parses keys stored as `Display` strings by earlier versions
+    pub(crate) fn from_legacy(bytes: &[u8]) -> Result<Self, StorageError> {
+        let key = std::str::from_utf8(bytes).ok().and_then(|key| {
+            let (priority, offset) = key.split_once('_')?;
+            Some(Self(priority.parse().ok()?, offset.parse().ok()?))
+        });
+
+        key.ok_or_else(|| StorageError::Corruption(format!("invalid legacy key: {:?}", bytes)))
+    }
+}
+
+// keys sort in delivery order: higher priority first, FIFO within a priority
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.cmp(&self.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl Display for Key {
@@ -45,16 +555,116 @@ impl Display for Key {
     }
 }
 
-impl<T: Deref<Target = [u8]>> From<T> for Key {
-    fn from(bytes: T) -> Self {
-        let (priority, offset) = bytes.split_at(4);
+impl TryFrom<&[u8]> for Key {
+    type Error = StorageError;
 
-        let priority = std::str::from_utf8(priority).unwrap();
-        let priority = priority.parse().unwrap();
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: [u8; Self::ENCODED_LEN] = bytes
+            .try_into()
+            .map_err(|_| StorageError::Corruption(format!("invalid key: {:?}", bytes)))?;
 
-        let offset = std::str::from_utf8(&offset[1..]).unwrap();
-        let offset = offset.parse().unwrap();
+        let mut priority = [0; 2];
+        priority.copy_from_slice(&bytes[..2]);
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&bytes[2..]);
 
-        Self(priority, offset)
+        Ok(Self(
+            !u16::from_be_bytes(priority),
+            u64::from_be_bytes(offset),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // shared by the storages, which all page through queues the same way
+    pub(crate) fn it_reads_batches_after_key(storage: &impl Storage) {
+        let keys: Vec<_> = (0..6)
+            .map(|i| {
+                storage
+                    .push_with_priority("q0", i % 2, Payload::from(vec![i as u8]))
+                    .unwrap()
+            })
+            .collect();
+        storage.remove("q0", keys[1]).unwrap();
+
+        // delivery order is 1, 3, 5, 0, 2, 4
+        let after = |key, size| -> Vec<u8> {
+            let batch = storage.batch_after("q0", key, size).unwrap();
+            batch.into_iter().map(|(_, payload)| payload[0]).collect()
+        };
+        assert_eq!(after(keys[1], 10), vec![3, 5, 0, 2, 4]);
+        assert_eq!(after(keys[3], 2), vec![5, 0]);
+        assert_eq!(after(keys[0], 2), vec![2, 4]);
+        assert_eq!(after(keys[5], 10), vec![0, 2, 4]);
+        assert_eq!(after(keys[2], 10), vec![4]);
+        assert!(after(keys[4], 10).is_empty());
+    }
+
+    // a dead letter queue that refuses the move fails the checkout, which
+    // leases nothing
+    pub(crate) fn it_leases_nothing_when_dead_lettering_fails(storage: &impl Storage) {
+        let limits = Limits {
+            max_deliveries: Some(1),
+            ..Limits::default()
+        };
+        storage.create_queue_with("q0", limits).unwrap();
+        let full = Limits {
+            max_len: Some(0),
+            ..Limits::default()
+        };
+        storage
+            .create_queue_with(&dead_letter_queue("q0"), full)
+            .unwrap();
+
+        let first = storage
+            .push_with_priority("q0", 1, Payload::from_static(b"a"))
+            .unwrap();
+        let second = storage.push("q0", Payload::from_static(b"b")).unwrap();
+        let batch = storage.checkout("q0", 1, Duration::from_secs(0)).unwrap();
+        assert_eq!(batch[0].0, first);
+
+        assert!(matches!(
+            storage.checkout("q0", 1, Duration::from_secs(60)),
+            Err(StorageError::QueueFull(_))
+        ));
+        assert_eq!(storage.delivery("q0", second).unwrap().count, 0);
+        assert_eq!(storage.len("q0").unwrap(), 2);
+    }
+
+    #[test]
+    fn encoded_keys_sort_in_delivery_order() {
+        let mut keys = vec![
+            Key::with_priority(0, 1),
+            Key::with_priority(u16::MAX, 0),
+            Key::with_priority(0, 10_000_000_000_000),
+            Key::with_priority(12_000, 7),
+            Key::with_priority(0, 0),
+        ];
+        let mut encoded: Vec<_> = keys.iter().map(Key::to_bytes).collect();
+
+        keys.sort();
+        encoded.sort();
+
+        let decoded: Vec<_> = encoded
+            .iter()
+            .map(|bytes| Key::try_from(&bytes[..]).unwrap())
+            .collect();
+        assert_eq!(decoded, keys);
+    }
+
+    #[test]
+    fn it_rejects_malformed_keys() {
+        assert!(matches!(
+            Key::try_from(&b"0000_000000000001"[..]),
+            Err(StorageError::Corruption(_))
+        ));
+        assert_eq!(
+            Key::from_legacy(b"0003_000000000042").unwrap(),
+            Key::with_priority(3, 42)
+        );
+        assert!(Key::from_legacy(b"0003000000000042").is_err());
     }
 }
@@ -0,0 +1,116 @@
+use std::convert::{TryFrom, TryInto};
+
+use crate::{lease::Lease, StorageError};
+
+// caps of a single queue, set when the queue is created
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Limits {
+    pub max_len: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub overflow: Overflow,
+    // a message checked out this many times without an ack is moved to the
+    // dead letter queue of the queue instead of being handed out again
+    pub max_deliveries: Option<u32>,
+}
+
+// the queue that messages out of deliveries in `name` move to, it is
+// created with default limits on the first move
+pub fn dead_letter_queue(name: &str) -> String {
+    format!("{}.dlq", name)
+}
+
+// what a push into a queue at its limits does
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Overflow {
+    // the push fails with `StorageError::QueueFull`
+    RejectNew,
+    // messages are dropped in the order they were pushed
+    DropOldest,
+    // messages of the lowest priority are dropped first, oldest first within
+    // a priority; a push of lower priority than anything queued is rejected
+    DropLowestPriority,
+}
+
+impl Default for Overflow {
+    fn default() -> Self {
+        Self::RejectNew
+    }
+}
+
+impl Limits {
+    pub(crate) const ENCODED_LEN: usize = 21;
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_len.is_none() && self.max_bytes.is_none()
+    }
+
+    pub(crate) fn fits(&self, len: usize, bytes: u64) -> bool {
+        self.max_len.map_or(true, |max| len <= max)
+            && self.max_bytes.map_or(true, |max| bytes <= max)
+    }
+
+    // whether a message whose lease ran out is due for the dead letter queue
+    pub(crate) fn is_exhausted(&self, lease: Option<Lease>) -> bool {
+        matches!(
+            (self.max_deliveries, lease),
+            (Some(max), Some(lease)) if u32::from(lease.attempts) >= max
+        )
+    }
+
+    // a missing limit is stored as `u64::MAX`
+    pub(crate) fn to_bytes(self) -> [u8; Self::ENCODED_LEN] {
+        let max_len = self.max_len.map_or(u64::MAX, |max| max as u64);
+        let max_bytes = self.max_bytes.unwrap_or(u64::MAX);
+
+        let mut bytes = [0; Self::ENCODED_LEN];
+        bytes[..8].copy_from_slice(&max_len.to_be_bytes());
+        bytes[8..16].copy_from_slice(&max_bytes.to_be_bytes());
+        bytes[16] = match self.overflow {
+            Overflow::RejectNew => 0,
+            Overflow::DropOldest => 1,
+            Overflow::DropLowestPriority => 2,
+        };
+        let max_deliveries = self.max_deliveries.unwrap_or(u32::MAX);
+        bytes[17..].copy_from_slice(&max_deliveries.to_be_bytes());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for Limits {
+    type Error = StorageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let invalid = || StorageError::Corruption(format!("invalid limits: {:?}", bytes));
+        let bytes: [u8; Self::ENCODED_LEN] = bytes.try_into().map_err(|_| invalid())?;
+
+        let mut max_len = [0; 8];
+        max_len.copy_from_slice(&bytes[..8]);
+        let mut max_bytes = [0; 8];
+        max_bytes.copy_from_slice(&bytes[8..16]);
+        let mut max_deliveries = [0; 4];
+        max_deliveries.copy_from_slice(&bytes[17..]);
+
+        let overflow = match bytes[16] {
+            0 => Overflow::RejectNew,
+            1 => Overflow::DropOldest,
+            2 => Overflow::DropLowestPriority,
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            max_len: match u64::from_be_bytes(max_len) {
+                u64::MAX => None,
+                max => Some(max as usize),
+            },
+            max_bytes: match u64::from_be_bytes(max_bytes) {
+                u64::MAX => None,
+                max => Some(max),
+            },
+            overflow,
+            max_deliveries: match u32::from_be_bytes(max_deliveries) {
+                u32::MAX => None,
+                max => Some(max),
+            },
+        })
+    }
+}
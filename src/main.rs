@@ -1,18 +1,29 @@
-use std::{collections::BTreeMap, num::NonZeroU16};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    io,
+    num::NonZeroU16,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use prettytable::{cell, row, Table};
 use structopt::StructOpt;
 
 use mqtt_storage::{
-    app::{self, EgressStats, IngressStats},
-    Memory, QueueFile, Sled,
+    app::{self, EgressStats, IngressStats, RetainedStats, Workload},
+    Inline, Memory, Offload, QueueFile, RetainedStore, Sled, Storage, SubscriptionStore,
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
+    if let Some(Command::Migrate { from, to }) = &opt.command {
+        return migrate(from, to);
+    }
 
     let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(200);
@@ -29,29 +40,30 @@ async fn main() -> Result<()> {
         pb.set_message("tree backed memory");
 
         let storage = Memory::tree("q", opt.queues);
-        let res = app::run(storage, opt.duration, opt.parallel).await?;
+        let res = run(storage, &opt).await?;
         results.insert("BTreeMap", res);
 
         pb.set_message("vec backed memory");
 
         let storage = Memory::vec("q", opt.queues);
-        let res = app::run(storage, opt.duration, opt.parallel).await?;
+        let res = run(storage, &opt).await?;
         results.insert("VecDeque", res);
     }
 
     if opt.sled {
         pb.set_message("sled");
 
-        let storage = Sled::new("sled", "q", opt.queues);
-        let res = app::run(storage, opt.duration, opt.parallel).await?;
+        let storage = Sled::new("sled", "q", opt.queues)?;
+        let res = run(storage, &opt).await?;
         results.insert("sled", res);
     }
 
-    if opt.queue_file {
+    // queue files keep no retained messages or subscriptions
+    if opt.queue_file && !opt.retained && !opt.routed {
         pb.set_message("queue file");
 
-        let storage = QueueFile::new("qf", "q", opt.queues);
-        let res = app::run(storage, opt.duration, opt.parallel).await?;
+        let storage = QueueFile::new("qf", "q", opt.queues)?;
+        let res = run_queues(storage, &opt).await?;
         results.insert("queue file", res);
     }
 
@@ -61,8 +73,8 @@ async fn main() -> Result<()> {
         if opt.rocksdb {
             pb.set_message("rocksdb");
 
-            let storage = Rocksdb::new("rocksdb", "q", opt.queues);
-            let res = app::run(storage, opt.duration, opt.parallel).await?;
+            let storage = Rocksdb::new("rocksdb", "q", opt.queues)?;
+            let res = run(storage, &opt).await?;
             results.insert("rocksdb", res);
         }
     }
@@ -74,22 +86,163 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn print(results: BTreeMap<&str, (IngressStats, EgressStats)>) {
+#[derive(Debug)]
+enum Report {
+    Queues(IngressStats, EgressStats),
+    Retained(RetainedStats),
+}
+
+async fn run<S>(storage: S, opt: &Opt) -> Result<Report>
+where
+    S: Storage + RetainedStore + SubscriptionStore + Send + Sync + 'static,
+{
+    let workload = workload(opt);
+    if opt.routed {
+        limit_budget(&storage, opt);
+        let (ingress, egress) = match opt.exec {
+            Exec::Inline => app::routed(Inline::new(storage), workload).await?,
+            Exec::Offload => app::routed(Offload::new(storage, opt.pool), workload).await?,
+        };
+        return Ok(Report::Queues(ingress, egress));
+    }
+    if !opt.retained {
+        return run_queues(storage, opt).await;
+    }
+
+    let stats = match opt.exec {
+        Exec::Inline => app::retained(Inline::new(storage), workload).await?,
+        Exec::Offload => app::retained(Offload::new(storage, opt.pool), workload).await?,
+    };
+    Ok(Report::Retained(stats))
+}
+
+async fn run_queues<S>(storage: S, opt: &Opt) -> Result<Report>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    limit_budget(&storage, opt);
+    let workload = workload(opt);
+    let (ingress, egress) = match opt.exec {
+        Exec::Inline => app::run(Inline::new(storage), workload).await?,
+        Exec::Offload => app::run(Offload::new(storage, opt.pool), workload).await?,
+    };
+    Ok(Report::Queues(ingress, egress))
+}
+
+fn limit_budget(storage: &impl Storage, opt: &Opt) {
+    storage.budget().set_limit(opt.budget);
+    storage
+        .budget()
+        .set_timeout(Duration::from_millis(opt.budget_timeout));
+}
+
+fn workload(opt: &Opt) -> Workload {
+    Workload {
+        secs: opt.duration,
+        parallel: opt.parallel,
+        priorities: opt.priorities,
+        batch: opt.batch,
+        acks: opt.acks,
+        lease: opt.lease.map(Duration::from_millis),
+        wait: opt.wait,
+    }
+}
+
+// the target is opened like the source, so it must not hold anything yet; it
+// is verified by reading it back in full once everything is copied. A target
+// backend that cannot hold something the source does fails the copy, queue
+// files keep no retained messages, subscriptions or sessions, see
+// `mqtt_storage::copy`
+fn migrate(from: &Location, to: &Location) -> Result<()> {
+    if !from.path.exists() {
+        bail!("nothing to migrate at {}", from);
+    }
+    let source = from.open()?;
+    let target = to.open()?;
+    if !target.names().is_empty()
+        || !target.retained_messages()?.is_empty()
+        || !target.all_subscriptions()?.is_empty()
+    {
+        bail!("{} is not empty", to);
+    }
+
+    let mut total = 0;
+    for name in source.names() {
+        total += source.stats(&name)?.len as u64;
+    }
+
+    let pb = ProgressBar::new(total);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{bar:40} {pos}/{len} messages {wide_msg}")
+            .progress_chars("=> "),
+    );
+    pb.set_message(&format!("{} to {}", from, to));
+
+    let copied = mqtt_storage::copy(&*source, &*target, |copied| pb.set_position(copied))?;
+    pb.set_message("verifying");
+    let found = mqtt_storage::export(&*target, io::sink())?;
+    pb.finish_and_clear();
+
+    if found != copied {
+        bail!(
+            "{} does not match {} after copying: {:?}, expected {:?}",
+            to,
+            from,
+            found,
+            copied
+        );
+    }
+    println!(
+        "migrated {} queues with {} messages and {} inflight, {} retained messages and {} subscriptions from {} to {}, checksum {:08x}",
+        copied.queues,
+        copied.messages,
+        copied.inflight,
+        copied.retained,
+        copied.subscriptions,
+        from,
+        to,
+        copied.checksum
+    );
+
+    Ok(())
+}
+
+fn print(results: BTreeMap<&str, Report>) {
+    let (queues, retained): (Vec<_>, Vec<_>) = results
+        .into_iter()
+        .partition(|(_, report)| matches!(report, Report::Queues(..)));
+
+    if !queues.is_empty() {
+        print_queues(queues);
+    }
+    if !retained.is_empty() {
+        print_retained(retained);
+    }
+}
+
+fn print_queues(results: Vec<(&str, Report)>) {
     let mut table = Table::new();
     table.add_row(row![
         "storage",
         "writes",
         "total write",
+        "full",
         "empty iter",
         "loop iter",
         "reads",
         "total read"
     ]);
-    for (mode, (i, e)) in results {
+    for (mode, report) in results {
+        let (i, e) = match report {
+            Report::Queues(i, e) => (i, e),
+            Report::Retained(_) => continue,
+        };
         table.add_row(row![
             mode,
             i.total_items,
             HumanBytes(i.total_bytes),
+            i.full,
             e.empty,
             e.loop_iter,
             e.total_items,
@@ -100,6 +253,26 @@ fn print(results: BTreeMap<&str, (IngressStats, EgressStats)>) {
     table.printstd();
 }
 
+fn print_retained(results: Vec<(&str, Report)>) {
+    let mut table = Table::new();
+    table.add_row(row!["storage", "sets", "total set", "lookups", "matched"]);
+    for (mode, report) in results {
+        let stats = match report {
+            Report::Retained(stats) => stats,
+            Report::Queues(..) => continue,
+        };
+        table.add_row(row![
+            mode,
+            stats.sets,
+            HumanBytes(stats.total_bytes),
+            stats.lookups,
+            stats.matched
+        ]);
+    }
+
+    table.printstd();
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 struct Opt {
@@ -124,4 +297,180 @@ struct Opt {
 
     #[structopt(default_value = "1", long, short)]
     parallel: NonZeroU16,
+
+    #[structopt(
+        help = "Run storage operations inline on executor threads or offload them to a dedicated pool",
+        default_value = "inline",
+        possible_values = &["inline", "offload"],
+        long
+    )]
+    exec: Exec,
+
+    #[structopt(
+        help = "Number of threads in the offload pool",
+        default_value = "4",
+        long
+    )]
+    pool: usize,
+
+    #[structopt(
+        help = "Number of distinct priorities to push with",
+        default_value = "1",
+        long
+    )]
+    priorities: u16,
+
+    #[structopt(
+        help = "Number of messages stored per atomic write batch, 1 pushes them one by one",
+        default_value = "1",
+        long
+    )]
+    batch: usize,
+
+    #[structopt(
+        help = "Number of messages acknowledged per batch removal, 1 removes them one by one",
+        default_value = "1",
+        long
+    )]
+    acks: usize,
+
+    #[structopt(
+        help = "Check messages out with a lease of this many milliseconds instead of tracking them in egress",
+        long
+    )]
+    lease: Option<u64>,
+
+    #[structopt(
+        help = "Wait for a push into drained queues instead of polling them in egress",
+        long
+    )]
+    wait: bool,
+
+    #[structopt(
+        help = "Cap the bytes held by all queues together, ingress waits for egress to free space beyond it",
+        long
+    )]
+    budget: Option<u64>,
+
+    #[structopt(
+        help = "Milliseconds a push waits for the budget before it counts as full",
+        default_value = "1000",
+        long
+    )]
+    budget_timeout: u64,
+
+    #[structopt(
+        help = "Set and look up retained messages by topic filter instead of running queues, skips queue-file storage",
+        long
+    )]
+    retained: bool,
+
+    #[structopt(
+        help = "Push every message into the queues of clients with a matching subscription instead of a random queue, skips queue-file storage",
+        long
+    )]
+    routed: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(
+        about = "Copy everything from one persistent storage into a new one, keeping keys, priorities, expiry and deliveries"
+    )]
+    Migrate {
+        #[structopt(
+            help = "Storage to copy from as backend:path, it is left as it is",
+            long
+        )]
+        from: Location,
+
+        #[structopt(
+            help = "Storage to copy into as backend:path, it must not hold queues",
+            long
+        )]
+        to: Location,
+    },
+}
+
+// a persistent storage given as `backend:path`, like `sled:./data`
+#[derive(Debug)]
+struct Location {
+    backend: Backend,
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    Sled,
+    #[cfg(feature = "rocksdb")]
+    Rocksdb,
+    QueueFile,
+}
+
+impl Location {
+    // opening never wipes what is stored at the path
+    fn open(&self) -> Result<Box<dyn Storage>> {
+        let path = self.path.clone();
+        Ok(match self.backend {
+            Backend::Sled => Box::new(Sled::open(path)?),
+            #[cfg(feature = "rocksdb")]
+            Backend::Rocksdb => Box::new(mqtt_storage::Rocksdb::open(path)?),
+            Backend::QueueFile => Box::new(QueueFile::open(path)?),
+        })
+    }
+}
+
+impl FromStr for Location {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (backend, path) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expected backend:path, got {}", s))?;
+        let backend = match backend {
+            "sled" => Backend::Sled,
+            #[cfg(feature = "rocksdb")]
+            "rocksdb" => Backend::Rocksdb,
+            "queue-file" => Backend::QueueFile,
+            _ => return Err(anyhow!("unknown backend: {}", backend)),
+        };
+
+        Ok(Self {
+            backend,
+            path: path.into(),
+        })
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let backend = match self.backend {
+            Backend::Sled => "sled",
+            #[cfg(feature = "rocksdb")]
+            Backend::Rocksdb => "rocksdb",
+            Backend::QueueFile => "queue-file",
+        };
+        write!(f, "{}:{}", backend, self.path.display())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Exec {
+    Inline,
+    Offload,
+}
+
+impl FromStr for Exec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "inline" => Ok(Self::Inline),
+            "offload" => Ok(Self::Offload),
+            _ => Err(anyhow!("unknown execution mode: {}", s)),
+        }
+    }
 }
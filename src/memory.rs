@@ -1,83 +1,676 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
     fmt::Display,
-    sync::Arc,
+    ops::Bound,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
 };
 
-use dashmap::DashMap;
+use dashmap::{mapref::one::RefMut, DashMap};
+use tokio::sync::Notify;
 
-use crate::{Key, Payload, Storage};
+use crate::{
+    expiry,
+    lease::{self, Lease},
+    retained::{self, Trie},
+    stats::{Entry, Tally},
+    subscription::{self, Subscriptions},
+    topic, Budget, Checkout, Delivered, Delivery, Key, Limits, MessageState, Payload, PushOptions,
+    Pushed, QoS, QueueStats, ReadySet, RetainedStore, Storage, StorageError, StoredMessage,
+    SubscriptionOptions, SubscriptionStore,
+};
 
 #[derive(Default)]
 pub struct Memory<Q> {
-    queues: Arc<DashMap<String, Q>>,
+    queues: Arc<DashMap<String, Slot<Q>>>,
+    // held shared by single pushes and drops, and exclusively by
+    // `push_many`, so that room checked for a batch is still there when it
+    // is applied
+    pushes: RwLock<()>,
+    ready: ReadySet,
+    budget: Arc<Budget>,
+    retained: RwLock<Trie>,
+    subscriptions: RwLock<Subscriptions>,
 }
 
 impl Memory<BTreeQueue> {
     pub fn tree(prefix: impl Display, count: u16) -> Self {
+        let budget = Arc::new(Budget::default());
         let queues = (0..count)
-            .map(|i| (format!("{}{}", prefix, i), BTreeQueue::default()))
+            .map(|i| {
+                let slot = Slot::new(Limits::default(), &budget);
+                (format!("{}{}", prefix, i), slot)
+            })
             .collect();
 
         Self {
             queues: Arc::new(queues),
+            ready: ReadySet::default(),
+            budget,
+            pushes: RwLock::default(),
+            retained: RwLock::default(),
+            subscriptions: RwLock::default(),
         }
     }
 }
 
 impl Memory<VecQueue> {
     pub fn vec(prefix: impl Display, count: u16) -> Self {
+        let budget = Arc::new(Budget::default());
         let queues = (0..count)
-            .map(|i| (format!("{}{}", prefix, i), VecQueue::default()))
+            .map(|i| {
+                let slot = Slot::new(Limits::default(), &budget);
+                (format!("{}{}", prefix, i), slot)
+            })
             .collect();
 
         Self {
             queues: Arc::new(queues),
+            ready: ReadySet::default(),
+            budget,
+            pushes: RwLock::default(),
+            retained: RwLock::default(),
+            subscriptions: RwLock::default(),
         }
     }
 }
 
-impl<Q: Queue> Storage for Memory<Q> {
+impl<Q> Memory<Q> {
+    fn queue(&self, name: &str) -> Result<RefMut<'_, String, Slot<Q>>, StorageError> {
+        self.queues
+            .get_mut(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
+    }
+}
+
+impl<Q: Queue + Default> Storage for Memory<Q> {
     fn names(&self) -> Vec<String> {
         self.queues.iter().map(|i| i.key().clone()).collect()
     }
 
-    fn push(&self, name: &str, payload: Payload) -> Key {
-        if let Some(mut queue) = self.queues.get_mut(name) {
-            queue.push(payload)
-        } else {
-            panic!("no queue: {}", name)
+    fn contains(&self, name: &str) -> bool {
+        self.queues.contains_key(name)
+    }
+
+    fn create_queue_with(&self, name: &str, limits: Limits) -> Result<(), StorageError> {
+        crate::check_queue_name(name)?;
+        self.queues
+            .entry(name.into())
+            .or_insert_with(|| Slot::new(limits, &self.budget));
+        Ok(())
+    }
+
+    fn limits(&self, name: &str) -> Result<Limits, StorageError> {
+        Ok(self.queue(name)?.limits)
+    }
+
+    // removal waits for the shard lock, so an in-flight push either lands
+    // before the queue is gone or fails with `UnknownQueue` afterwards
+    fn drop_queue(&self, name: &str) -> Result<(), StorageError> {
+        let _pushes = self.pushes.read().unwrap_or_else(PoisonError::into_inner);
+        let (_, slot) = self
+            .queues
+            .remove(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+
+        slot.notify.notify_one();
+        Ok(())
+    }
+
+    fn subscribe(&self, name: &str) -> Result<Arc<Notify>, StorageError> {
+        Ok(self.queue(name)?.notify.clone())
+    }
+
+    fn ready(&self) -> &ReadySet {
+        &self.ready
+    }
+
+    fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    fn push_with(
+        &self,
+        name: &str,
+        payload: Payload,
+        options: PushOptions,
+    ) -> Result<Pushed, StorageError> {
+        let _pushes = self.pushes.read().unwrap_or_else(PoisonError::into_inner);
+        let mut slot = self.queue(name)?;
+        let size = payload.len();
+        let dropped = slot.make_room(name, &[(options.priority, size)])?;
+
+        let now = lease::now();
+        let key = slot.queue.push(options.priority, payload);
+        slot.tally.push(key, size, now);
+        if let Some(ttl) = options.ttl {
+            slot.tally.expire(key, expiry::deadline(now, ttl));
+        }
+        slot.notify.notify_one();
+        self.ready.mark(name);
+
+        Ok(Pushed::new(key, &slot.limits, dropped))
+    }
+
+    fn restore(
+        &self,
+        name: &str,
+        key: Key,
+        payload: Payload,
+        state: MessageState,
+    ) -> Result<Key, StorageError> {
+        let _pushes = self.pushes.read().unwrap_or_else(PoisonError::into_inner);
+        let mut slot = self.queue(name)?;
+        if slot.tally.remove(key).is_some() {
+            slot.queue.take(key);
+        }
+
+        let size = payload.len();
+        slot.queue.restore(key, payload);
+        slot.tally.push(key, size, state.pushed_at);
+        if let Some(deadline) = state.expires_at {
+            slot.tally.expire(key, deadline);
+        }
+        match state.lease {
+            Some(lease) => slot.leases.insert(key, lease),
+            None => slot.leases.remove(&key),
+        };
+        slot.notify.notify_one();
+        self.ready.mark(name);
+
+        Ok(key)
+    }
+
+    // queues are locked one at a time, but no other push or drop runs
+    // while a batch is applied, only acks and purges that free room; once
+    // every queue has room for its part and the budget for all of them,
+    // nothing can fail halfway, so there is nothing to roll back
+    fn push_many_with_priority(
+        &self,
+        items: Vec<(&str, u16, Payload)>,
+    ) -> Result<Vec<Key>, StorageError> {
+        let _pushes = self.pushes.write().unwrap_or_else(PoisonError::into_inner);
+
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+        let mut indexes = HashMap::new();
+        for (i, (name, _, _)) in items.iter().enumerate() {
+            let index = *indexes.entry(*name).or_insert_with(|| {
+                groups.push((*name, Vec::new()));
+                groups.len() - 1
+            });
+            groups[index].1.push(i);
+        }
+
+        let incoming = |group: &[usize]| -> Vec<_> {
+            group
+                .iter()
+                .map(|i| (items[*i].1, items[*i].2.len()))
+                .collect()
+        };
+        let mut dropped = Vec::with_capacity(groups.len());
+        let mut added = 0u64;
+        for (name, group) in &groups {
+            let slot = self.queue(name)?;
+            let incoming = incoming(group);
+            let keys = slot.tally.make_room(name, &slot.limits, &incoming)?;
+            let freed: u64 = keys
+                .iter()
+                .filter_map(|key| slot.tally.get(*key))
+                .map(|entry| entry.size as u64)
+                .sum();
+            let size: u64 = incoming.iter().map(|(_, size)| *size as u64).sum();
+            added += size.saturating_sub(freed);
+            dropped.push(keys);
+        }
+        if !self.budget.admits(added) {
+            return Err(StorageError::Full);
+        }
+
+        let now = lease::now();
+        let mut keys = vec![Key::default(); items.len()];
+        for ((name, group), dropped) in groups.iter().zip(dropped) {
+            let mut slot = self.queue(name)?;
+            slot.drop_messages(&dropped);
+            for i in group {
+                let (_, priority, payload) = &items[*i];
+                keys[*i] = slot.queue.push(*priority, payload.clone());
+                slot.tally.push(keys[*i], payload.len(), now);
+            }
+
+            slot.notify.notify_one();
+            self.ready.mark(name);
+        }
+
+        Ok(keys)
+    }
+
+    fn remove(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        self.remove_many(name, &[key])
+    }
+
+    fn remove_many(&self, name: &str, keys: &[Key]) -> Result<(), StorageError> {
+        let mut slot = self.queue(name)?;
+        slot.queue.remove_many(keys);
+        for key in keys {
+            slot.leases.remove(key);
+            slot.tally.remove(*key);
+        }
+
+        Ok(())
+    }
+
+    fn remove_up_to(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        let mut slot = self.queue(name)?;
+        slot.queue.remove_up_to(key);
+        slot.leases.retain(|leased, _| *leased > key);
+        slot.tally.remove_up_to(key);
+
+        Ok(())
+    }
+
+    // shards are locked one at a time, so the message is taken out of `from`
+    // first and put back under its key and with its lease if `to` refuses it
+    fn move_to(&self, from: &str, key: Key, to: &str) -> Result<Option<Key>, StorageError> {
+        let _pushes = self.pushes.read().unwrap_or_else(PoisonError::into_inner);
+        let now = lease::now();
+        let (payload, lease, entry) = {
+            let mut slot = self.queue(from)?;
+            slot.purge(now);
+            let payload = match slot.queue.take(key) {
+                Some(payload) => payload,
+                None => return Ok(None),
+            };
+            let entry = slot.tally.remove(key).unwrap_or(Entry {
+                size: payload.len(),
+                pushed_at: now,
+                expires_at: None,
+            });
+            (payload, slot.leases.remove(&key), entry)
+        };
+
+        let moved = self.queue(to).and_then(|mut slot| {
+            slot.make_room(to, &[(key.priority(), entry.size)])?;
+            let moved = slot.queue.push(key.priority(), payload.clone());
+            slot.tally.push(moved, entry.size, now);
+            if let Some(deadline) = entry.expires_at {
+                slot.tally.expire(moved, deadline);
+            }
+            slot.notify.notify_one();
+            Ok(moved)
+        });
+
+        match moved {
+            Ok(moved) => {
+                self.ready.mark(to);
+                Ok(Some(moved))
+            }
+            Err(e) => {
+                if let Ok(mut slot) = self.queue(from) {
+                    slot.queue.restore(key, payload);
+                    slot.tally.push(key, entry.size, entry.pushed_at);
+                    if let Some(deadline) = entry.expires_at {
+                        slot.tally.expire(key, deadline);
+                    }
+                    if let Some(lease) = lease {
+                        slot.leases.insert(key, lease);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn batch(&self, name: &str, size: usize) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        self.purge_expired(name)?;
+        let slot = self
+            .queues
+            .get(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+
+        Ok(slot.queue.batch(size))
+    }
+
+    fn batch_after(
+        &self,
+        name: &str,
+        after: Key,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        self.purge_expired(name)?;
+        let slot = self
+            .queues
+            .get(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+
+        Ok(slot
+            .queue
+            .iter_after(after)
+            .take(size)
+            .map(|(k, v)| (*k, v.clone()))
+            .collect())
+    }
+
+    fn peek(
+        &self,
+        name: &str,
+        after: Option<Key>,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload, MessageState)>, StorageError> {
+        let slot = self
+            .queues
+            .get(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+
+        let messages = match after {
+            Some(after) => slot.queue.iter_after(after),
+            None => slot.queue.iter(),
+        };
+        Ok(messages
+            .take(size)
+            .map(|(key, payload)| {
+                let lease = slot.leases.get(key).copied();
+                let state = MessageState::new(slot.tally.get(*key), lease);
+                (*key, payload.clone(), state)
+            })
+            .collect())
+    }
+
+    fn checkout(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        crate::checkout(self, name, || {
+            let mut slot = self.queue(name)?;
+            let now = lease::now();
+            slot.purge(now);
+            Ok(slot.checkout(size, lease, now))
+        })
+    }
+
+    fn batch_with_delivery(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> Result<VecDeque<Delivered>, StorageError> {
+        self.purge_expired(name)?;
+        let slot = self.queue(name)?;
+        Ok(slot
+            .queue
+            .batch(size)
+            .into_iter()
+            .map(|(key, payload)| {
+                let delivery = Delivery::of(slot.leases.get(&key).copied());
+                (key, payload, delivery)
+            })
+            .collect())
+    }
+
+    fn delivery(&self, name: &str, key: Key) -> Result<Delivery, StorageError> {
+        Ok(Delivery::of(self.queue(name)?.leases.get(&key).copied()))
+    }
+
+    fn mark_delivered(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        let mut slot = self.queue(name)?;
+        if slot.tally.get(key).is_some() {
+            let previous = slot.leases.get(&key).copied();
+            slot.leases.insert(key, Lease::delivered(previous));
         }
+
+        Ok(())
+    }
+
+    fn available_at(&self, name: &str) -> Result<Option<u64>, StorageError> {
+        let now = lease::now();
+        let mut slot = self.queue(name)?;
+        slot.purge(now);
+        let leases = slot.leases.values().map(|lease| Ok(*lease));
+        lease::available_at(slot.tally.len(), leases, now)
+    }
+
+    fn purge_expired(&self, name: &str) -> Result<usize, StorageError> {
+        Ok(self.queue(name)?.purge(lease::now()))
+    }
+
+    fn stats(&self, name: &str) -> Result<QueueStats, StorageError> {
+        let slot = self
+            .queues
+            .get(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+
+        Ok(slot.tally.stats(lease::now()))
+    }
+
+    fn retained_messages(&self) -> Result<Vec<StoredMessage>, StorageError> {
+        let trie = self.retained.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(trie.messages().into_iter().cloned().collect())
+    }
+
+    fn restore_retained(&self, message: &StoredMessage) -> Result<(), StorageError> {
+        self.set_retained(message)
     }
 
-    fn remove(&self, name: &str, key: Key) {
-        if let Some(mut queue) = self.queues.get_mut(name) {
-            queue.remove(key)
-        } else {
-            panic!("no queue: {}", name)
+    fn all_subscriptions(
+        &self,
+    ) -> Result<Vec<(String, String, SubscriptionOptions)>, StorageError> {
+        let subscriptions = self
+            .subscriptions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(subscriptions.all())
+    }
+
+    fn restore_subscription(
+        &self,
+        client_id: &str,
+        filter: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), StorageError> {
+        self.add_subscription(client_id, filter, options)
+    }
+}
+
+impl<Q> RetainedStore for Memory<Q> {
+    fn set_retained(&self, message: &StoredMessage) -> Result<(), StorageError> {
+        topic::validate_name(&message.topic)?;
+        if message.payload.is_empty() {
+            return self.clear_retained(&message.topic);
         }
+
+        let mut trie = self
+            .retained
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        trie.set(message.clone());
+        Ok(())
+    }
+
+    fn clear_retained(&self, topic: &str) -> Result<(), StorageError> {
+        let mut trie = self
+            .retained
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        trie.clear(topic);
+        Ok(())
     }
 
-    fn batch(&self, name: &str, size: usize) -> VecDeque<(Key, Payload)> {
-        if let Some(queue) = self.queues.get(name) {
-            queue.batch(size)
-        } else {
-            panic!("no queue: {}", name)
+    fn retained(&self, topic: &str) -> Result<Option<StoredMessage>, StorageError> {
+        let trie = self.retained.read().unwrap_or_else(PoisonError::into_inner);
+        let now = lease::now();
+        Ok(trie
+            .get(topic)
+            .filter(|message| retained::is_live(message, now))
+            .cloned())
+    }
+
+    fn matching(&self, filter: &str) -> Result<Vec<StoredMessage>, StorageError> {
+        topic::validate(filter)?;
+
+        let trie = self.retained.read().unwrap_or_else(PoisonError::into_inner);
+        let mut found = Vec::new();
+        trie.matching(filter, &mut found);
+
+        let now = lease::now();
+        Ok(found
+            .into_iter()
+            .filter(|message| retained::is_live(message, now))
+            .cloned()
+            .collect())
+    }
+}
+
+impl<Q> SubscriptionStore for Memory<Q> {
+    fn add_subscription(
+        &self,
+        client_id: &str,
+        filter: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), StorageError> {
+        subscription::validate(client_id, filter)?;
+        self.subscriptions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .add(client_id, filter, options);
+        Ok(())
+    }
+
+    fn remove_subscription(&self, client_id: &str, filter: &str) -> Result<(), StorageError> {
+        self.subscriptions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(client_id, filter);
+        Ok(())
+    }
+
+    fn remove_client(&self, client_id: &str) -> Result<(), StorageError> {
+        self.subscriptions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove_client(client_id);
+        Ok(())
+    }
+
+    fn subscriptions(&self, client_id: &str) -> Vec<(String, SubscriptionOptions)> {
+        let subscriptions = self
+            .subscriptions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        subscriptions.of(client_id)
+    }
+
+    fn route(&self, topic: &str) -> Vec<(String, QoS)> {
+        let subscriptions = self
+            .subscriptions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        subscriptions.route(topic)
+    }
+}
+
+// leases live next to the queue, so acks and checkouts see both under the
+// same shard lock
+#[derive(Debug)]
+struct Slot<Q> {
+    queue: Q,
+    leases: HashMap<Key, Lease>,
+    notify: Arc<Notify>,
+    tally: Tally,
+    limits: Limits,
+}
+
+impl<Q: Queue> Slot<Q> {
+    fn new(limits: Limits, budget: &Arc<Budget>) -> Self
+    where
+        Q: Default,
+    {
+        Self {
+            queue: Q::default(),
+            leases: HashMap::new(),
+            notify: Arc::default(),
+            tally: Tally::new(budget.clone()),
+            limits,
+        }
+    }
+
+    // drops whatever the overflow policy picks for `incoming` to fit
+    fn make_room(
+        &mut self,
+        name: &str,
+        incoming: &[(u16, usize)],
+    ) -> Result<Vec<Key>, StorageError> {
+        let dropped = self.tally.make_room(name, &self.limits, incoming)?;
+        self.drop_messages(&dropped);
+        Ok(dropped)
+    }
+
+    fn drop_messages(&mut self, keys: &[Key]) {
+        self.queue.remove_many(keys);
+        for key in keys {
+            self.leases.remove(key);
+            self.tally.remove(*key);
+        }
+    }
+
+    fn purge(&mut self, now: u64) -> usize {
+        let expired = self.tally.due(now);
+        if expired.is_empty() {
+            return 0;
+        }
+
+        self.queue.remove_many(&expired);
+        for key in &expired {
+            self.leases.remove(key);
+        }
+        self.tally.purge(&expired);
+        expired.len()
+    }
+
+    fn checkout(&mut self, size: usize, lease: Duration, now: u64) -> Checkout {
+        let Self {
+            queue,
+            leases,
+            limits,
+            ..
+        } = self;
+
+        let mut exhausted = Vec::new();
+        let batch: VecDeque<_> = queue
+            .iter()
+            .filter(|(key, _)| match leases.get(key) {
+                Some(lease) if lease.is_active(now) => false,
+                lease if limits.is_exhausted(lease.copied()) => {
+                    exhausted.push(**key);
+                    false
+                }
+                _ => true,
+            })
+            .take(size)
+            .map(|(key, payload)| (*key, payload.clone()))
+            .collect();
+        if !exhausted.is_empty() {
+            return (VecDeque::new(), exhausted);
+        }
+
+        for (key, _) in &batch {
+            let previous = leases.get(key).copied();
+            leases.insert(*key, Lease::renew(previous, now, lease));
         }
+        (batch, exhausted)
     }
 }
 
 #[derive(Debug, Default)]
 pub struct BTreeQueue {
-    last_key: Key,
+    offset: u64,
     items: BTreeMap<Key, Payload>,
 }
 
 impl Queue for BTreeQueue {
-    fn push(&mut self, item: Payload) -> Key {
-        let current_key = self.last_key;
+    fn push(&mut self, priority: u16, item: Payload) -> Key {
+        let current_key = Key::with_priority(priority, self.offset);
         self.items.insert(current_key, item);
-        self.last_key = current_key.next();
+        self.offset += 1;
 
         current_key
     }
@@ -86,71 +679,542 @@ impl Queue for BTreeQueue {
         self.items.remove(&key);
     }
 
-    fn batch(&self, count: usize) -> VecDeque<(Key, Payload)> {
-        self.items
-            .iter()
-            .take(count)
-            .map(|(k, v)| (*k, v.to_vec()))
-            .collect()
+    fn remove_up_to(&mut self, key: Key) {
+        self.items = self.items.split_off(&key);
+        self.items.remove(&key);
+    }
+
+    fn take(&mut self, key: Key) -> Option<Payload> {
+        self.items.remove(&key)
+    }
+
+    fn restore(&mut self, key: Key, item: Payload) {
+        self.offset = self.offset.max(key.1.saturating_add(1));
+        self.items.insert(key, item);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Payload)> + '_> {
+        Box::new(self.items.iter())
+    }
+
+    fn iter_after(&self, after: Key) -> Box<dyn Iterator<Item = (&Key, &Payload)> + '_> {
+        Box::new(self.items.range((Bound::Excluded(after), Bound::Unbounded)))
     }
 }
 
 pub trait Queue {
-    fn push(&mut self, item: Payload) -> Key;
+    fn push(&mut self, priority: u16, item: Payload) -> Key;
 
     fn remove(&mut self, key: Key);
 
-    fn batch(&self, count: usize) -> VecDeque<(Key, Payload)>;
+    fn remove_many(&mut self, keys: &[Key]) {
+        for key in keys {
+            self.remove(*key);
+        }
+    }
+
+    fn remove_up_to(&mut self, key: Key);
+
+    // takes a message out to move it to another queue
+    fn take(&mut self, key: Key) -> Option<Payload>;
+
+    // puts a message back under its key, later pushes follow it
+    fn restore(&mut self, key: Key, item: Payload);
+
+    // messages in delivery order
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Payload)> + '_>;
+
+    fn iter_after(&self, after: Key) -> Box<dyn Iterator<Item = (&Key, &Payload)> + '_>;
+
+    fn batch(&self, count: usize) -> VecDeque<(Key, Payload)> {
+        self.iter()
+            .take(count)
+            .map(|(k, v)| (*k, v.clone()))
+            .collect()
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct VecQueue {
-    last_key: Key,
+    offset: u64,
     items: VecDeque<(Key, Payload)>,
 }
 
 impl Queue for VecQueue {
-    fn push(&mut self, item: Payload) -> Key {
-        let current_key = self.last_key;
-        self.items.push_front((current_key, item));
-        self.last_key = current_key.next();
+    // a message only jumps ahead of lower priorities, so this is a plain
+    // `push_back` unless priorities are mixed
+    fn push(&mut self, priority: u16, item: Payload) -> Key {
+        let current_key = Key::with_priority(priority, self.offset);
+        let index = self.items.partition_point(|(k, _)| *k < current_key);
+        self.items.insert(index, (current_key, item));
+        self.offset += 1;
 
         current_key
     }
 
     fn remove(&mut self, key: Key) {
-        let index = self.last_key.offset(key);
-        self.items.remove(index as usize);
+        if let Ok(index) = self.items.binary_search_by_key(&key, |(k, _)| *k) {
+            self.items.remove(index);
+        }
     }
 
-    fn batch(&self, count: usize) -> VecDeque<(Key, Payload)> {
-        self.items
-            .iter()
-            .take(count)
-            .map(|(k, v)| (*k, v.to_vec()))
-            .collect()
+    fn remove_up_to(&mut self, key: Key) {
+        let index = self.items.partition_point(|(k, _)| *k <= key);
+        self.items.drain(..index);
+    }
+
+    fn take(&mut self, key: Key) -> Option<Payload> {
+        let index = self.items.binary_search_by_key(&key, |(k, _)| *k).ok()?;
+        self.items.remove(index).map(|(_, item)| item)
+    }
+
+    fn restore(&mut self, key: Key, item: Payload) {
+        self.offset = self.offset.max(key.1.saturating_add(1));
+        let index = self.items.partition_point(|(k, _)| *k < key);
+        self.items.insert(index, (key, item));
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Key, &Payload)> + '_> {
+        Box::new(self.items.iter().map(|(k, v)| (k, v)))
+    }
+
+    fn iter_after(&self, after: Key) -> Box<dyn Iterator<Item = (&Key, &Payload)> + '_> {
+        let index = self.items.partition_point(|(k, _)| *k <= after);
+        Box::new(self.items.range(index..).map(|(k, v)| (k, v)))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::{dead_letter_queue, Overflow};
+
     use super::*;
 
     #[test]
     fn vec_queue_works() {
         let mut queue = VecQueue::default();
-        let key = queue.push(vec![1, 2, 3]);
+        let key = queue.push(0, Payload::from(vec![1, 2, 3]));
         queue.remove(key);
 
         assert!(queue.items.is_empty());
     }
 
+    #[test]
+    fn memory_drops_queue() {
+        let storage = Memory::tree("q", 1);
+        storage.create_queue("session").unwrap();
+        storage
+            .push("session", Payload::from(vec![1, 2, 3]))
+            .unwrap();
+
+        assert!(storage.contains("session"));
+
+        storage.drop_queue("session").unwrap();
+
+        assert!(!storage.contains("session"));
+        assert!(matches!(
+            storage.push("session", Payload::from(vec![1])),
+            Err(StorageError::UnknownQueue(_))
+        ));
+        assert!(matches!(
+            storage.drop_queue("session"),
+            Err(StorageError::UnknownQueue(_))
+        ));
+    }
+
     #[test]
     fn vec_queue_batch_works() {
         let mut queue = VecQueue::default();
-        let key = queue.push(vec![1, 2, 3]);
+        let key = queue.push(0, Payload::from(vec![1, 2, 3]));
         queue.remove(key);
 
         assert!(queue.items.is_empty());
     }
+
+    #[test]
+    fn queues_deliver_higher_priority_first() {
+        fn check(storage: impl Storage) {
+            storage.push("q0", Payload::from(vec![1])).unwrap();
+            storage
+                .push_with_priority("q0", 5, Payload::from(vec![2]))
+                .unwrap();
+            storage.push("q0", Payload::from(vec![3])).unwrap();
+            storage
+                .push_with_priority("q0", 5, Payload::from(vec![4]))
+                .unwrap();
+
+            let payloads: Vec<_> = storage
+                .batch("q0", 10)
+                .unwrap()
+                .into_iter()
+                .map(|(_, payload)| payload)
+                .collect();
+            assert_eq!(payloads, vec![vec![2], vec![4], vec![1], vec![3]]);
+        }
+
+        check(Memory::tree("q", 1));
+        check(Memory::vec("q", 1));
+    }
+
+    #[test]
+    fn push_many_is_all_or_nothing() {
+        let storage = Memory::tree("q", 2);
+
+        let keys = storage
+            .push_many(vec![
+                ("q0", Payload::from(vec![1])),
+                ("q1", Payload::from(vec![1])),
+                ("q0", Payload::from(vec![2])),
+            ])
+            .unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                Key::with_offset(0),
+                Key::with_offset(0),
+                Key::with_offset(1)
+            ]
+        );
+
+        assert!(matches!(
+            storage.push_many(vec![("q0", Payload::from(vec![3])), ("q2", Payload::from(vec![3]))]),
+            Err(StorageError::UnknownQueue(name)) if name == "q2"
+        ));
+        assert_eq!(storage.batch("q0", 10).unwrap().len(), 2);
+        assert_eq!(storage.batch("q1", 10).unwrap().len(), 1);
+
+        // each part fits into the budget, both together do not
+        storage.budget().set_limit(Some(6));
+        assert!(matches!(
+            storage.push_many(vec![
+                ("q0", Payload::from(vec![3; 2])),
+                ("q1", Payload::from(vec![3; 2]))
+            ]),
+            Err(StorageError::Full)
+        ));
+        assert_eq!(storage.batch("q0", 10).unwrap().len(), 2);
+        assert_eq!(storage.batch("q1", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reserved_queue_names_are_rejected() {
+        let storage = Memory::tree("q", 0);
+        assert!(matches!(
+            storage.create_queue("__leases"),
+            Err(StorageError::InvalidQueueName(name)) if name == "__leases"
+        ));
+        assert!(!storage.contains("__leases"));
+
+        for name in &["", ".", "..", "a/b", "a\\b", "a\0b"] {
+            assert!(matches!(
+                storage.create_queue(name),
+                Err(StorageError::InvalidQueueName(_))
+            ));
+        }
+        assert!(storage.names().is_empty());
+    }
+
+    #[test]
+    fn push_many_keeps_priorities() {
+        fn check(storage: impl Storage) {
+            let keys = storage
+                .push_many_with_priority(vec![
+                    ("q0", 0, Payload::from(vec![1])),
+                    ("q0", 2, Payload::from(vec![2])),
+                    ("q1", 1, Payload::from(vec![3])),
+                ])
+                .unwrap();
+            assert_eq!(keys[1], Key::with_priority(2, keys[0].1 + 1));
+            assert_eq!(keys[2].priority(), 1);
+
+            let batch = storage.batch("q0", 10).unwrap();
+            assert_eq!(
+                batch,
+                vec![
+                    (keys[1], Payload::from(vec![2])),
+                    (keys[0], Payload::from(vec![1]))
+                ]
+            );
+        }
+
+        check(Memory::tree("q", 2));
+        check(Memory::vec("q", 2));
+    }
+
+    #[test]
+    fn queues_remove_acknowledged_ranges() {
+        fn check(storage: impl Storage) {
+            let keys: Vec<_> = (0..6)
+                .map(|i| {
+                    storage
+                        .push_with_priority("q0", i % 2, Payload::from(vec![i as u8]))
+                        .unwrap()
+                })
+                .collect();
+
+            storage.remove_many("q0", &[keys[1], keys[4]]).unwrap();
+            // delivery order is 1, 3, 5, 0, 2, 4
+            storage.remove_up_to("q0", keys[0]).unwrap();
+
+            let batch = storage.batch("q0", 10).unwrap();
+            assert_eq!(batch, vec![(keys[2], Payload::from(vec![2]))]);
+        }
+
+        check(Memory::tree("q", 1));
+        check(Memory::vec("q", 1));
+    }
+
+    #[test]
+    fn queues_remove_up_to_the_last_offset() {
+        fn check(storage: impl Storage) {
+            storage
+                .push_with_priority("q0", 1, Payload::from(vec![1]))
+                .unwrap();
+            let low = storage.push("q0", Payload::from(vec![2])).unwrap();
+
+            storage
+                .remove_up_to("q0", Key::with_priority(1, u64::MAX))
+                .unwrap();
+            let batch = storage.batch("q0", 10).unwrap();
+            assert_eq!(batch, vec![(low, Payload::from(vec![2]))]);
+            assert_eq!(storage.len("q0").unwrap(), 1);
+        }
+
+        check(Memory::tree("q", 1));
+        check(Memory::vec("q", 1));
+    }
+
+    #[test]
+    fn checkout_hides_leased_messages() {
+        let storage = Memory::tree("q", 1);
+        let keys: Vec<_> = (0..3)
+            .map(|i| storage.push("q0", Payload::from(vec![i])).unwrap())
+            .collect();
+
+        let first = storage.checkout("q0", 2, Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            first,
+            vec![
+                (keys[0], Payload::from(vec![0])),
+                (keys[1], Payload::from(vec![1]))
+            ]
+        );
+
+        let second = storage.checkout("q0", 2, Duration::from_secs(60)).unwrap();
+        assert_eq!(second, vec![(keys[2], Payload::from(vec![2]))]);
+
+        storage.remove("q0", keys[0]).unwrap();
+        assert!(storage
+            .checkout("q0", 2, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+        assert_eq!(storage.batch("q0", 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn checkout_redelivers_expired_leases() {
+        let storage = Memory::vec("q", 1);
+        let key = storage.push("q0", Payload::from(vec![1])).unwrap();
+
+        assert_eq!(
+            storage
+                .checkout("q0", 1, Duration::from_secs(0))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            storage.checkout("q0", 1, Duration::from_secs(60)).unwrap(),
+            vec![(key, Payload::from(vec![1]))]
+        );
+        assert!(storage
+            .checkout("q0", 1, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn full_queues_apply_overflow_policy() {
+        let storage = Memory::tree("q", 0);
+        let limits = |overflow| Limits {
+            max_len: Some(2),
+            max_bytes: None,
+            overflow,
+            max_deliveries: None,
+        };
+        storage
+            .create_queue_with("reject", limits(Overflow::RejectNew))
+            .unwrap();
+        storage
+            .create_queue_with("oldest", limits(Overflow::DropOldest))
+            .unwrap();
+        storage
+            .create_queue_with("lowest", limits(Overflow::DropLowestPriority))
+            .unwrap();
+
+        for name in &["reject", "oldest", "lowest"] {
+            storage
+                .push_with_priority(name, 1, Payload::from_static(b"a"))
+                .unwrap();
+            storage.push(name, Payload::from_static(b"b")).unwrap();
+        }
+
+        assert!(matches!(
+            storage.push("reject", Payload::from_static(b"c")),
+            Err(StorageError::QueueFull(name)) if name == "reject"
+        ));
+
+        let pushed = storage
+            .push_with("oldest", Payload::from_static(b"c"), PushOptions::default())
+            .unwrap();
+        assert_eq!(pushed.overflow, Some(Overflow::DropOldest));
+        assert_eq!(pushed.dropped, vec![Key::with_priority(1, 0)]);
+
+        let pushed = storage
+            .push_with(
+                "lowest",
+                Payload::from_static(b"c"),
+                PushOptions {
+                    priority: 2,
+                    ..PushOptions::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(pushed.overflow, Some(Overflow::DropLowestPriority));
+        assert_eq!(pushed.dropped, vec![Key::with_priority(0, 1)]);
+
+        // a whole batch is rejected before anything is dropped or pushed
+        assert!(matches!(
+            storage.push_many(vec![
+                ("oldest", Payload::from_static(b"d")),
+                ("oldest", Payload::from_static(b"e")),
+                ("oldest", Payload::from_static(b"f")),
+            ]),
+            Err(StorageError::QueueFull(_))
+        ));
+        assert_eq!(storage.len("oldest").unwrap(), 2);
+        assert_eq!(storage.len("reject").unwrap(), 2);
+    }
+
+    #[test]
+    fn expired_messages_are_never_read() {
+        let storage = Memory::tree("q", 1);
+        let expired = storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_secs(0))
+            .unwrap();
+        let kept = storage
+            .push_with_expiry("q0", Payload::from_static(b"b"), Duration::from_secs(3600))
+            .unwrap();
+
+        assert_eq!(storage.len("q0").unwrap(), 2);
+        assert_eq!(storage.batch_after("q0", expired, 10).unwrap().len(), 1);
+        assert_eq!(
+            storage.checkout("q0", 10, Duration::from_secs(1)).unwrap(),
+            vec![(kept, Payload::from_static(b"b"))]
+        );
+
+        let stats = storage.stats("q0").unwrap();
+        assert_eq!((stats.len, stats.expired), (1, 1));
+        assert_eq!(storage.purge_expired("q0").unwrap(), 0);
+    }
+
+    #[test]
+    fn deliveries_are_counted_with_checkouts() {
+        let storage = Memory::tree("q", 1);
+        let key = storage.push("q0", Payload::from_static(b"a")).unwrap();
+        assert!(!storage.batch_with_delivery("q0", 1).unwrap()[0].2.is_dup());
+
+        storage.mark_delivered("q0", key).unwrap();
+        assert!(storage.batch_with_delivery("q0", 1).unwrap()[0].2.is_dup());
+        storage.checkout("q0", 1, Duration::from_secs(60)).unwrap();
+        storage.mark_delivered("q0", key).unwrap();
+
+        // marking keeps the lease the checkout took
+        assert!(storage
+            .checkout("q0", 1, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+        assert_eq!(storage.delivery("q0", key).unwrap().count, 3);
+
+        storage.remove("q0", key).unwrap();
+        storage.mark_delivered("q0", key).unwrap();
+        assert_eq!(storage.delivery("q0", key).unwrap(), Delivery::default());
+    }
+
+    #[test]
+    fn marked_deliveries_do_not_exhaust_messages() {
+        let storage = Memory::tree("q", 0);
+        let limits = Limits {
+            max_deliveries: Some(1),
+            ..Limits::default()
+        };
+        storage.create_queue_with("q0", limits).unwrap();
+        let key = storage.push("q0", Payload::from_static(b"a")).unwrap();
+        for _ in 0..3 {
+            storage.mark_delivered("q0", key).unwrap();
+        }
+
+        let batch = storage.checkout("q0", 1, Duration::from_secs(0)).unwrap();
+        assert_eq!(batch[0].0, key);
+        assert_eq!(storage.batch_with_delivery("q0", 1).unwrap()[0].2.count, 4);
+
+        // the checkout used up the only delivery
+        assert!(storage
+            .checkout("q0", 1, Duration::from_secs(0))
+            .unwrap()
+            .is_empty());
+        assert_eq!(storage.len(&dead_letter_queue("q0")).unwrap(), 1);
+    }
+
+    #[test]
+    fn exhausted_messages_move_to_dead_letter_queue() {
+        let storage = Memory::vec("q", 0);
+        let limits = Limits {
+            max_deliveries: Some(2),
+            ..Limits::default()
+        };
+        storage.create_queue_with("q0", limits).unwrap();
+        let first = storage
+            .push_with_priority("q0", 1, Payload::from_static(b"a"))
+            .unwrap();
+        let second = storage.push("q0", Payload::from_static(b"b")).unwrap();
+
+        for _ in 0..2 {
+            let batch = storage.checkout("q0", 1, Duration::from_secs(0)).unwrap();
+            assert_eq!(batch[0].0, first);
+        }
+        assert_eq!(
+            storage.checkout("q0", 1, Duration::from_secs(60)).unwrap(),
+            vec![(second, Payload::from_static(b"b"))]
+        );
+
+        let dead = storage.batch(&dead_letter_queue("q0"), 10).unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].0.priority(), 1);
+        assert_eq!(dead[0].1, Payload::from_static(b"a"));
+        assert_eq!(storage.move_to("q0", first, "q0.dlq").unwrap(), None);
+
+        // a move the target refuses leaves the message where it was
+        let full = Limits {
+            max_len: Some(0),
+            ..Limits::default()
+        };
+        storage.create_queue_with("full", full).unwrap();
+        assert!(storage.move_to("q0", second, "full").is_err());
+        assert_eq!(
+            storage.batch("q0", 10).unwrap(),
+            vec![(second, Payload::from_static(b"b"))]
+        );
+        assert_eq!(storage.len("q0").unwrap(), 1);
+    }
+
+    #[test]
+    fn it_reads_batches_after_key() {
+        crate::tests::it_reads_batches_after_key(&Memory::tree("q", 1));
+    }
+
+    #[test]
+    fn it_leases_nothing_when_dead_lettering_fails() {
+        crate::tests::it_leases_nothing_when_dead_lettering_fails(&Memory::tree("q", 0));
+    }
 }
@@ -0,0 +1,286 @@
+use std::{
+    convert::{TryFrom, TryInto},
+    time::Duration,
+};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::{Payload, StorageError};
+
+// an MQTT publish as it is kept in a queue. Messages are stored in a compact
+// encoding that starts with a version byte, so that records written by older
+// versions stay readable. Strings and binary properties are capped at 65535
+// bytes like on the wire
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct StoredMessage {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: Properties,
+    // wall clock milliseconds the broker received the publish at
+    pub received_at: u64,
+    pub payload: Payload,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl Default for QoS {
+    fn default() -> Self {
+        Self::AtMostOnce
+    }
+}
+
+// MQTT 5 publish properties worth keeping, the topic alias and subscription
+// identifiers only make sense for a single connection
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Properties {
+    pub payload_format_indicator: Option<u8>,
+    // seconds, applied as ttl when the message is pushed
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Payload>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+const VERSION: u8 = 1;
+
+// bits of the flags byte next to the qos in the lowest two
+const RETAIN: u8 = 1 << 2;
+const PAYLOAD_FORMAT: u8 = 1 << 3;
+const EXPIRY: u8 = 1 << 4;
+const CONTENT_TYPE: u8 = 1 << 5;
+const RESPONSE_TOPIC: u8 = 1 << 6;
+const CORRELATION_DATA: u8 = 1 << 7;
+
+impl StoredMessage {
+    pub fn ttl(&self) -> Option<Duration> {
+        self.properties
+            .message_expiry_interval
+            .map(|secs| Duration::from_secs(secs.into()))
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        let properties = &self.properties;
+        let string = |s: &Option<String>| s.as_ref().map_or(0, |s| 2 + s.len());
+
+        // version, flags, receive time, topic and user property count
+        let mut len = 1 + 1 + 8 + 2 + self.topic.len() + 2;
+        len += properties.payload_format_indicator.map_or(0, |_| 1);
+        len += properties.message_expiry_interval.map_or(0, |_| 4);
+        len += string(&properties.content_type) + string(&properties.response_topic);
+        len += properties
+            .correlation_data
+            .as_ref()
+            .map_or(0, |data| 2 + data.len());
+        len += properties
+            .user_properties
+            .iter()
+            .map(|(key, value)| 4 + key.len() + value.len())
+            .sum::<usize>();
+
+        len + self.payload.len()
+    }
+
+    // layout of version 1: version, flags, receive time, topic, the optional
+    // properties flagged as present, user properties, and the payload as
+    // the rest
+    pub fn to_bytes(&self) -> Result<Payload, StorageError> {
+        let properties = &self.properties;
+        let mut flags = self.qos as u8;
+        for (set, flag) in [
+            (self.retain, RETAIN),
+            (
+                properties.payload_format_indicator.is_some(),
+                PAYLOAD_FORMAT,
+            ),
+            (properties.message_expiry_interval.is_some(), EXPIRY),
+            (properties.content_type.is_some(), CONTENT_TYPE),
+            (properties.response_topic.is_some(), RESPONSE_TOPIC),
+            (properties.correlation_data.is_some(), CORRELATION_DATA),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+
+        let mut bytes = BytesMut::with_capacity(self.encoded_len());
+        bytes.put_u8(VERSION);
+        bytes.put_u8(flags);
+        bytes.put_u64(self.received_at);
+        put_field(&mut bytes, "topic", self.topic.as_bytes())?;
+
+        if let Some(indicator) = properties.payload_format_indicator {
+            bytes.put_u8(indicator);
+        }
+        if let Some(interval) = properties.message_expiry_interval {
+            bytes.put_u32(interval);
+        }
+        if let Some(content_type) = &properties.content_type {
+            put_field(&mut bytes, "content type", content_type.as_bytes())?;
+        }
+        if let Some(response_topic) = &properties.response_topic {
+            put_field(&mut bytes, "response topic", response_topic.as_bytes())?;
+        }
+        if let Some(data) = &properties.correlation_data {
+            put_field(&mut bytes, "correlation data", data)?;
+        }
+
+        let count = u16::try_from(properties.user_properties.len())
+            .map_err(|_| StorageError::InvalidMessage("too many user properties".into()))?;
+        bytes.put_u16(count);
+        for (key, value) in &properties.user_properties {
+            put_field(&mut bytes, "user property", key.as_bytes())?;
+            put_field(&mut bytes, "user property", value.as_bytes())?;
+        }
+
+        bytes.put_slice(&self.payload);
+        Ok(bytes.freeze())
+    }
+}
+
+// the payload is a slice of the stored bytes, it is not copied
+impl TryFrom<Payload> for StoredMessage {
+    type Error = StorageError;
+
+    fn try_from(bytes: Payload) -> Result<Self, Self::Error> {
+        let mut reader = Reader(bytes);
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(StorageError::Corruption(format!(
+                "unsupported message version: {}",
+                version
+            )));
+        }
+
+        let flags = reader.u8()?;
+        let qos = match flags & 0b11 {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => return Err(StorageError::Corruption("invalid message qos".into())),
+        };
+        let received_at = u64::from_be_bytes(reader.array()?);
+        let topic = reader.string()?;
+
+        let mut properties = Properties::default();
+        if flags & PAYLOAD_FORMAT != 0 {
+            properties.payload_format_indicator = Some(reader.u8()?);
+        }
+        if flags & EXPIRY != 0 {
+            properties.message_expiry_interval = Some(u32::from_be_bytes(reader.array()?));
+        }
+        if flags & CONTENT_TYPE != 0 {
+            properties.content_type = Some(reader.string()?);
+        }
+        if flags & RESPONSE_TOPIC != 0 {
+            properties.response_topic = Some(reader.string()?);
+        }
+        if flags & CORRELATION_DATA != 0 {
+            properties.correlation_data = Some(reader.field()?);
+        }
+
+        let count = u16::from_be_bytes(reader.array()?);
+        properties.user_properties = (0..count)
+            .map(|_| Ok((reader.string()?, reader.string()?)))
+            .collect::<Result<_, StorageError>>()?;
+
+        Ok(Self {
+            topic,
+            qos,
+            retain: flags & RETAIN != 0,
+            properties,
+            received_at,
+            payload: reader.0,
+        })
+    }
+}
+
+fn put_field(bytes: &mut BytesMut, what: &str, field: &[u8]) -> Result<(), StorageError> {
+    let len = u16::try_from(field.len())
+        .map_err(|_| StorageError::InvalidMessage(format!("{} is too long", what)))?;
+    bytes.put_u16(len);
+    bytes.put_slice(field);
+    Ok(())
+}
+
+// reads from the front, whatever is left is the payload
+struct Reader(Payload);
+
+impl Reader {
+    fn take(&mut self, len: usize) -> Result<Payload, StorageError> {
+        if self.0.len() < len {
+            return Err(StorageError::Corruption("truncated message".into()));
+        }
+        Ok(self.0.split_to(len))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StorageError> {
+        let bytes = self.take(N)?;
+        Ok((&bytes[..]).try_into().expect("taken exactly N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, StorageError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn field(&mut self) -> Result<Payload, StorageError> {
+        let len = u16::from_be_bytes(self.array()?);
+        self.take(len.into())
+    }
+
+    fn string(&mut self) -> Result<String, StorageError> {
+        String::from_utf8(self.field()?.to_vec())
+            .map_err(|_| StorageError::Corruption("invalid utf-8 in message".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_message_round_trips() {
+        let message = StoredMessage {
+            topic: "devices/7/temperature".into(),
+            qos: QoS::ExactlyOnce,
+            retain: true,
+            properties: Properties {
+                payload_format_indicator: Some(1),
+                message_expiry_interval: Some(30),
+                content_type: Some("application/json".into()),
+                response_topic: None,
+                correlation_data: Some(Payload::from_static(b"\x00\x01")),
+                user_properties: vec![("unit".into(), "celsius".into())],
+            },
+            received_at: 1_600_000_000_000,
+            payload: Payload::from_static(b"{\"value\":21.5}"),
+        };
+
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(bytes.len(), message.encoded_len());
+        assert_eq!(StoredMessage::try_from(bytes.clone()).unwrap(), message);
+        assert_eq!(message.ttl(), Some(Duration::from_secs(30)));
+
+        let plain = StoredMessage::default();
+        let encoded = plain.to_bytes().unwrap();
+        assert_eq!(encoded.len(), 14);
+        assert_eq!(StoredMessage::try_from(encoded).unwrap(), plain);
+
+        assert!(matches!(
+            StoredMessage::try_from(bytes.slice(..bytes.len() - 15)),
+            Err(StorageError::Corruption(_))
+        ));
+        let mut newer = bytes.to_vec();
+        newer[0] = 2;
+        assert!(matches!(
+            StoredMessage::try_from(Payload::from(newer)),
+            Err(StorageError::Corruption(_))
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use crate::StorageError;
+
+// how far ahead of the offsets handed out the persisted mark is moved, so
+// that only one push in this many writes it
+const RESERVE: u64 = 1024;
+
+// offsets of a queue survive a restart through a persisted high-water mark
+// that is always ahead of every offset handed out, so that a queue drained
+// before a restart never hands out a key again; offsets reserved but never
+// used are skipped after a restart
+#[derive(Debug, Default)]
+pub(crate) struct Offsets {
+    next: AtomicU64,
+    // the persisted mark, offsets below it may be handed out
+    reserved: Mutex<u64>,
+}
+
+impl Offsets {
+    pub(crate) fn new(next: u64) -> Self {
+        Self {
+            next: AtomicU64::new(next),
+            reserved: Mutex::new(next),
+        }
+    }
+
+    // the mark is written before the offset is handed out, so a message is
+    // never stored under an offset beyond it
+    pub(crate) fn next(
+        &self,
+        persist: impl FnOnce(u64) -> Result<(), StorageError>,
+    ) -> Result<u64, StorageError> {
+        let offset = self.next.fetch_add(1, Ordering::SeqCst);
+        self.reserve(offset, persist)?;
+        Ok(offset)
+    }
+
+    // moves past an offset that was not handed out here, like the one of a
+    // restored message
+    pub(crate) fn advance(
+        &self,
+        offset: u64,
+        persist: impl FnOnce(u64) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        self.next
+            .fetch_max(offset.saturating_add(1), Ordering::SeqCst);
+        self.reserve(offset, persist)
+    }
+
+    fn reserve(
+        &self,
+        offset: u64,
+        persist: impl FnOnce(u64) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        let mut reserved = self.reserved.lock().unwrap_or_else(PoisonError::into_inner);
+        if offset < *reserved {
+            return Ok(());
+        }
+
+        let mark = offset.saturating_add(RESERVE);
+        persist(mark)?;
+        *reserved = mark;
+        Ok(())
+    }
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<u64, StorageError> {
+    bytes
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| StorageError::Corruption(format!("invalid offset mark: {:?}", bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_persist_a_mark_ahead() {
+        let marks = Mutex::new(Vec::new());
+        let persist = |mark| {
+            marks.lock().unwrap().push(mark);
+            Ok(())
+        };
+
+        let offsets = Offsets::new(5);
+        assert_eq!(offsets.next(persist).unwrap(), 5);
+        assert_eq!(offsets.next(persist).unwrap(), 6);
+        assert_eq!(*marks.lock().unwrap(), [5 + RESERVE]);
+
+        offsets.advance(5 + RESERVE, persist).unwrap();
+        assert_eq!(offsets.next(persist).unwrap(), 6 + RESERVE);
+        assert_eq!(*marks.lock().unwrap(), [5 + RESERVE, 5 + 2 * RESERVE]);
+
+        // a mark that cannot be written hands out no offset below it
+        let offsets = Offsets::new(0);
+        assert!(offsets.next(|_| Err(StorageError::Full)).is_err());
+        assert_eq!(offsets.next(persist).unwrap(), 1);
+    }
+}
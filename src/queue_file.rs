@@ -1,17 +1,60 @@
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, VecDeque},
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap, VecDeque},
+    convert::{TryFrom, TryInto},
     fmt::Display,
-    path::Path,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use dashmap::DashMap;
+use dashmap::{
+    mapref::{entry::Entry, one::RefMut},
+    DashMap,
+};
+use tokio::sync::Notify;
+
+use crate::{
+    expiry,
+    lease::{self, Lease},
+    offset::{self, Offsets},
+    stats::{self, Tally},
+    Budget, Checkout, Delivered, Delivery, Key, Limits, MessageState, Payload, PushOptions, Pushed,
+    QueueStats, ReadySet, Storage, StorageError,
+};
+
+const EXTENSION: &str = "qf";
+
+// every element is prefixed with its offset and the time it was pushed, so
+// that keys and ages of the messages left in a file can be restored on open
+const HEADER_LEN: usize = 16;
+
+// high-water mark of the offsets shared by all lanes of a queue, a
+// directory is only opened as a queue if it has one
+const OFFSET: &str = "offset";
+
+// count of the messages expired since the queue was created
+const EXPIRED: &str = "expired";
 
-use crate::{Key, Payload, Storage};
+// lease log of a queue, kept next to its lane files; the last record of a
+// key holds its lease, an empty one stands for a lease dropped with an ack
+const LEASES: &str = "leases";
+const LEASE_RECORD_LEN: usize = Key::ENCODED_LEN + Lease::ENCODED_LEN;
+
+// limits the queue was created with, missing for unlimited queues
+const LIMITS: &str = "limits";
+
+// expiry log of messages pushed with a ttl, appended on push and rewritten
+// from the tally once most of its records belong to acked messages
+const EXPIRY: &str = "expiry";
 
 pub struct QueueFile {
-    _path: Box<dyn AsRef<Path> + Send + Sync>,
+    path: Box<dyn AsRef<Path> + Send + Sync>,
     queues: DashMap<String, Queue>,
+    ready: ReadySet,
+    budget: Arc<Budget>,
 }
 
 impl QueueFile {
@@ -19,27 +62,54 @@ impl QueueFile {
         path: impl AsRef<Path> + Send + Sync + 'static,
         prefix: impl Display,
         count: u16,
-    ) -> Self {
+    ) -> Result<Self, StorageError> {
         if path.as_ref().exists() {
-            std::fs::remove_dir_all(&path).unwrap();
+            std::fs::remove_dir_all(&path)?;
         }
 
-        std::fs::create_dir(&path).unwrap();
+        let storage = Self::open(path)?;
+        for i in 0..count {
+            storage.create_queue(&format!("{}{}", prefix, i))?;
+        }
 
-        let queues = (0..count)
-            .map(|i| {
-                let path = path.as_ref().join(format!("{}{}.qf", prefix, i));
-                let db = queue_file::QueueFile::open(&path).unwrap();
+        Ok(storage)
+    }
 
-                let name = format!("{}{}", prefix, i);
-                (name, Queue::new(db))
-            })
-            .collect();
+    pub fn open(path: impl AsRef<Path> + Send + Sync + 'static) -> Result<Self, StorageError> {
+        std::fs::create_dir_all(&path)?;
 
-        Self {
-            _path: Box::new(path),
-            queues,
+        let queues = DashMap::new();
+        let ready = ReadySet::default();
+        let budget = Arc::new(Budget::default());
+        for entry in std::fs::read_dir(&path)? {
+            let dir = entry?.path();
+            if !dir.join(OFFSET).is_file() {
+                continue;
+            }
+
+            if let Some(name) = dir.file_name().and_then(|name| name.to_str()) {
+                let name = name.to_string();
+                ready.mark(&name);
+                queues.insert(name, Queue::open(dir, &budget)?);
+            }
         }
+
+        Ok(Self {
+            path: Box::new(path),
+            queues,
+            ready,
+            budget,
+        })
+    }
+
+    fn dir(&self, name: &str) -> PathBuf {
+        (*self.path).as_ref().join(name)
+    }
+
+    fn queue(&self, name: &str) -> Result<RefMut<'_, String, Queue>, StorageError> {
+        self.queues
+            .get_mut(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
     }
 }
 
@@ -48,87 +118,1110 @@ impl Storage for QueueFile {
         self.queues.iter().map(|i| i.key().clone()).collect()
     }
 
-    fn push(&self, name: &str, payload: Payload) -> Key {
-        if let Some(mut queue) = self.queues.get_mut(name) {
-            queue.push(payload)
-        } else {
-            panic!("no tree: {}", name)
+    fn contains(&self, name: &str) -> bool {
+        self.queues.contains_key(name)
+    }
+
+    fn create_queue_with(&self, name: &str, limits: Limits) -> Result<(), StorageError> {
+        crate::check_queue_name(name)?;
+        if let Entry::Vacant(entry) = self.queues.entry(name.into()) {
+            // the offset mark goes last, a directory left behind by a crash
+            // before it is not taken for a queue
+            let dir = self.dir(name);
+            std::fs::create_dir_all(&dir)?;
+            if limits != Limits::default() {
+                std::fs::write(dir.join(LIMITS), limits.to_bytes())?;
+            }
+            persist_u64(&dir.join(EXPIRED), 0)?;
+            persist_mark(&dir, 0)?;
+            entry.insert(Queue::open(dir, &self.budget)?);
         }
+
+        Ok(())
     }
 
-    fn batch(&self, name: &str, size: usize) -> VecDeque<(Key, Payload)> {
-        if let Some(mut queue) = self.queues.get_mut(name) {
-            queue.batch(size)
-        } else {
-            panic!("no tree: {}", name)
+    fn limits(&self, name: &str) -> Result<Limits, StorageError> {
+        Ok(self.queue(name)?.limits)
+    }
+
+    // files are only deleted once the queue is out of the map, which waits
+    // for in-flight operations holding the entry
+    fn drop_queue(&self, name: &str) -> Result<(), StorageError> {
+        let (_, queue) = self
+            .queues
+            .remove(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+
+        queue.notify.notify_one();
+        drop(queue);
+        std::fs::remove_dir_all(self.dir(name))?;
+        Ok(())
+    }
+
+    fn subscribe(&self, name: &str) -> Result<Arc<Notify>, StorageError> {
+        Ok(self.queue(name)?.notify.clone())
+    }
+
+    fn ready(&self) -> &ReadySet {
+        &self.ready
+    }
+
+    fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    fn push_with(
+        &self,
+        name: &str,
+        payload: Payload,
+        options: PushOptions,
+    ) -> Result<Pushed, StorageError> {
+        let pushed = self.queue(name)?.push(name, options, payload)?;
+        self.ready.mark(name);
+        Ok(pushed)
+    }
+
+    // a lane is only appended to, so a message keeps its key only when it
+    // follows the last one of its lane; otherwise it gets the next offset
+    // of the queue, which keeps the order
+    fn restore(
+        &self,
+        name: &str,
+        key: Key,
+        payload: Payload,
+        state: MessageState,
+    ) -> Result<Key, StorageError> {
+        let key = self.queue(name)?.restore(key, payload, state)?;
+        self.ready.mark(name);
+        Ok(key)
+    }
+
+    // queue files have no transactions, so only a missing or full queue is
+    // caught before anything is written; an I/O error may leave part of the
+    // items stored
+    fn push_many_with_priority(
+        &self,
+        items: Vec<(&str, u16, Payload)>,
+    ) -> Result<Vec<Key>, StorageError> {
+        let mut incoming: HashMap<&str, Vec<_>> = HashMap::new();
+        for (name, priority, payload) in &items {
+            incoming
+                .entry(*name)
+                .or_default()
+                .push((*priority, payload.len()));
+        }
+        for (name, incoming) in &incoming {
+            let queue = self.queue(name)?;
+            queue.tally.make_room(name, &queue.limits, incoming)?;
         }
+
+        items
+            .into_iter()
+            .map(|(name, priority, payload)| {
+                let options = PushOptions {
+                    priority,
+                    ..PushOptions::default()
+                };
+                let pushed = self.queue(name)?.push(name, options, payload)?;
+                self.ready.mark(name);
+                Ok(pushed.key)
+            })
+            .collect()
     }
 
-    fn remove(&self, name: &str, key: Key) {
-        if let Some(mut queue) = self.queues.get_mut(name) {
-            queue.remove(key);
-        } else {
-            panic!("no tree: {}", name)
+    fn batch(&self, name: &str, size: usize) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let mut queue = self.queue(name)?;
+        queue.purge(lease::now())?;
+        queue.batch(size)
+    }
+
+    fn batch_after(
+        &self,
+        name: &str,
+        after: Key,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let mut queue = self.queue(name)?;
+        queue.purge(lease::now())?;
+        queue.batch_after(after, size)
+    }
+
+    fn peek(
+        &self,
+        name: &str,
+        after: Option<Key>,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload, MessageState)>, StorageError> {
+        let mut queue = self.queue(name)?;
+        let page = match after {
+            Some(after) => queue.batch_after(after, size)?,
+            None => queue.batch(size)?,
+        };
+        Ok(page
+            .into_iter()
+            .map(|(key, payload)| {
+                let lease = queue.leases.get(&key).copied();
+                (key, payload, MessageState::new(queue.tally.get(key), lease))
+            })
+            .collect())
+    }
+
+    fn remove(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        self.queue(name)?.remove_many(&[key])
+    }
+
+    fn remove_many(&self, name: &str, keys: &[Key]) -> Result<(), StorageError> {
+        self.queue(name)?.remove_many(keys)
+    }
+
+    fn remove_up_to(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        self.queue(name)?.remove_up_to(key)
+    }
+
+    fn checkout(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        crate::checkout(self, name, || {
+            let mut queue = self.queue(name)?;
+            queue.purge(lease::now())?;
+            queue.checkout(size, lease)
+        })
+    }
+
+    fn batch_with_delivery(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> Result<VecDeque<Delivered>, StorageError> {
+        let mut queue = self.queue(name)?;
+        queue.purge(lease::now())?;
+        let batch = queue.batch(size)?;
+        Ok(batch
+            .into_iter()
+            .map(|(key, payload)| {
+                let delivery = Delivery::of(queue.leases.get(&key).copied());
+                (key, payload, delivery)
+            })
+            .collect())
+    }
+
+    fn delivery(&self, name: &str, key: Key) -> Result<Delivery, StorageError> {
+        Ok(Delivery::of(self.queue(name)?.leases.get(&key).copied()))
+    }
+
+    // only the lease of the message is appended, the lanes are left alone
+    fn mark_delivered(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        let mut queue = self.queue(name)?;
+        if queue.tally.get(key).is_none() {
+            return Ok(());
+        }
+
+        let previous = queue.leases.get(&key).copied();
+        queue.leases.insert(key, Lease::delivered(previous));
+        queue.append_leases(&[key])
+    }
+
+    // queue files have no transactions, so the message is pushed into `to`
+    // before it is acknowledged in `from`; a crash in between leaves it in
+    // both queues
+    fn move_to(&self, from: &str, key: Key, to: &str) -> Result<Option<Key>, StorageError> {
+        let (payload, ttl) = {
+            let mut source = self.queue(from)?;
+            let now = lease::now();
+            source.purge(now)?;
+            let payload = match source.get(key)? {
+                Some(payload) => payload,
+                None => return Ok(None),
+            };
+            let deadline = source.tally.get(key).and_then(|entry| entry.expires_at);
+            let ttl = deadline.map(|deadline| Duration::from_millis(deadline.saturating_sub(now)));
+            (payload, ttl)
+        };
+        if from == to {
+            return Ok(Some(key));
+        }
+
+        let options = PushOptions {
+            priority: key.priority(),
+            ttl,
+        };
+        let pushed = self.push_with(to, payload, options)?;
+        self.queue(from)?.remove_many(&[key])?;
+        Ok(Some(pushed.key))
+    }
+
+    fn available_at(&self, name: &str) -> Result<Option<u64>, StorageError> {
+        let now = lease::now();
+        let mut queue = self.queue(name)?;
+        queue.purge(now)?;
+        let leases = queue.leases.values().map(|lease| Ok(*lease));
+        lease::available_at(queue.tally.len(), leases, now)
+    }
+
+    fn purge_expired(&self, name: &str) -> Result<usize, StorageError> {
+        self.queue(name)?.purge(lease::now())
+    }
+
+    fn stats(&self, name: &str) -> Result<QueueStats, StorageError> {
+        Ok(self.queue(name)?.tally.stats(lease::now()))
+    }
+}
+
+impl From<queue_file::Error> for StorageError {
+    fn from(e: queue_file::Error) -> Self {
+        match e {
+            queue_file::Error::Io { source } => Self::Io(source),
+            queue_file::Error::TooManyElements {} | queue_file::Error::ElementTooBig {} => {
+                Self::Full
+            }
+            queue_file::Error::CorruptedFile { msg } => Self::Corruption(msg),
+            e @ queue_file::Error::UnsupportedVersion { .. } => Self::Corruption(e.to_string()),
         }
     }
 }
 
+// a queue file is a plain FIFO, so every priority gets its own file in the
+// queue directory and lanes are read from the highest priority down; the
+// lanes share one offset counter, like the priorities of the other backends
 struct Queue {
-    file: queue_file::QueueFile,
-    last_key: Key,
-    oldest: Key,
-    to_remove: BinaryHeap<Reverse<Key>>,
+    dir: PathBuf,
+    offsets: Offsets,
+    lanes: BTreeMap<Reverse<u16>, Lane>,
+    leases: BTreeMap<Key, Lease>,
+    lease_log: Log,
+    notify: Arc<Notify>,
+    // acked messages leave the tally right away, even while they wait in
+    // the file for the ones before them
+    tally: Tally,
+    limits: Limits,
+    expiry_log: Log,
 }
 
 impl Queue {
-    fn new(file: queue_file::QueueFile) -> Self {
-        Self {
+    fn open(dir: PathBuf, budget: &Arc<Budget>) -> Result<Self, StorageError> {
+        let mut lanes = BTreeMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let file = entry?.path();
+            if file.extension() != Some(EXTENSION.as_ref()) {
+                continue;
+            }
+
+            let priority = file
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse().ok());
+            if let Some(priority) = priority {
+                lanes.insert(Reverse(priority), Lane::open(&file, priority)?);
+            }
+        }
+
+        // the mark is ahead of every offset handed out, unless it was lost
+        let mark = offset::decode(&std::fs::read(dir.join(OFFSET))?)?;
+        let next = lanes
+            .values()
+            .filter_map(|lane| lane.tail)
+            .map(|key| key.1 + 1)
+            .fold(mark, u64::max);
+
+        // leases of messages popped before a crash are dropped
+        let (lease_log, records) = Log::open(dir.join(LEASES), LEASE_RECORD_LEN)?;
+        let mut leases = BTreeMap::new();
+        for record in records.chunks(LEASE_RECORD_LEN) {
+            let (key, lease) = record.split_at(Key::ENCODED_LEN);
+            let (key, lease) = (Key::try_from(key)?, Lease::try_from(lease)?);
+            if lease == Lease::default() {
+                leases.remove(&key);
+            } else {
+                leases.insert(key, lease);
+            }
+        }
+        leases.retain(|key, _| {
+            matches!(lanes.get(&Reverse(key.priority())), Some(lane) if lane.contains(*key))
+        });
+
+        let limits = match std::fs::read(dir.join(LIMITS)) {
+            Ok(bytes) => Limits::try_from(&bytes[..])?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Limits::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut tally = Tally::new(budget.clone());
+        for lane in lanes.values_mut() {
+            for element in Elements::new(&mut lane.reader, lane.priority)? {
+                let element = element?;
+                tally.push(element.key, element.len - HEADER_LEN, element.pushed_at);
+            }
+        }
+        match std::fs::read(dir.join(EXPIRED)) {
+            Ok(bytes) => tally.set_expired(stats::decode_expired(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // records of messages popped before a crash are dropped with the
+        // next rewrite
+        let (mut expiry_log, records) = Log::open(dir.join(EXPIRY), expiry::RECORD_LEN)?;
+        for record in records.chunks(expiry::RECORD_LEN) {
+            let (deadline, key) = expiry::decode(record)?;
+            tally.expire(key, deadline);
+        }
+        let deadlines = tally.deadlines();
+        expiry_log.compact(deadlines.len(), || expiry_records(deadlines))?;
+
+        Ok(Self {
+            dir,
+            offsets: Offsets::new(next),
+            lanes,
+            leases,
+            lease_log,
+            notify: Arc::default(),
+            tally,
+            limits,
+            expiry_log,
+        })
+    }
+
+    // room is made by acknowledging the dropped messages; the expiry record
+    // goes first, one left behind by a crash is dropped with the next
+    // rewrite of the log
+    fn push(
+        &mut self,
+        name: &str,
+        options: PushOptions,
+        item: Payload,
+    ) -> Result<Pushed, StorageError> {
+        let priority = options.priority;
+        let dropped = self
+            .tally
+            .make_room(name, &self.limits, &[(priority, item.len())])?;
+        if !dropped.is_empty() {
+            self.remove_many(&dropped)?;
+        }
+
+        let lane = open_lane(&mut self.lanes, &self.dir, priority)?;
+        let dir = &self.dir;
+        let offset = self.offsets.next(|mark| persist_mark(dir, mark))?;
+        let key = Key::with_priority(priority, offset);
+
+        let now = lease::now();
+        let deadline = options.ttl.map(|ttl| expiry::deadline(now, ttl));
+        if let Some(deadline) = deadline {
+            self.expiry_log.append(&expiry::record(deadline, key))?;
+        }
+
+        let size = item.len();
+        lane.push(key, now, item)?;
+        self.tally.push(key, size, now);
+        if let Some(deadline) = deadline {
+            self.tally.expire(key, deadline);
+        }
+        self.notify.notify_one();
+        Ok(Pushed::new(key, &self.limits, dropped))
+    }
+
+    // the expiry record goes first like for a push
+    fn restore(
+        &mut self,
+        key: Key,
+        item: Payload,
+        state: MessageState,
+    ) -> Result<Key, StorageError> {
+        let size = item.len();
+        let lane = open_lane(&mut self.lanes, &self.dir, key.priority())?;
+        let dir = &self.dir;
+        let key = if !matches!(lane.tail, Some(tail) if tail >= key) {
+            self.offsets
+                .advance(key.1, |mark| persist_mark(dir, mark))?;
+            key
+        } else {
+            let offset = self.offsets.next(|mark| persist_mark(dir, mark))?;
+            Key::with_priority(key.priority(), offset)
+        };
+        if let Some(deadline) = state.expires_at {
+            self.expiry_log.append(&expiry::record(deadline, key))?;
+        }
+
+        lane.push(key, state.pushed_at, item)?;
+        self.tally.push(key, size, state.pushed_at);
+        if let Some(deadline) = state.expires_at {
+            self.tally.expire(key, deadline);
+        }
+        if let Some(lease) = state.lease {
+            self.leases.insert(key, lease);
+            self.append_leases(&[key])?;
+        }
+        self.notify.notify_one();
+        Ok(key)
+    }
+
+    fn remove_many(&mut self, keys: &[Key]) -> Result<(), StorageError> {
+        // acks are not persisted, an acked message may come back after a
+        // restart, but not under the lease it had
+        let mut released = Vec::new();
+        for key in keys {
+            if let Some(lane) = self.lanes.get_mut(&Reverse(key.priority())) {
+                if lane.contains(*key) {
+                    lane.acked.insert(*key);
+                }
+            }
+            self.tally.remove(*key);
+            if self.leases.remove(key).is_some() {
+                released.push(*key);
+            }
+        }
+
+        for lane in self.lanes.values_mut() {
+            lane.pop_removed()?;
+        }
+
+        let lease = Lease::default();
+        let records = lease_records(released.iter().map(|key| (key, &lease)));
+        self.lease_log.append(&records)?;
+        self.compact_leases()?;
+        self.compact_expiry()
+    }
+
+    // lanes of higher priorities are delivered before `key`, so they are
+    // emptied entirely
+    fn remove_up_to(&mut self, key: Key) -> Result<(), StorageError> {
+        for (Reverse(priority), lane) in self.lanes.iter_mut() {
+            if *priority < key.priority() {
+                break;
+            }
+
+            lane.remove_up_to(key)?;
+        }
+
+        self.tally.remove_up_to(key);
+        self.compact_expiry()?;
+
+        let mut leases = self.leases.split_off(&key);
+        leases.remove(&key);
+        if std::mem::replace(&mut self.leases, leases).is_empty() {
+            return Ok(());
+        }
+
+        self.compact_leases()
+    }
+
+    fn batch(&mut self, count: usize) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let mut batch = VecDeque::new();
+        for lane in self.lanes.values_mut() {
+            if batch.len() >= count {
+                break;
+            }
+
+            lane.batch(&mut batch, count, None, |_| true)?;
+        }
+
+        Ok(batch)
+    }
+
+    // a queue file can only be read from its head, but a lane remembers
+    // where its last batch ended, so paging through it with the last key of
+    // a batch does not read the headers before it again; lanes of higher
+    // priorities are skipped entirely
+    fn batch_after(
+        &mut self,
+        after: Key,
+        count: usize,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let mut batch = VecDeque::new();
+        for (Reverse(priority), lane) in self.lanes.iter_mut() {
+            if batch.len() >= count {
+                break;
+            }
+
+            if *priority > after.priority() {
+                continue;
+            }
+
+            lane.batch(&mut batch, count, Some(after), |_| true)?;
+        }
+
+        Ok(batch)
+    }
+
+    // messages out of deliveries are returned separately for the dead
+    // letter queue
+    fn checkout(&mut self, count: usize, duration: Duration) -> Result<Checkout, StorageError> {
+        let now = lease::now();
+        let leases = &self.leases;
+        let limits = &self.limits;
+
+        let mut batch = VecDeque::new();
+        let mut exhausted = Vec::new();
+        for lane in self.lanes.values_mut() {
+            if batch.len() >= count {
+                break;
+            }
+
+            lane.batch(&mut batch, count, None, |key| match leases.get(&key) {
+                Some(lease) if lease.is_active(now) => false,
+                lease if limits.is_exhausted(lease.copied()) => {
+                    exhausted.push(key);
+                    false
+                }
+                _ => true,
+            })?;
+        }
+
+        if !exhausted.is_empty() {
+            return Ok((VecDeque::new(), exhausted));
+        }
+        if batch.is_empty() {
+            return Ok((batch, exhausted));
+        }
+
+        let mut keys = Vec::with_capacity(batch.len());
+        for (key, _) in &batch {
+            let previous = self.leases.get(key).copied();
+            self.leases
+                .insert(*key, Lease::renew(previous, now, duration));
+            keys.push(*key);
+        }
+        self.append_leases(&keys)?;
+
+        Ok((batch, exhausted))
+    }
+
+    // a message that is acked but still in its file is gone already
+    fn get(&mut self, key: Key) -> Result<Option<Payload>, StorageError> {
+        let lane = match self.lanes.get_mut(&Reverse(key.priority())) {
+            Some(lane) if lane.contains(key) => lane,
+            _ => return Ok(None),
+        };
+
+        let mut batch = VecDeque::new();
+        lane.batch(&mut batch, 1, None, |k| k == key)?;
+        Ok(batch.pop_front().map(|(_, payload)| payload))
+    }
+
+    // expired messages are acknowledged, which drops their expiry records
+    fn purge(&mut self, now: u64) -> Result<usize, StorageError> {
+        let expired = self.tally.due(now);
+        if !expired.is_empty() {
+            self.tally.purge(&expired);
+            self.remove_many(&expired)?;
+            persist_u64(&self.dir.join(EXPIRED), self.tally.expired())?;
+        }
+
+        Ok(expired.len())
+    }
+
+    fn append_leases(&mut self, keys: &[Key]) -> Result<(), StorageError> {
+        let leases = &self.leases;
+        self.lease_log.append(&lease_records(
+            keys.iter()
+                .filter_map(|key| leases.get(key).map(|lease| (key, lease))),
+        ))?;
+        self.compact_leases()
+    }
+
+    // leases of acked messages are left in the log, they only go once it is
+    // rewritten from the table
+    fn compact_leases(&mut self) -> Result<(), StorageError> {
+        let leases = &self.leases;
+        self.lease_log
+            .compact(leases.len(), || lease_records(leases.iter()))
+    }
+
+    // so are the expiry records of acked messages, only messages still in
+    // the tally keep theirs
+    fn compact_expiry(&mut self) -> Result<(), StorageError> {
+        let deadlines = self.tally.deadlines();
+        self.expiry_log
+            .compact(deadlines.len(), || expiry_records(deadlines))
+    }
+}
+
+fn expiry_records(deadlines: &BTreeSet<(u64, Key)>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(deadlines.len() * expiry::RECORD_LEN);
+    for (deadline, key) in deadlines {
+        bytes.extend_from_slice(&expiry::record(*deadline, *key));
+    }
+    bytes
+}
+
+fn lease_records<'a>(leases: impl Iterator<Item = (&'a Key, &'a Lease)>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (key, lease) in leases {
+        bytes.extend_from_slice(&key.to_bytes());
+        bytes.extend_from_slice(&lease.to_bytes());
+    }
+    bytes
+}
+
+// a log of fixed size records that is only appended to, every append is
+// synced; once most of its records are stale it is rewritten next to the old
+// one and renamed over it, so a crash leaves either version intact
+struct Log {
+    path: PathBuf,
+    file: File,
+    record_len: usize,
+    records: usize,
+}
+
+// logs shorter than this are never rewritten
+const COMPACT_AFTER: usize = 1024;
+
+impl Log {
+    // a record torn by a crash during the append is cut off, so that later
+    // appends stay aligned
+    fn open(path: PathBuf, record_len: usize) -> Result<(Self, Vec<u8>), StorageError> {
+        let mut bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = bytes.len() - bytes.len() % record_len;
+        if len < bytes.len() {
+            file.set_len(len as u64)?;
+            bytes.truncate(len);
+        }
+
+        let log = Self {
+            path,
+            file,
+            record_len,
+            records: len / record_len,
+        };
+        Ok((log, bytes))
+    }
+
+    fn append(&mut self, records: &[u8]) -> Result<(), StorageError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        self.file.write_all(records)?;
+        self.file.sync_data()?;
+        self.records += records.len() / self.record_len;
+        Ok(())
+    }
+
+    // `live` records are left of the log, it is rewritten with `records`
+    // once they are less than half of it
+    fn compact(
+        &mut self,
+        live: usize,
+        records: impl FnOnce() -> Vec<u8>,
+    ) -> Result<(), StorageError> {
+        if self.records <= COMPACT_AFTER || live * 2 > self.records {
+            return Ok(());
+        }
+
+        let records = records();
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&records)?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = records.len() / self.record_len;
+        Ok(())
+    }
+}
+
+struct Lane {
+    priority: u16,
+    file: queue_file::QueueFile,
+    // a second handle on the file, which reads elements past the head
+    reader: Reader,
+    // keys of the first and the last element, `None` while the file is empty
+    head: Option<Key>,
+    tail: Option<Key>,
+    // acked elements that wait in the file for the ones before them
+    acked: BTreeSet<Key>,
+    // the last element handed out, where paging with `batch_after` resumes
+    cursor: Option<Mark>,
+}
+
+struct Mark {
+    key: Key,
+    pos: u64,
+    // positions move when the file is expanded
+    file_len: u64,
+}
+
+impl Lane {
+    fn open(path: &Path, priority: u16) -> Result<Self, StorageError> {
+        let file = queue_file::QueueFile::open(path)?;
+        let mut lane = Self {
+            priority,
             file,
-            last_key: Key::default(),
-            oldest: Key::default(),
-            to_remove: BinaryHeap::default(),
+            reader: Reader::open(path)?,
+            head: None,
+            tail: None,
+            acked: BTreeSet::new(),
+            cursor: None,
+        };
+
+        let header = lane.reader.header()?;
+        if header.count > 0 {
+            lane.head = Some(lane.reader.element(&header, priority, header.first)?.key);
+            lane.tail = Some(lane.reader.element(&header, priority, header.last)?.key);
         }
+
+        Ok(lane)
+    }
+
+    fn push(&mut self, key: Key, pushed_at: u64, item: Payload) -> Result<(), StorageError> {
+        let mut element = Vec::with_capacity(HEADER_LEN + item.len());
+        element.extend_from_slice(&key.1.to_be_bytes());
+        element.extend_from_slice(&pushed_at.to_be_bytes());
+        element.extend_from_slice(&item);
+
+        self.file.add(&element)?;
+        self.head.get_or_insert(key);
+        self.tail = Some(key);
+        Ok(())
     }
 
-    fn push(&mut self, item: Vec<u8>) -> Key {
-        let current_key = self.last_key;
+    // `key` may be of a lower priority, which empties the lane
+    fn remove_up_to(&mut self, key: Key) -> Result<(), StorageError> {
+        let count = match self.tail {
+            None => return Ok(()),
+            Some(tail) if tail <= key => self.file.size(),
+            Some(_) => {
+                let mut count = 0;
+                for element in Elements::new(&mut self.reader, self.priority)? {
+                    if element?.key > key {
+                        break;
+                    }
+                    count += 1;
+                }
+                count
+            }
+        };
 
-        self.file.add(&item).unwrap();
-        self.last_key = current_key.next();
+        self.remove_n(count)?;
+        self.pop_removed()
+    }
 
-        current_key
+    // acks may arrive out of order, so elements are only popped once every
+    // one before them is acknowledged too, and then all in one go
+    fn pop_removed(&mut self) -> Result<(), StorageError> {
+        let mut count = 0;
+        if !self.acked.is_empty() {
+            for element in Elements::new(&mut self.reader, self.priority)? {
+                if !self.acked.remove(&element?.key) {
+                    break;
+                }
+                count += 1;
+            }
+        }
+
+        self.remove_n(count)
     }
 
-    fn remove(&mut self, key: Key) {
-        self.to_remove.push(Reverse(key));
+    fn remove_n(&mut self, count: usize) -> Result<(), StorageError> {
+        if count == 0 {
+            return Ok(());
+        }
 
-        while matches!(self.to_remove.peek(), Some(smallest) if *smallest == Reverse(self.oldest)) {
-            self.oldest = self.oldest.next();
-            self.file.remove().unwrap();
-            self.to_remove.pop();
+        self.file.remove_n(count)?;
+        let header = self.reader.header()?;
+        self.head = if header.count > 0 {
+            Some(
+                self.reader
+                    .element(&header, self.priority, header.first)?
+                    .key,
+            )
+        } else {
+            self.tail = None;
+            None
+        };
+
+        // acks of popped elements are dropped with them
+        match self.head {
+            Some(head) => self.acked = self.acked.split_off(&head),
+            None => self.acked.clear(),
         }
+        Ok(())
     }
 
-    fn batch(&mut self, count: usize) -> VecDeque<(Key, Vec<u8>)> {
-        let oldest = self.oldest.1;
-        self.file
-            .iter()
-            .take(count)
-            .enumerate()
-            .map(|(i, v)| (Key::with_offset(oldest + i as u64), v.to_vec()))
-            .collect()
+    // keys are only handed out once, so a key between the first and the
+    // last one of the lane is in its file
+    fn contains(&self, key: Key) -> bool {
+        matches!((self.head, self.tail), (Some(head), Some(tail)) if head <= key && key <= tail)
+            && !self.acked.contains(&key)
+    }
+
+    // fills `batch` up to `count` with the elements after `after` passing
+    // `filter`, acked ones are skipped; payloads are only read for those
+    // handed out
+    fn batch(
+        &mut self,
+        batch: &mut VecDeque<(Key, Payload)>,
+        count: usize,
+        after: Option<Key>,
+        mut filter: impl FnMut(Key) -> bool,
+    ) -> Result<(), StorageError> {
+        if batch.len() >= count {
+            return Ok(());
+        }
+
+        // a page following the last one starts at the cursor instead of
+        // walking the headers from the head again
+        let resume = match (&self.cursor, self.head, after) {
+            (Some(cursor), Some(head), Some(after))
+                if head <= cursor.key && cursor.key <= after =>
+            {
+                Some(cursor)
+            }
+            _ => None,
+        };
+        let mut elements = Elements::resume(&mut self.reader, self.priority, resume)?;
+
+        while let Some(element) = elements.next() {
+            let element = element?;
+            if matches!(after, Some(after) if element.key <= after)
+                || self.acked.contains(&element.key)
+                || !filter(element.key)
+            {
+                continue;
+            }
+
+            batch.push_back((element.key, elements.payload(&element)?));
+            self.cursor = Some(Mark {
+                key: element.key,
+                pos: element.pos,
+                file_len: elements.header.file_len,
+            });
+            if batch.len() >= count {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// `queue_file::QueueFile` can only read from the head of a file, the lanes
+// read elements at any position through this second handle instead; it
+// follows the versioned format of the crate, a header with the length of the
+// file, the number of elements and the positions of the first and the last
+// one, followed by a ring of elements, each prefixed with its length. That
+// format is private to the crate, so its version is pinned and
+// `it_reads_files_like_the_queue_file_crate` fails once they part ways
+struct Reader {
+    file: File,
+}
+
+const FILE_HEADER_LEN: u64 = 32;
+const VERSIONED_HEADER: u32 = 0x8000_0001;
+const LENGTH_LEN: u64 = 4;
+
+struct Header {
+    file_len: u64,
+    count: usize,
+    first: u64,
+    last: u64,
+}
+
+struct Element {
+    key: Key,
+    pushed_at: u64,
+    pos: u64,
+    // of the element data, including the header
+    len: usize,
+}
+
+impl Reader {
+    fn open(path: &Path) -> Result<Self, StorageError> {
+        Ok(Self {
+            file: File::open(path)?,
+        })
+    }
+
+    fn header(&mut self) -> Result<Header, StorageError> {
+        let mut bytes = [0; FILE_HEADER_LEN as usize];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut bytes)?;
+
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+        if u32_at(0) != VERSIONED_HEADER {
+            return Err(StorageError::Corruption(
+                "unsupported queue file header".into(),
+            ));
+        }
+
+        Ok(Header {
+            file_len: u64_at(4),
+            count: u32_at(12) as usize,
+            first: u64_at(16),
+            last: u64_at(24),
+        })
+    }
+
+    // reads across the end of the ring
+    fn read(&mut self, header: &Header, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        let pos = header.wrap(pos);
+        let before_end = buf.len().min((header.file_len - pos) as usize);
+
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.read_exact(&mut buf[..before_end])?;
+        if before_end < buf.len() {
+            self.file.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+            self.file.read_exact(&mut buf[before_end..])?;
+        }
+
+        Ok(())
+    }
+
+    fn element(
+        &mut self,
+        header: &Header,
+        priority: u16,
+        pos: u64,
+    ) -> Result<Element, StorageError> {
+        let mut bytes = [0; LENGTH_LEN as usize + HEADER_LEN];
+        self.read(header, pos, &mut bytes)?;
+
+        let (len, header) = bytes.split_at(LENGTH_LEN as usize);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        if len < HEADER_LEN {
+            return Err(StorageError::Corruption("element without header".into()));
+        }
+
+        let (offset, pushed_at) = header.split_at(8);
+        Ok(Element {
+            key: Key::with_priority(priority, offset::decode(offset)?),
+            pushed_at: u64::from_be_bytes(pushed_at.try_into().unwrap()),
+            pos,
+            len,
+        })
+    }
+
+    fn payload(&mut self, header: &Header, element: &Element) -> Result<Payload, StorageError> {
+        let mut bytes = vec![0; element.len];
+        self.read(header, element.pos + LENGTH_LEN, &mut bytes)?;
+        Ok(Payload::from(bytes).slice(HEADER_LEN..))
+    }
+}
+
+impl Header {
+    fn wrap(&self, pos: u64) -> u64 {
+        if pos < self.file_len {
+            pos
+        } else {
+            FILE_HEADER_LEN + pos - self.file_len
+        }
+    }
+}
+
+// elements of a lane from its head on, only their headers are read
+struct Elements<'a> {
+    reader: &'a mut Reader,
+    header: Header,
+    priority: u16,
+    next: Option<u64>,
+}
+
+impl<'a> Elements<'a> {
+    fn new(reader: &'a mut Reader, priority: u16) -> Result<Self, StorageError> {
+        let header = reader.header()?;
+        let next = Some(header.first).filter(|_| header.count > 0);
+        Ok(Self {
+            reader,
+            header,
+            priority,
+            next,
+        })
+    }
+
+    // starts at the element of `mark` instead, if it is still where the mark
+    // says it is
+    fn resume(
+        reader: &'a mut Reader,
+        priority: u16,
+        mark: Option<&Mark>,
+    ) -> Result<Self, StorageError> {
+        let mut elements = Self::new(reader, priority)?;
+        if let Some(mark) = mark.filter(|mark| mark.file_len == elements.header.file_len) {
+            let element = elements
+                .reader
+                .element(&elements.header, priority, mark.pos);
+            if matches!(element, Ok(element) if element.key == mark.key) {
+                elements.next = Some(mark.pos);
+            }
+        }
+
+        Ok(elements)
+    }
+
+    fn payload(&mut self, element: &Element) -> Result<Payload, StorageError> {
+        self.reader.payload(&self.header, element)
+    }
+}
+
+impl Iterator for Elements<'_> {
+    type Item = Result<Element, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pos = self.next.take()?;
+        let element = self.reader.element(&self.header, self.priority, pos);
+        if let Ok(element) = &element {
+            if pos != self.header.last {
+                let end = pos + LENGTH_LEN + element.len as u64;
+                self.next = Some(self.header.wrap(end));
+            }
+        }
+
+        Some(element)
     }
 }
 
+// the lane of a priority, its file is created on the first push
+fn open_lane<'a>(
+    lanes: &'a mut BTreeMap<Reverse<u16>, Lane>,
+    dir: &Path,
+    priority: u16,
+) -> Result<&'a mut Lane, StorageError> {
+    let lane = match lanes.entry(Reverse(priority)) {
+        btree_map::Entry::Occupied(entry) => entry.into_mut(),
+        btree_map::Entry::Vacant(entry) => {
+            let path = dir.join(format!("{}.{}", priority, EXTENSION));
+            entry.insert(Lane::open(&path, priority)?)
+        }
+    };
+
+    Ok(lane)
+}
+
+fn persist_mark(dir: &Path, mark: u64) -> Result<(), StorageError> {
+    persist_u64(&dir.join(OFFSET), mark)
+}
+
+// the value is written next to the old one and renamed over it, so a crash
+// leaves either intact
+fn persist_u64(path: &Path, value: u64) -> Result<(), StorageError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&value.to_be_bytes())?;
+    file.sync_data()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::Overflow;
+
     use super::*;
 
     #[test]
     fn it_iters_without_delete() {
         let path = tempfile::TempDir::new().unwrap();
 
-        let mut file = queue_file::QueueFile::open(&path.as_ref().join("foo.qf")).unwrap();
+        let mut file = queue_file::QueueFile::open(path.as_ref().join("foo.qf")).unwrap();
         file.add(b"1").unwrap();
         file.add(b"2").unwrap();
 
@@ -139,6 +1232,495 @@ mod tests {
         assert!(matches!(file.iter().next(), Some(p) if p.to_vec() == b"2"));
 
         file.remove().unwrap();
-        assert!(matches!(file.iter().next(), None));
+        assert!(file.iter().next().is_none());
+    }
+
+    #[test]
+    fn it_reads_files_like_the_queue_file_crate() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("0.qf");
+        let mut file = queue_file::QueueFile::open(&path).unwrap();
+        let mut reader = Reader::open(&path).unwrap();
+
+        // the ring wraps around the end of the initial 4 KiB and then grows
+        for i in 0..120u64 {
+            let mut element = i.to_be_bytes().to_vec();
+            element.extend_from_slice(&(i * 10).to_be_bytes());
+            element.extend_from_slice(&[i as u8; 100]);
+            file.add(&element).unwrap();
+            if i % 3 != 0 {
+                file.remove().unwrap();
+            }
+
+            let expected: Vec<_> = file.iter().map(|element| element.to_vec()).collect();
+            let mut elements = Elements::new(&mut reader, 0).unwrap();
+            assert_eq!(elements.header.count, file.size());
+            let mut read = Vec::new();
+            while let Some(element) = elements.next() {
+                let element = element.unwrap();
+                let payload = elements.payload(&element).unwrap();
+                let mut bytes = element.key.1.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&element.pushed_at.to_be_bytes());
+                bytes.extend_from_slice(&payload);
+                read.push(bytes);
+            }
+            assert_eq!(read, expected);
+        }
+    }
+
+    #[test]
+    fn it_reports_unknown_queue() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 1).unwrap();
+
+        assert!(storage.push("q0", Payload::from_static(b"1")).is_ok());
+        assert!(matches!(
+            storage.push("q1", Payload::from_static(b"1")),
+            Err(StorageError::UnknownQueue(name)) if name == "q1"
+        ));
+    }
+
+    #[test]
+    fn it_deletes_files_of_dropped_queue() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 0).unwrap();
+
+        storage.create_queue("session").unwrap();
+        storage.push("session", Payload::from_static(b"1")).unwrap();
+        assert!(path.as_ref().join("qf/session/0.qf").exists());
+
+        storage.drop_queue("session").unwrap();
+
+        assert!(!storage.contains("session"));
+        assert!(!path.as_ref().join("qf/session").exists());
+    }
+
+    #[test]
+    fn it_keeps_queues_inside_its_directory() {
+        let path = tempfile::TempDir::new().unwrap();
+        let sibling = path.as_ref().join("sibling");
+        std::fs::create_dir(&sibling).unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 1).unwrap();
+
+        for name in &["../sibling", "..", ".", "", "a/b", "a\\b"] {
+            assert!(matches!(
+                storage.create_queue(name),
+                Err(StorageError::InvalidQueueName(_))
+            ));
+            assert!(matches!(
+                storage.drop_queue(name),
+                Err(StorageError::UnknownQueue(_))
+            ));
+        }
+        assert!(sibling.exists());
+        assert!(!path.as_ref().join("qf/a").exists());
+
+        drop(storage);
+        let storage = QueueFile::open(path.as_ref().join("qf")).unwrap();
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+    }
+
+    #[test]
+    fn it_restores_offsets_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        let first = storage.push("q0", Payload::from_static(b"1")).unwrap();
+        let second = storage.push("q0", Payload::from_static(b"2")).unwrap();
+        storage.remove("q0", first).unwrap();
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+
+        let batch = storage.batch("q0", 10).unwrap();
+        assert_eq!(batch, vec![(second, Payload::from_static(b"2"))]);
+        assert!(storage.push("q0", Payload::from_static(b"3")).unwrap() > second);
+    }
+
+    #[test]
+    fn it_keeps_push_times_and_expired_counts_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_millis(1))
+            .unwrap();
+        let key = storage.push("q0", Payload::from_static(b"bb")).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(storage.purge_expired("q0").unwrap(), 1);
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        let stats = storage.stats("q0").unwrap();
+        assert_eq!((stats.len, stats.size_bytes, stats.expired), (1, 2, 1));
+        assert_eq!(stats.oldest, Some(key));
+        assert!(stats.oldest_age.unwrap() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn it_shares_offsets_between_lanes() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        let low = storage.push("q0", Payload::from_static(b"1")).unwrap();
+        let high = storage
+            .push_with_priority("q0", 2, Payload::from_static(b"2"))
+            .unwrap();
+        assert_eq!(high, Key::with_priority(2, low.1 + 1));
+        storage.remove("q0", low).unwrap();
+        storage.remove("q0", high).unwrap();
+        drop(storage);
+
+        // neither lane reuses an offset of the other after a restart
+        let storage = QueueFile::open(path).unwrap();
+        let next = storage.push("q0", Payload::from_static(b"3")).unwrap();
+        assert!(next.1 > high.1);
+    }
+
+    #[test]
+    fn it_reads_elements_across_the_end_of_the_file() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 1).unwrap();
+
+        // fills the initial 4 KiB, wraps around its end and then grows the
+        // file while wrapped
+        let mut keys = VecDeque::new();
+        for i in 0..120u8 {
+            keys.push_back((i, storage.push("q0", Payload::from(vec![i; 100])).unwrap()));
+            if i % 3 != 0 {
+                let (_, key) = keys.pop_front().unwrap();
+                storage.remove("q0", key).unwrap();
+            }
+        }
+
+        let batch = storage.batch("q0", usize::MAX).unwrap();
+        let expected: Vec<_> = keys
+            .iter()
+            .map(|(i, key)| (*key, Payload::from(vec![*i; 100])))
+            .collect();
+        assert_eq!(batch, expected);
+
+        let (_, middle) = keys[keys.len() / 2];
+        let after = storage.batch_after("q0", middle, usize::MAX).unwrap();
+        assert_eq!(after, &expected[keys.len() / 2 + 1..]);
+    }
+
+    #[test]
+    fn it_ignores_directories_without_offset_mark() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        drop(storage);
+        std::fs::create_dir_all(path.join("lost+found")).unwrap();
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+    }
+
+    #[test]
+    fn it_reads_lanes_by_priority() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        storage.push("q0", Payload::from_static(b"1")).unwrap();
+        let high = storage
+            .push_with_priority("q0", 2, Payload::from_static(b"2"))
+            .unwrap();
+        storage.push("q0", Payload::from_static(b"3")).unwrap();
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        let batch = storage.batch("q0", 10).unwrap();
+        let payloads: Vec<_> = batch.iter().map(|(_, payload)| payload.clone()).collect();
+        assert_eq!(payloads, vec![b"2".to_vec(), b"1".to_vec(), b"3".to_vec()]);
+
+        storage.remove("q0", high).unwrap();
+        assert_eq!(storage.batch("q0", 1).unwrap()[0].1, b"1".to_vec());
+    }
+
+    #[test]
+    fn it_pops_acknowledged_ranges() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 1).unwrap();
+
+        let keys: Vec<_> = (0..6)
+            .map(|i| {
+                storage
+                    .push_with_priority("q0", i % 2, Payload::from(vec![i as u8]))
+                    .unwrap()
+            })
+            .collect();
+
+        storage.remove_many("q0", &[keys[4], keys[1]]).unwrap();
+        // delivery order is 1, 3, 5, 0, 2, 4
+        storage.remove_up_to("q0", keys[0]).unwrap();
+
+        // the ack of 4 waits for 2, both are popped together afterwards
+        let batch = storage.batch("q0", 10).unwrap();
+        assert_eq!(batch, vec![(keys[2], Payload::from(vec![2]))]);
+
+        storage.remove("q0", keys[2]).unwrap();
+        assert!(storage.batch("q0", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_keeps_leases_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        let keys: Vec<_> = (0..4)
+            .map(|i| storage.push("q0", Payload::from(vec![i])).unwrap())
+            .collect();
+
+        let leased = storage.checkout("q0", 2, Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            leased,
+            vec![
+                (keys[0], Payload::from(vec![0])),
+                (keys[1], Payload::from(vec![1]))
+            ]
+        );
+        // an out of order ack is not handed out again
+        storage.remove("q0", keys[1]).unwrap();
+        storage.checkout("q0", 1, Duration::from_secs(0)).unwrap();
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+        assert_eq!(
+            storage.checkout("q0", 10, Duration::from_secs(60)).unwrap(),
+            vec![
+                (keys[1], Payload::from(vec![1])),
+                (keys[2], Payload::from(vec![2])),
+                (keys[3], Payload::from(vec![3]))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_appends_leases_and_compacts_them() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+        let leases = path.join("q0").join(LEASES);
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        for i in 0..700u32 {
+            storage
+                .push("q0", Payload::from(i.to_be_bytes().to_vec()))
+                .unwrap();
+            let (key, _) = storage
+                .checkout("q0", 1, Duration::from_secs(60))
+                .unwrap()
+                .pop_front()
+                .unwrap();
+            storage.remove("q0", key).unwrap();
+        }
+        let len = std::fs::metadata(&leases).unwrap().len() as usize;
+        assert!(len <= (COMPACT_AFTER + 1) * LEASE_RECORD_LEN);
+
+        let last = storage.push("q0", Payload::from_static(b"a")).unwrap();
+        storage.checkout("q0", 1, Duration::from_secs(60)).unwrap();
+        drop(storage);
+
+        // a record torn by a crash is cut off
+        let mut file = OpenOptions::new().append(true).open(&leases).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        let storage = QueueFile::open(path.clone()).unwrap();
+        assert!(storage
+            .checkout("q0", 1, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+        storage.mark_delivered("q0", last).unwrap();
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.delivery("q0", last).unwrap().count, 2);
+    }
+
+    #[test]
+    fn it_reads_batches_after_key() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 1).unwrap();
+        crate::tests::it_reads_batches_after_key(&storage);
+    }
+
+    #[test]
+    fn it_leases_nothing_when_dead_lettering_fails() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 0).unwrap();
+        crate::tests::it_leases_nothing_when_dead_lettering_fails(&storage);
+    }
+
+    #[test]
+    fn it_pages_through_a_growing_file() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = QueueFile::new(path.as_ref().join("qf"), "q", 1).unwrap();
+        let push = |i: u32| {
+            storage
+                .push("q0", Payload::from(vec![i as u8; 512]))
+                .unwrap()
+        };
+        let mut keys: Vec<_> = (0..20).map(push).collect();
+
+        // pushes in between expand the file and move the elements after the
+        // cursor, pops move the head past it
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let batch = match after {
+                Some(after) => storage.batch_after("q0", after, 3).unwrap(),
+                None => storage.batch("q0", 3).unwrap(),
+            };
+            after = match batch.back() {
+                Some((key, _)) => Some(*key),
+                None => break,
+            };
+            seen.extend(batch.iter().map(|(key, _)| *key));
+
+            if keys.len() < 200 {
+                let pushed: Vec<_> = (0..10).map(|i| push(keys.len() as u32 + i)).collect();
+                keys.extend(pushed);
+            }
+            let acked = match seen.len() % 4 {
+                0 => seen[seen.len() - 1],
+                _ => seen[seen.len() - 2],
+            };
+            storage.remove_up_to("q0", acked).unwrap();
+        }
+
+        assert_eq!(seen, keys);
+    }
+
+    #[test]
+    fn it_counts_acked_messages_out_of_order() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        let keys: Vec<_> = (1..=3)
+            .map(|i| storage.push("q0", Payload::from(vec![0; i])).unwrap())
+            .collect();
+
+        // still in the file behind the first one, but no longer queued
+        storage.remove("q0", keys[1]).unwrap();
+        let stats = storage.stats("q0").unwrap();
+        assert_eq!((stats.len, stats.size_bytes), (2, 4));
+        assert_eq!(stats.oldest, Some(keys[0]));
+        assert_eq!(stats.newest, Some(keys[2]));
+
+        storage.remove("q0", keys[0]).unwrap();
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.len("q0").unwrap(), 1);
+        assert_eq!(storage.oldest_key("q0").unwrap(), Some(keys[2]));
+        assert!(storage.oldest_age("q0").unwrap().is_some());
+    }
+
+    #[test]
+    fn it_keeps_limits_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let limits = Limits {
+            max_len: Some(1),
+            max_bytes: None,
+            overflow: Overflow::DropLowestPriority,
+            max_deliveries: None,
+        };
+        let storage = QueueFile::new(path.clone(), "q", 0).unwrap();
+        storage.create_queue_with("limited", limits).unwrap();
+        let first = storage.push("limited", Payload::from_static(b"a")).unwrap();
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.limits("limited").unwrap(), limits);
+
+        let pushed = storage
+            .push_with(
+                "limited",
+                Payload::from_static(b"b"),
+                PushOptions {
+                    priority: 1,
+                    ..PushOptions::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(pushed.dropped, vec![first]);
+        assert!(matches!(
+            storage.push("limited", Payload::from_static(b"c")),
+            Err(StorageError::QueueFull(_))
+        ));
+        assert_eq!(
+            storage.batch("limited", 10).unwrap(),
+            vec![(pushed.key, Payload::from_static(b"b"))]
+        );
+    }
+
+    #[test]
+    fn it_keeps_expiry_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        let acked = storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_secs(3600))
+            .unwrap();
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"b"), Duration::from_secs(0))
+            .unwrap();
+        let plain = storage.push("q0", Payload::from_static(b"c")).unwrap();
+        storage.remove("q0", acked).unwrap();
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.purge_expired("q0").unwrap(), 1);
+        assert_eq!(storage.expired("q0").unwrap(), 1);
+        assert_eq!(
+            storage.batch("q0", 10).unwrap(),
+            vec![(plain, Payload::from_static(b"c"))]
+        );
+    }
+
+    #[test]
+    fn it_drops_expiry_records_of_acked_messages() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("qf");
+
+        let storage = QueueFile::new(path.clone(), "q", 1).unwrap();
+        let ttl = Duration::from_secs(3600);
+        let keys: Vec<_> = (0..COMPACT_AFTER + 1)
+            .map(|_| {
+                storage
+                    .push_with_expiry("q0", Payload::from_static(b"a"), ttl)
+                    .unwrap()
+            })
+            .collect();
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"b"), Duration::from_millis(20))
+            .unwrap();
+        storage.remove_many("q0", &keys).unwrap();
+
+        let log = path.join("q0").join(EXPIRY);
+        assert_eq!(
+            std::fs::metadata(&log).unwrap().len(),
+            expiry::RECORD_LEN as u64
+        );
+        drop(storage);
+
+        let storage = QueueFile::open(path).unwrap();
+        assert_eq!(storage.len("q0").unwrap(), 1);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(storage.purge_expired("q0").unwrap(), 1);
+        assert_eq!(storage.oldest_key("q0").unwrap(), None);
     }
 }
@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use tokio::sync::watch;
+
+// queues that may have messages to hand out, in the order egress visits them;
+// a queue with weight 3 is picked three times in a row before the next one.
+// Queues are marked on push and only dropped once they are found empty, so the
+// set may name a drained queue but never misses a non-empty one
+#[derive(Debug)]
+pub struct ReadySet {
+    inner: Mutex<Ring>,
+    // bumped whenever a queue joins the set, wakes every parked worker; the
+    // receiver is kept so that there is always one to send to
+    joined: (watch::Sender<()>, watch::Receiver<()>),
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    order: VecDeque<String>,
+    // queues in `order` with the picks they got in the current round
+    picks: HashMap<String, u32>,
+    weights: HashMap<String, u32>,
+    // queues out of the set whose messages are all leased, until the first
+    // lease runs out
+    parked: HashMap<String, u64>,
+}
+
+impl Default for ReadySet {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::default(),
+            joined: watch::channel(()),
+        }
+    }
+}
+
+impl ReadySet {
+    pub fn mark(&self, name: &str) {
+        let mut ring = self.lock();
+        ring.parked.remove(name);
+        if ring.picks.contains_key(name) {
+            return;
+        }
+
+        ring.picks.insert(name.into(), 0);
+        ring.order.push_back(name.into());
+        drop(ring);
+        self.wake();
+    }
+
+    // the first moment a parked queue is due, see `unpark`
+    pub fn parked_until(&self) -> Option<u64> {
+        self.lock().parked.values().min().copied()
+    }
+
+    // `changed` on the receiver resolves once a queue joined the set since
+    // the last call, a new receiver may see one join that happened before it
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.joined.1.clone()
+    }
+
+    // picks per round, 0 is treated as 1
+    pub fn set_weight(&self, name: &str, weight: u32) {
+        self.lock().weights.insert(name.into(), weight);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().order.is_empty()
+    }
+
+    // the queue is out of the set until `restore`, so a concurrent `mark`
+    // appends it again instead of being swallowed
+    pub(crate) fn take(&self) -> Option<(String, u32)> {
+        let mut ring = self.lock();
+        let name = ring.order.pop_front()?;
+        let picks = ring.picks.remove(&name).unwrap_or_default();
+        Some((name, picks))
+    }
+
+    // counts one more pick for a queue that is still non-empty, it keeps the
+    // head of the ring until its weight is used up
+    pub(crate) fn restore(&self, name: String, picks: u32) {
+        let mut ring = self.lock();
+        if ring.picks.contains_key(&name) {
+            return;
+        }
+
+        let weight = ring.weights.get(&name).copied().unwrap_or(1).max(1);
+        if picks + 1 < weight {
+            ring.picks.insert(name.clone(), picks + 1);
+            ring.order.push_front(name);
+        } else {
+            ring.picks.insert(name.clone(), 0);
+            ring.order.push_back(name);
+        }
+        drop(ring);
+        self.wake();
+    }
+
+    // a queue taken out of the set that only has leased messages, a push
+    // marking it in the meantime wins
+    pub(crate) fn park(&self, name: String, until: u64) {
+        let mut ring = self.lock();
+        if !ring.picks.contains_key(&name) {
+            ring.parked.insert(name, until);
+        }
+    }
+
+    // puts the parked queues whose first lease ran out by `now` back
+    pub(crate) fn unpark(&self, now: u64) {
+        let mut ring = self.lock();
+        if ring.parked.values().all(|until| *until > now) {
+            return;
+        }
+
+        let Ring {
+            order,
+            picks,
+            parked,
+            ..
+        } = &mut *ring;
+        parked.retain(|name, until| {
+            if *until > now {
+                return true;
+            }
+
+            if !picks.contains_key(name) {
+                picks.insert(name.clone(), 0);
+                order.push_back(name.clone());
+            }
+            false
+        });
+    }
+
+    fn wake(&self) {
+        let _ = self.joined.0.send(());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Ring> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{Memory, Payload, Storage};
+
+    #[test]
+    fn next_ready_skips_empty_queues_round_robin() {
+        let storage = Memory::tree("q", 4);
+        for name in &["q1", "q3", "q1"] {
+            storage.push(name, Payload::from_static(b"m")).unwrap();
+        }
+
+        let picks: Vec<_> = (0..4)
+            .map(|_| storage.next_ready().unwrap().unwrap())
+            .collect();
+        assert_eq!(picks, vec!["q1", "q3", "q1", "q3"]);
+
+        let keys: Vec<_> = storage.batch("q3", 1).unwrap().into_iter().collect();
+        storage.remove("q3", keys[0].0).unwrap();
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q1"));
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q1"));
+        assert_eq!(storage.ready().len(), 1);
+    }
+
+    #[test]
+    fn next_ready_honors_weights() {
+        let storage = Memory::vec("q", 2);
+        storage.ready().set_weight("q0", 3);
+        storage.push("q0", Payload::from_static(b"m")).unwrap();
+        storage.push("q1", Payload::from_static(b"m")).unwrap();
+
+        let picks: Vec<_> = (0..8)
+            .map(|_| storage.next_ready().unwrap().unwrap())
+            .collect();
+        assert_eq!(picks, vec!["q0", "q0", "q0", "q1", "q0", "q0", "q0", "q1"]);
+    }
+
+    #[test]
+    fn next_ready_parks_fully_leased_queues() {
+        let storage = Memory::tree("q", 2);
+        storage.push("q0", Payload::from_static(b"m")).unwrap();
+        storage.push("q1", Payload::from_static(b"m")).unwrap();
+
+        let lease = Duration::from_millis(50);
+        assert_eq!(storage.checkout("q0", 10, lease).unwrap().len(), 1);
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q1"));
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q1"));
+        assert!(storage.ready().parked_until().is_some());
+
+        // a push makes the queue ready again right away
+        storage.push("q0", Payload::from_static(b"m")).unwrap();
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q1"));
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q0"));
+        assert!(storage.ready().parked_until().is_none());
+
+        assert_eq!(storage.checkout("q0", 10, lease).unwrap().len(), 1);
+        storage.drop_queue("q1").unwrap();
+        assert_eq!(storage.next_ready().unwrap(), None);
+
+        // and so does the end of its leases
+        thread::sleep(lease);
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q0"));
+    }
+
+    #[test]
+    fn next_ready_forgets_dropped_queues() {
+        let storage = Memory::tree("q", 2);
+        storage.push("q0", Payload::from_static(b"m")).unwrap();
+        storage.push("q1", Payload::from_static(b"m")).unwrap();
+        storage.drop_queue("q0").unwrap();
+
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q1"));
+        assert_eq!(storage.next_ready().unwrap().as_deref(), Some("q1"));
+
+        storage.drop_queue("q1").unwrap();
+        assert_eq!(storage.next_ready().unwrap(), None);
+        assert!(storage.ready().is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use crate::{StorageError, StoredMessage};
+
+// the last retained message of every topic. Unlike queues this is keyed by
+// topic, a new message replaces the one before it. Lookups by filter follow
+// MQTT matching: `+` matches a single level, a trailing `#` any number of
+// levels including none, and neither matches topics starting with `$` at
+// the first level
+pub trait RetainedStore {
+    // a message with an empty payload clears the topic, as on the wire; a
+    // topic with wildcards is rejected with `StorageError::InvalidTopic`
+    fn set_retained(&self, message: &StoredMessage) -> Result<(), StorageError>;
+    fn clear_retained(&self, topic: &str) -> Result<(), StorageError>;
+    fn retained(&self, topic: &str) -> Result<Option<StoredMessage>, StorageError>;
+
+    // messages whose expiry interval has passed are left out
+    fn matching(&self, filter: &str) -> Result<Vec<StoredMessage>, StorageError>;
+}
+
+pub(crate) fn is_live(message: &StoredMessage, now: u64) -> bool {
+    match message.ttl() {
+        Some(ttl) => message.received_at.saturating_add(ttl.as_millis() as u64) > now,
+        None => true,
+    }
+}
+
+// one node per topic level, the in-memory store walks it level by level
+// instead of testing every topic against the filter
+#[derive(Debug, Default)]
+pub(crate) struct Trie {
+    children: HashMap<String, Trie>,
+    message: Option<StoredMessage>,
+}
+
+impl Trie {
+    pub(crate) fn set(&mut self, message: StoredMessage) {
+        let mut node = self;
+        for level in message.topic.split('/') {
+            node = node.children.entry(level.into()).or_default();
+        }
+        node.message = Some(message);
+    }
+
+    // empty branches are pruned on the way back up
+    pub(crate) fn clear(&mut self, topic: &str) {
+        let levels: Vec<_> = topic.split('/').collect();
+        self.remove(&levels);
+    }
+
+    pub(crate) fn get(&self, topic: &str) -> Option<&StoredMessage> {
+        let mut node = self;
+        for level in topic.split('/') {
+            node = node.children.get(level)?;
+        }
+        node.message.as_ref()
+    }
+
+    // every message by topic, `$` topics included
+    pub(crate) fn messages(&self) -> Vec<&StoredMessage> {
+        let mut found = Vec::new();
+        self.all(&mut found);
+        found.sort_by(|a, b| a.topic.cmp(&b.topic));
+        found
+    }
+
+    pub(crate) fn matching<'a>(&'a self, filter: &str, found: &mut Vec<&'a StoredMessage>) {
+        let levels: Vec<_> = filter.split('/').collect();
+        self.walk(&levels, true, found);
+    }
+
+    fn remove(&mut self, levels: &[&str]) -> bool {
+        match levels.split_first() {
+            None => self.message = None,
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get_mut(*level) {
+                    if child.remove(rest) {
+                        self.children.remove(*level);
+                    }
+                }
+            }
+        }
+
+        self.message.is_none() && self.children.is_empty()
+    }
+
+    fn walk<'a>(&'a self, levels: &[&str], root: bool, found: &mut Vec<&'a StoredMessage>) {
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                found.extend(&self.message);
+                return;
+            }
+        };
+
+        match *level {
+            "#" => {
+                // `a/#` matches `a` itself as well
+                if !root {
+                    found.extend(&self.message);
+                }
+                self.each_child(root, |child| child.all(found));
+            }
+            "+" => self.each_child(root, |child| child.walk(rest, false, found)),
+            level => {
+                if let Some(child) = self.children.get(level) {
+                    child.walk(rest, false, found);
+                }
+            }
+        }
+    }
+
+    fn all<'a>(&'a self, found: &mut Vec<&'a StoredMessage>) {
+        found.extend(&self.message);
+        for child in self.children.values() {
+            child.all(found);
+        }
+    }
+
+    // wildcards at the root skip `$` topics
+    fn each_child<'a>(&'a self, root: bool, mut f: impl FnMut(&'a Trie)) {
+        for (level, child) in &self.children {
+            if !(root && level.starts_with('$')) {
+                f(child);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trie_looks_up_filters() {
+        let mut trie = Trie::default();
+        for topic in &["a/b", "a/b/c", "a/d", "$SYS/uptime", "x"] {
+            trie.set(StoredMessage {
+                topic: topic.to_string(),
+                ..StoredMessage::default()
+            });
+        }
+
+        let topics = |trie: &Trie, filter: &str| {
+            let mut found = Vec::new();
+            trie.matching(filter, &mut found);
+            let mut topics: Vec<_> = found.iter().map(|m| m.topic.clone()).collect();
+            topics.sort();
+            topics
+        };
+        assert_eq!(topics(&trie, "a/+"), vec!["a/b", "a/d"]);
+        assert_eq!(topics(&trie, "a/#"), vec!["a/b", "a/b/c", "a/d"]);
+        assert_eq!(topics(&trie, "#"), vec!["a/b", "a/b/c", "a/d", "x"]);
+        assert_eq!(topics(&trie, "$SYS/+"), vec!["$SYS/uptime"]);
+
+        trie.clear("a/b/c");
+        trie.clear("a/b/c/d");
+        assert_eq!(topics(&trie, "a/#"), vec!["a/b", "a/d"]);
+        assert!(trie.get("a/b").is_some());
+        assert!(trie.children["a"].children["b"].children.is_empty());
+    }
+}
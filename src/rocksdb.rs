@@ -1,19 +1,80 @@
 use std::{
     collections::{HashMap, VecDeque},
+    convert::TryFrom,
     fmt::Display,
+    io,
     path::Path,
     sync::atomic::AtomicU64,
     sync::atomic::Ordering,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
 };
 
-use rocksdb::{IteratorMode, Options, DB};
+use rocksdb::{
+    ColumnFamily, DBIterator, Direction, IteratorMode, Options, WriteBatch, DB,
+    DEFAULT_COLUMN_FAMILY_NAME,
+};
+use tokio::sync::Notify;
 
-use crate::{Key, Payload, Storage};
+use crate::{
+    expiry,
+    lease::{self, Lease},
+    offset::{self, Offsets},
+    retained,
+    session::{self, Inflight, PacketState, Window},
+    stats::{self, Tally},
+    subscription::{self, Subscriptions},
+    topic, Budget, Checkout, Delivered, Delivery, Key, Limits, MessageState, Payload, PushOptions,
+    Pushed, QoS, QueueStats, ReadySet, RetainedStore, SessionStateStore, Storage, StorageError,
+    StoredMessage, SubscriptionOptions, SubscriptionStore, SYSTEM_PREFIX,
+};
 
 pub struct Rocksdb {
     _path: Box<dyn AsRef<Path> + Send + Sync>,
+    inner: RwLock<Inner>,
+    ready: ReadySet,
+    budget: Arc<Budget>,
+    // everything in the subscriptions column family arranged for routing
+    routes: RwLock<Subscriptions>,
+}
+
+// retained messages keyed by topic
+const RETAINED_CF: &str = "__retained";
+
+// subscription options keyed by client id and filter, see
+// `subscription::record_key`
+const SUBSCRIPTIONS_CF: &str = "__subscriptions";
+
+// `DB::create_cf`/`DB::drop_cf` need exclusive access, so column family
+// handles and queue state are guarded together and regular operations share
+// a read lock
+struct Inner {
     db: DB,
-    offsets: HashMap<String, AtomicU64>,
+    queues: HashMap<String, Queue>,
+}
+
+#[derive(Default)]
+struct Queue {
+    offsets: Offsets,
+    checkout: Mutex<()>,
+    notify: Arc<Notify>,
+    tally: Mutex<Tally>,
+    limits: Limits,
+    // earliest deadline in the expiry column family, `u64::MAX` without
+    // records
+    next_due: AtomicU64,
+    // it also serializes state changes of the inflight window
+    window: Mutex<Window>,
+}
+
+impl Queue {
+    fn window(&self) -> MutexGuard<'_, Window> {
+        self.window.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn tally(&self) -> MutexGuard<'_, Tally> {
+        self.tally.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 }
 
 impl Rocksdb {
@@ -21,73 +82,1728 @@ impl Rocksdb {
         path: impl AsRef<Path> + Send + Sync + 'static,
         prefix: impl Display,
         count: u16,
-    ) -> Self {
+    ) -> Result<Self, StorageError> {
         if path.as_ref().exists() {
-            std::fs::remove_dir_all(&path).unwrap();
+            std::fs::remove_dir_all(&path)?;
+        }
+
+        let storage = Self::open(path)?;
+        for i in 0..count {
+            storage.create_queue(&format!("{}{}", prefix, i))?;
         }
 
+        Ok(storage)
+    }
+
+    pub fn open(path: impl AsRef<Path> + Send + Sync + 'static) -> Result<Self, StorageError> {
         let mut db_opts = Options::default();
         db_opts.create_missing_column_families(true);
         db_opts.create_if_missing(true);
 
-        let mut db = DB::open(&db_opts, &path).unwrap();
-        let mut offsets = HashMap::new();
+        let names: Vec<_> = if path.as_ref().exists() {
+            DB::list_cf(&db_opts, &path)?
+                .into_iter()
+                .filter(|name| name != DEFAULT_COLUMN_FAMILY_NAME)
+                .collect()
+        } else {
+            Vec::new()
+        };
 
-        for i in 0..count {
-            let name = format!("{}{}", prefix, i);
-            let opts = Options::default();
-            db.create_cf(&name, &opts).unwrap();
-            offsets.insert(name, AtomicU64::default());
+        let db = DB::open_cf(&db_opts, &path, &names)?;
+        let mut inner = Inner {
+            db,
+            queues: HashMap::new(),
+        };
+        let budget = Arc::new(Budget::default());
+        for cf in &[RETAINED_CF, SUBSCRIPTIONS_CF] {
+            if inner.db.cf_handle(cf).is_none() {
+                inner.db.create_cf(cf, &Options::default())?;
+            }
+        }
+
+        // the iterator borrows the db, so it has to go before any create_cf
+        let mut routes = Subscriptions::default();
+        {
+            let mut iter = inner
+                .db
+                .iterator_cf(inner.subscriptions()?, IteratorMode::Start);
+            for (key, value) in iter.by_ref() {
+                let (client_id, filter, options) = subscription::decode(&key, &value)?;
+                routes.add(&client_id, &filter, options);
+            }
+            iter.status()?;
         }
 
-        Self {
+        for name in names {
+            if name.starts_with(SYSTEM_PREFIX) {
+                continue;
+            }
+
+            // queues created before leases, expiry, sessions or message
+            // records existed have no column family for them
+            let sides = [
+                leases_cf(&name),
+                expiry_cf(&name),
+                session_cf(&name),
+                meta_cf(&name),
+            ];
+            for side in &sides {
+                if inner.db.cf_handle(side).is_none() {
+                    inner.db.create_cf(side, &Options::default())?;
+                }
+            }
+
+            let cf = inner.cf(&name)?;
+            let meta = inner.meta(&name)?;
+            migrate_legacy_keys(&inner.db, cf)?;
+            prune_leases(&inner.db, cf, inner.leases(&name)?)?;
+            record_messages(&inner.db, cf, meta)?;
+            let offset = match inner.db.get(offset_key(&name))? {
+                Some(mark) => offset::decode(&mark)?,
+                None => 0,
+            };
+            let offset = offset.max(next_offset(&inner.db, cf)?);
+
+            let mut tally = Tally::new(budget.clone());
+            let mut iter = inner.db.iterator_cf(meta, IteratorMode::Start);
+            for (key, record) in iter.by_ref() {
+                if &*key == stats::EXPIRED {
+                    tally.set_expired(stats::decode_expired(&record)?);
+                    continue;
+                }
+
+                let (size, pushed_at) = stats::decode_record(&record)?;
+                tally.push(Key::try_from(&*key)?, size, pushed_at);
+            }
+            iter.status()?;
+            let next_due = load_expiry(&inner.db, inner.expiry(&name)?, &mut tally)?;
+
+            let mut iter = inner
+                .db
+                .iterator_cf(inner.session(&name)?, IteratorMode::Start);
+            let window = Window::load(iter.by_ref().map(|(packet_id, record)| {
+                Ok((
+                    session::packet_id(&packet_id)?,
+                    Inflight::try_from(&*record)?,
+                ))
+            }))?;
+            iter.status()?;
+
+            let limits = match inner.db.get(limits_key(&name))? {
+                Some(bytes) => Limits::try_from(&bytes[..])?,
+                None => Limits::default(),
+            };
+
+            let queue = Queue {
+                offsets: Offsets::new(offset),
+                tally: Mutex::new(tally),
+                limits,
+                next_due: AtomicU64::new(next_due),
+                window: Mutex::new(window),
+                ..Queue::default()
+            };
+            inner.queues.insert(name, queue);
+        }
+
+        let ready = ReadySet::default();
+        inner.queues.keys().for_each(|name| ready.mark(name));
+
+        Ok(Self {
             _path: Box::new(path),
-            db,
-            offsets,
+            inner: RwLock::new(inner),
+            ready,
+            budget,
+            routes: RwLock::new(routes),
+        })
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Inner> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // leases the next `size` messages of a checkout, messages out of
+    // deliveries are returned separately
+    fn lease(&self, name: &str, size: usize, lease: Duration) -> Result<Checkout, StorageError> {
+        let inner = self.read();
+        inner.purge(name, lease::now())?;
+        let cf = inner.cf(name)?;
+        let leases = inner.leases(name)?;
+        let queue = inner.queue(name)?;
+        let _guard = queue
+            .checkout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let now = lease::now();
+        let mut batch = VecDeque::new();
+        let mut exhausted = Vec::new();
+        let mut writes = WriteBatch::default();
+
+        let mut iter = inner.db.iterator_cf(cf, IteratorMode::Start);
+        for (key, value) in iter.by_ref() {
+            if batch.len() >= size {
+                break;
+            }
+
+            let previous = match inner.db.get_pinned_cf(leases, &key)? {
+                Some(lease) => Some(Lease::try_from(&*lease)?),
+                None => None,
+            };
+            if matches!(previous, Some(lease) if lease.is_active(now)) {
+                continue;
+            }
+            if queue.limits.is_exhausted(previous) {
+                exhausted.push(Key::try_from(&*key)?);
+                continue;
+            }
+
+            let lease = Lease::renew(previous, now, lease);
+            writes.put_cf(leases, &key, lease.to_bytes());
+            batch.push_back((Key::try_from(&*key)?, Vec::from(value).into()));
         }
+        iter.status()?;
+        if !exhausted.is_empty() {
+            return Ok((VecDeque::new(), exhausted));
+        }
+
+        inner.db.write(writes)?;
+        Ok((batch, exhausted))
+    }
+}
+
+impl Inner {
+    fn cf(&self, name: &str) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
+    }
+
+    fn leases(&self, name: &str) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(&leases_cf(name))
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
+    }
+
+    fn expiry(&self, name: &str) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(&expiry_cf(name))
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
+    }
+
+    fn session(&self, name: &str) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(&session_cf(name))
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
+    }
+
+    fn meta(&self, name: &str) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(&meta_cf(name))
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
+    }
+
+    // messages go together with their leases and records
+    fn delete(
+        &self,
+        batch: &mut WriteBatch,
+        name: &str,
+        keys: impl IntoIterator<Item = Key>,
+    ) -> Result<(), StorageError> {
+        let sides = [self.cf(name)?, self.leases(name)?, self.meta(name)?];
+        for key in keys {
+            for cf in &sides {
+                batch.delete_cf(cf, key.to_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    // writes a message with its record
+    fn put(
+        &self,
+        batch: &mut WriteBatch,
+        name: &str,
+        key: Key,
+        payload: &[u8],
+        pushed_at: u64,
+    ) -> Result<(), StorageError> {
+        batch.put_cf(self.cf(name)?, key.to_bytes(), payload);
+        let record = stats::record(payload.len(), pushed_at);
+        batch.put_cf(self.meta(name)?, key.to_bytes(), record);
+        Ok(())
+    }
+
+    fn inflight(&self, name: &str, packet_id: u16) -> Result<Inflight, StorageError> {
+        match self
+            .db
+            .get_cf(self.session(name)?, packet_id.to_be_bytes())?
+        {
+            Some(record) => Inflight::try_from(&record[..]),
+            None => Err(StorageError::UnknownPacketId(packet_id)),
+        }
+    }
+
+    fn subscriptions(&self) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(SUBSCRIPTIONS_CF)
+            .ok_or_else(|| StorageError::Backend("missing subscriptions column family".into()))
+    }
+
+    fn retained(&self) -> Result<&ColumnFamily, StorageError> {
+        self.db
+            .cf_handle(RETAINED_CF)
+            .ok_or_else(|| StorageError::Backend("missing retained column family".into()))
+    }
+
+    fn queue(&self, name: &str) -> Result<&Queue, StorageError> {
+        self.queues
+            .get(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
+    }
+
+    fn next_offset(&self, name: &str, queue: &Queue) -> Result<u64, StorageError> {
+        queue.offsets.next(|mark| self.persist(name, mark))
+    }
+
+    fn persist(&self, name: &str, mark: u64) -> Result<(), StorageError> {
+        self.db.put(offset_key(name), mark.to_be_bytes())?;
+        Ok(())
+    }
+
+    // expired messages, their leases and every record that came due go in
+    // one write batch
+    //
+    // the tally is held across the write, so that the expired counter is
+    // written in the order purges of the queue happen
+    fn purge(&self, name: &str, now: u64) -> Result<usize, StorageError> {
+        let queue = self.queue(name)?;
+        let mut tally = queue.tally();
+        let expired = tally.due(now);
+        let records_due = queue.next_due.load(Ordering::SeqCst) <= now;
+        if expired.is_empty() && !records_due {
+            return Ok(0);
+        }
+
+        let expiry = self.expiry(name)?;
+
+        let mut batch = WriteBatch::default();
+        self.delete(&mut batch, name, expired.iter().copied())?;
+        if !expired.is_empty() {
+            let count = tally.expired() + expired.len() as u64;
+            batch.put_cf(self.meta(name)?, stats::EXPIRED, count.to_be_bytes());
+        }
+        if records_due {
+            let end = now.saturating_add(1).to_be_bytes();
+            batch.delete_range_cf(expiry, 0u64.to_be_bytes(), end);
+        }
+
+        self.db.write(batch)?;
+        tally.purge(&expired);
+        drop(tally);
+        if records_due {
+            let next_due = first_deadline(&self.db, expiry)?;
+            queue.next_due.store(next_due, Ordering::SeqCst);
+        }
+
+        Ok(expired.len())
     }
 }
 
 impl Storage for Rocksdb {
     fn names(&self) -> Vec<String> {
-        self.offsets.keys().cloned().collect()
+        self.read().queues.keys().cloned().collect()
     }
 
-    fn push(&self, name: &str, payload: Payload) -> Key {
-        if let Some(cf) = self.db.cf_handle(name) {
-            let offset = self.offsets.get(name).unwrap();
-            let offset = offset.fetch_add(1, Ordering::SeqCst);
+    fn contains(&self, name: &str) -> bool {
+        self.read().queues.contains_key(name)
+    }
 
-            let current_key = Key::with_offset(offset);
+    // limits live in the default column family, keyed by the queue name
+    fn create_queue_with(&self, name: &str, limits: Limits) -> Result<(), StorageError> {
+        crate::check_queue_name(name)?;
+        let mut inner = self.write();
+        if !inner.queues.contains_key(name) {
+            inner.db.create_cf(name, &Options::default())?;
+            inner.db.create_cf(leases_cf(name), &Options::default())?;
+            inner.db.create_cf(expiry_cf(name), &Options::default())?;
+            inner.db.create_cf(session_cf(name), &Options::default())?;
+            inner.db.create_cf(meta_cf(name), &Options::default())?;
+            inner
+                .db
+                .put_cf(inner.meta(name)?, stats::EXPIRED, 0u64.to_be_bytes())?;
+            inner.db.put(limits_key(name), limits.to_bytes())?;
 
-            self.db
-                .put_cf(cf, current_key.to_string(), payload)
-                .unwrap();
+            let queue = Queue {
+                tally: Mutex::new(Tally::new(self.budget.clone())),
+                limits,
+                next_due: AtomicU64::new(u64::MAX),
+                ..Queue::default()
+            };
+            inner.queues.insert(name.into(), queue);
+        }
 
-            current_key
-        } else {
-            panic!("no cf: {}", name)
+        Ok(())
+    }
+
+    fn limits(&self, name: &str) -> Result<Limits, StorageError> {
+        Ok(self.read().queue(name)?.limits)
+    }
+
+    // the write lock waits for in-flight operations on any queue, pushes
+    // issued afterwards fail with `UnknownQueue`
+    fn drop_queue(&self, name: &str) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        let queue = inner
+            .queues
+            .remove(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+        queue.notify.notify_one();
+
+        inner.db.drop_cf(name)?;
+        inner.db.drop_cf(&leases_cf(name))?;
+        inner.db.drop_cf(&expiry_cf(name))?;
+        inner.db.drop_cf(&session_cf(name))?;
+        inner.db.drop_cf(&meta_cf(name))?;
+        inner.db.delete(limits_key(name))?;
+        inner.db.delete(offset_key(name))?;
+        Ok(())
+    }
+
+    fn subscribe(&self, name: &str) -> Result<Arc<Notify>, StorageError> {
+        Ok(self.read().queue(name)?.notify.clone())
+    }
+
+    fn ready(&self) -> &ReadySet {
+        &self.ready
+    }
+
+    fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    // the message is counted before it is written, so that an ack racing
+    // with the push cannot be counted first and leave it in the tally;
+    // messages dropped to make room go in the same write batch
+    fn push_with(
+        &self,
+        name: &str,
+        payload: Payload,
+        options: PushOptions,
+    ) -> Result<Pushed, StorageError> {
+        let inner = self.read();
+        let queue = inner.queue(name)?;
+        let offset = inner.next_offset(name, queue)?;
+
+        let now = lease::now();
+        let current_key = Key::with_priority(options.priority, offset);
+        let incoming = [(current_key, payload.len())];
+        let dropped = queue.tally().reserve(name, &queue.limits, &incoming, now)?;
+
+        let mut batch = WriteBatch::default();
+        let written = inner
+            .put(&mut batch, name, current_key, &payload, now)
+            .and_then(|_| inner.delete(&mut batch, name, dropped.iter().map(|(key, _)| *key)));
+        if let Err(e) = written {
+            queue.tally().unreserve(&incoming, dropped);
+            return Err(e);
+        }
+        let deadline = options.ttl.map(|ttl| expiry::deadline(now, ttl));
+        if let Some(deadline) = deadline {
+            let record = expiry::record(deadline, current_key);
+            batch.put_cf(inner.expiry(name)?, record, []);
         }
+        if let Err(e) = inner.db.write(batch) {
+            queue.tally().unreserve(&incoming, dropped);
+            return Err(e.into());
+        }
+
+        // the tally learns about the expiry once the message is written, so
+        // a purge never runs ahead of the write
+        if let Some(deadline) = deadline {
+            queue.tally().expire(current_key, deadline);
+            queue.next_due.fetch_min(deadline, Ordering::SeqCst);
+        }
+        queue.notify.notify_one();
+        self.ready.mark(name);
+
+        let dropped = dropped.into_iter().map(|(key, _)| key).collect();
+        Ok(Pushed::new(current_key, &queue.limits, dropped))
     }
 
-    fn batch(&self, name: &str, size: usize) -> VecDeque<(Key, Payload)> {
-        if let Some(cf) = self.db.cf_handle(name) {
-            self.db
-                .iterator_cf(cf, IteratorMode::Start)
-                .take(size)
-                .map(|(k, v)| (k.into(), v.into()))
-                .collect()
-        } else {
-            panic!("no cf: {}", name)
+    // counted before the write like a push; the offset moves past the key
+    // first, so that later pushes follow it. The message goes in one write
+    // batch with its lease and expiry record, which replaces the one of a
+    // message stored under the key already
+    fn restore(
+        &self,
+        name: &str,
+        key: Key,
+        payload: Payload,
+        state: MessageState,
+    ) -> Result<Key, StorageError> {
+        let inner = self.read();
+        let queue = inner.queue(name)?;
+        queue
+            .offsets
+            .advance(key.1, |mark| inner.persist(name, mark))?;
+
+        let mut tally = queue.tally();
+        let previous = tally.remove(key);
+        tally.push(key, payload.len(), state.pushed_at);
+        drop(tally);
+
+        let mut batch = WriteBatch::default();
+        let written = inner
+            .put(&mut batch, name, key, &payload, state.pushed_at)
+            .and_then(|_| {
+                let expiry = inner.expiry(name)?;
+                if let Some(deadline) = previous.and_then(|entry| entry.expires_at) {
+                    batch.delete_cf(expiry, expiry::record(deadline, key));
+                }
+                if let Some(deadline) = state.expires_at {
+                    batch.put_cf(expiry, expiry::record(deadline, key), []);
+                }
+                let leases = inner.leases(name)?;
+                match state.lease {
+                    Some(lease) => batch.put_cf(leases, key.to_bytes(), lease.to_bytes()),
+                    None => batch.delete_cf(leases, key.to_bytes()),
+                }
+                Ok(inner.db.write(batch)?)
+            });
+        if let Err(e) = written {
+            queue.tally().remove(key);
+            return Err(e);
+        }
+        if let Some(deadline) = state.expires_at {
+            queue.tally().expire(key, deadline);
+            queue.next_due.fetch_min(deadline, Ordering::SeqCst);
+        }
+        queue.notify.notify_one();
+        self.ready.mark(name);
+
+        Ok(key)
+    }
+
+    fn push_many_with_priority(
+        &self,
+        items: Vec<(&str, u16, Payload)>,
+    ) -> Result<Vec<Key>, StorageError> {
+        let inner = self.read();
+        let now = lease::now();
+        let mut batch = WriteBatch::default();
+        let mut keys = Vec::with_capacity(items.len());
+        let mut groups = Vec::new();
+        let mut indexes = HashMap::new();
+
+        for (name, priority, payload) in items {
+            let queue = inner.queue(name)?;
+            let offset = inner.next_offset(name, queue)?;
+            let index = *indexes.entry(name).or_insert_with(|| {
+                groups.push((name, queue, Vec::new()));
+                groups.len() - 1
+            });
+
+            let current_key = Key::with_priority(priority, offset);
+            groups[index].2.push((current_key, payload.len()));
+            inner.put(&mut batch, name, current_key, &payload, now)?;
+            keys.push(current_key);
+        }
+
+        // counted before the write like a single push; the queues are known
+        // to exist by now, so deleting the dropped messages cannot fail
+        let mut dropped = Vec::with_capacity(groups.len());
+        for (name, queue, incoming) in &groups {
+            match queue.tally().reserve(name, &queue.limits, incoming, now) {
+                Ok(keys) => {
+                    inner.delete(&mut batch, name, keys.iter().map(|(key, _)| *key))?;
+                    dropped.push(keys);
+                }
+                Err(e) => {
+                    for ((_, queue, incoming), keys) in groups.iter().zip(dropped) {
+                        queue.tally().unreserve(incoming, keys);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Err(e) = inner.db.write(batch) {
+            for ((_, queue, incoming), keys) in groups.iter().zip(dropped) {
+                queue.tally().unreserve(incoming, keys);
+            }
+            return Err(e.into());
+        }
+        for (name, queue, _) in &groups {
+            queue.notify.notify_one();
+            self.ready.mark(name);
+        }
+        Ok(keys)
+    }
+
+    fn batch(&self, name: &str, size: usize) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let inner = self.read();
+        inner.purge(name, lease::now())?;
+        let cf = inner.cf(name)?;
+
+        read(inner.db.iterator_cf(cf, IteratorMode::Start), None, size)
+    }
+
+    // seeking lands right on the next live key, instead of walking over the
+    // tombstones of acknowledged messages from the head
+    fn batch_after(
+        &self,
+        name: &str,
+        after: Key,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let inner = self.read();
+        inner.purge(name, lease::now())?;
+        let cf = inner.cf(name)?;
+
+        let start = after.to_bytes();
+        let iter = inner
+            .db
+            .iterator_cf(cf, IteratorMode::From(&start, Direction::Forward));
+        read(iter, Some(after), size)
+    }
+
+    fn peek(
+        &self,
+        name: &str,
+        after: Option<Key>,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload, MessageState)>, StorageError> {
+        let inner = self.read();
+        let cf = inner.cf(name)?;
+        let leases = inner.leases(name)?;
+        let queue = inner.queue(name)?;
+        let _guard = queue
+            .checkout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let start = after.map(|after| after.to_bytes());
+        let mode = match &start {
+            Some(start) => IteratorMode::From(start, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        read(inner.db.iterator_cf(cf, mode), after, size)?
+            .into_iter()
+            .map(|(key, payload)| {
+                let lease = match inner.db.get_pinned_cf(leases, key.to_bytes())? {
+                    Some(lease) => Some(Lease::try_from(&*lease)?),
+                    None => None,
+                };
+                let state = MessageState::new(queue.tally().get(key), lease);
+                Ok((key, payload, state))
+            })
+            .collect()
+    }
+
+    fn remove(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        self.remove_many(name, &[key])
+    }
+
+    // a message, its lease and its record are deleted in one write batch
+    fn remove_many(&self, name: &str, keys: &[Key]) -> Result<(), StorageError> {
+        let inner = self.read();
+
+        let mut batch = WriteBatch::default();
+        inner.delete(&mut batch, name, keys.iter().copied())?;
+
+        inner.db.write(batch)?;
+
+        let mut tally = inner.queue(name)?.tally();
+        for key in keys {
+            tally.remove(*key);
+        }
+        Ok(())
+    }
+
+    // the range end is exclusive, so it stops right after `key`
+    fn remove_up_to(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        let inner = self.read();
+
+        // encoded keys all have the same length, so the end right behind
+        // `key` is the key with a zero byte appended, even at `u64::MAX`;
+        // the expired counter sorts before the first key
+        let first = Key::with_priority(u16::MAX, 0).to_bytes();
+        let mut end = key.to_bytes().to_vec();
+        end.push(0);
+
+        let mut batch = WriteBatch::default();
+        for cf in &[inner.cf(name)?, inner.leases(name)?, inner.meta(name)?] {
+            batch.delete_range_cf(cf, &first[..], &end[..]);
+        }
+
+        inner.db.write(batch)?;
+        inner.queue(name)?.tally().remove_up_to(key);
+        Ok(())
+    }
+
+    // checkouts of a queue are serialized, so that two consumers never lease
+    // the same message; messages out of deliveries are moved once the lock
+    // is released
+    fn checkout(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        crate::checkout(self, name, || self.lease(name, size, lease))
+    }
+
+    // serialized with checkouts and marks, the only writers of leases
+    fn batch_with_delivery(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> Result<VecDeque<Delivered>, StorageError> {
+        let inner = self.read();
+        inner.purge(name, lease::now())?;
+        let cf = inner.cf(name)?;
+        let leases = inner.leases(name)?;
+        let _guard = inner
+            .queue(name)?
+            .checkout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        read(inner.db.iterator_cf(cf, IteratorMode::Start), None, size)?
+            .into_iter()
+            .map(|(key, payload)| {
+                let lease = match inner.db.get_pinned_cf(leases, key.to_bytes())? {
+                    Some(lease) => Some(Lease::try_from(&*lease)?),
+                    None => None,
+                };
+                Ok((key, payload, Delivery::of(lease)))
+            })
+            .collect()
+    }
+
+    fn delivery(&self, name: &str, key: Key) -> Result<Delivery, StorageError> {
+        let inner = self.read();
+        let lease = match inner
+            .db
+            .get_pinned_cf(inner.leases(name)?, key.to_bytes())?
+        {
+            Some(lease) => Some(Lease::try_from(&*lease)?),
+            None => None,
+        };
+        Ok(Delivery::of(lease))
+    }
+
+    // serialized with checkouts, which update the same lease record
+    fn mark_delivered(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        let inner = self.read();
+        let cf = inner.cf(name)?;
+        let leases = inner.leases(name)?;
+        let _guard = inner
+            .queue(name)?
+            .checkout
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if inner.db.get_pinned_cf(cf, key.to_bytes())?.is_none() {
+            return Ok(());
+        }
+
+        let previous = match inner.db.get_pinned_cf(leases, key.to_bytes())? {
+            Some(lease) => Some(Lease::try_from(&*lease)?),
+            None => None,
+        };
+        inner.db.put_cf(
+            leases,
+            key.to_bytes(),
+            Lease::delivered(previous).to_bytes(),
+        )?;
+        Ok(())
+    }
+
+    // one write batch takes the message and its lease out of `from` and puts
+    // it into `to` together with its expiry record
+    fn move_to(&self, from: &str, key: Key, to: &str) -> Result<Option<Key>, StorageError> {
+        let inner = self.read();
+        inner.purge(from, lease::now())?;
+        let cf = inner.cf(from)?;
+        let payload = match inner.db.get_cf(cf, key.to_bytes())? {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+        if from == to {
+            return Ok(Some(key));
+        }
+
+        let source = inner.queue(from)?;
+        let target = inner.queue(to)?;
+        let moved = Key::with_priority(key.priority(), inner.next_offset(to, target)?);
+        let now = lease::now();
+        let incoming = [(moved, payload.len())];
+        let dropped = target.tally().reserve(to, &target.limits, &incoming, now)?;
+        let deadline = source.tally().get(key).and_then(|entry| entry.expires_at);
+
+        let mut batch = WriteBatch::default();
+        let written = inner
+            .delete(&mut batch, from, Some(key))
+            .and_then(|_| inner.delete(&mut batch, to, dropped.iter().map(|(key, _)| *key)))
+            .and_then(|_| inner.put(&mut batch, to, moved, &payload, now))
+            .and_then(|_| {
+                if let Some(deadline) = deadline {
+                    let record = expiry::record(deadline, moved);
+                    batch.put_cf(inner.expiry(to)?, record, []);
+                }
+                Ok(inner.db.write(batch)?)
+            });
+        if let Err(e) = written {
+            target.tally().unreserve(&incoming, dropped);
+            return Err(e);
+        }
+
+        source.tally().remove(key);
+        if let Some(deadline) = deadline {
+            target.tally().expire(moved, deadline);
+            target.next_due.fetch_min(deadline, Ordering::SeqCst);
+        }
+        target.notify.notify_one();
+        self.ready.mark(to);
+        Ok(Some(moved))
+    }
+
+    fn available_at(&self, name: &str) -> Result<Option<u64>, StorageError> {
+        let now = lease::now();
+        let inner = self.read();
+        inner.purge(name, now)?;
+        let len = inner.queue(name)?.tally().len();
+        let mut iter = inner
+            .db
+            .iterator_cf(inner.leases(name)?, IteratorMode::Start);
+        let available = lease::available_at(
+            len,
+            iter.by_ref().map(|(_, lease)| Lease::try_from(&*lease)),
+            now,
+        )?;
+        iter.status()?;
+        Ok(available)
+    }
+
+    fn purge_expired(&self, name: &str) -> Result<usize, StorageError> {
+        self.read().purge(name, lease::now())
+    }
+
+    fn stats(&self, name: &str) -> Result<QueueStats, StorageError> {
+        Ok(self.read().queue(name)?.tally().stats(lease::now()))
+    }
+
+    fn retained_messages(&self) -> Result<Vec<StoredMessage>, StorageError> {
+        let inner = self.read();
+        let mut iter = inner.db.iterator_cf(inner.retained()?, IteratorMode::Start);
+        let messages = iter
+            .by_ref()
+            .map(|(_, bytes)| StoredMessage::try_from(Payload::from(Vec::from(bytes))))
+            .collect::<Result<_, _>>()?;
+        iter.status()?;
+        Ok(messages)
+    }
+
+    fn restore_retained(&self, message: &StoredMessage) -> Result<(), StorageError> {
+        self.set_retained(message)
+    }
+
+    fn all_subscriptions(
+        &self,
+    ) -> Result<Vec<(String, String, SubscriptionOptions)>, StorageError> {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(routes.all())
+    }
+
+    fn restore_subscription(
+        &self,
+        client_id: &str,
+        filter: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), StorageError> {
+        self.add_subscription(client_id, filter, options)
+    }
+
+    fn inflight_window(&self, name: &str) -> Result<Vec<(u16, Inflight)>, StorageError> {
+        self.inflight(name)
+    }
+
+    fn restore_inflight(
+        &self,
+        name: &str,
+        packet_id: u16,
+        inflight: Inflight,
+    ) -> Result<(), StorageError> {
+        let inner = self.read();
+        let mut window = inner.queue(name)?.window();
+        inner.db.put_cf(
+            inner.session(name)?,
+            packet_id.to_be_bytes(),
+            inflight.to_bytes(),
+        )?;
+        window.restore(packet_id, inflight.key);
+        Ok(())
+    }
+}
+
+impl RetainedStore for Rocksdb {
+    fn set_retained(&self, message: &StoredMessage) -> Result<(), StorageError> {
+        topic::validate_name(&message.topic)?;
+        if message.payload.is_empty() {
+            return self.clear_retained(&message.topic);
+        }
+
+        let inner = self.read();
+        inner
+            .db
+            .put_cf(inner.retained()?, &message.topic, message.to_bytes()?)?;
+        Ok(())
+    }
+
+    fn clear_retained(&self, topic: &str) -> Result<(), StorageError> {
+        let inner = self.read();
+        inner.db.delete_cf(inner.retained()?, topic)?;
+        Ok(())
+    }
+
+    fn retained(&self, topic: &str) -> Result<Option<StoredMessage>, StorageError> {
+        let inner = self.read();
+        let message = match inner.db.get_cf(inner.retained()?, topic)? {
+            Some(bytes) => StoredMessage::try_from(Payload::from(bytes))?,
+            None => return Ok(None),
+        };
+
+        Ok(Some(message).filter(|message| retained::is_live(message, lease::now())))
+    }
+
+    // iterates from the literal part of the filter until topics stop
+    // starting with it
+    fn matching(&self, filter: &str) -> Result<Vec<StoredMessage>, StorageError> {
+        topic::validate(filter)?;
+
+        let inner = self.read();
+        let prefix = topic::literal_prefix(filter);
+        let mode = IteratorMode::From(prefix.as_bytes(), Direction::Forward);
+        let mut iter = inner.db.iterator_cf(inner.retained()?, mode);
+
+        let now = lease::now();
+        let mut found = Vec::new();
+        for (topic, bytes) in iter.by_ref() {
+            if !topic.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if !topic::matches(filter, &String::from_utf8_lossy(&topic)) {
+                continue;
+            }
+
+            let message = StoredMessage::try_from(Payload::from(Vec::from(bytes)))?;
+            if retained::is_live(&message, now) {
+                found.push(message);
+            }
+        }
+        iter.status()?;
+
+        Ok(found)
+    }
+}
+
+// records are keyed by packet id, see `session::Inflight`
+impl SessionStateStore for Rocksdb {
+    // without transactions the record is written first and taken back if
+    // the message is gone by then, so a removal racing the publish either
+    // fails it or comes after it like an ack of the inflight message
+    fn publish(&self, name: &str, key: Key) -> Result<u16, StorageError> {
+        let inner = self.read();
+        let cf = inner.cf(name)?;
+        let session = inner.session(name)?;
+        let mut window = inner.queue(name)?.window();
+
+        let packet_id = window.allocate(name, key, |id| {
+            Ok(inner.db.get_pinned_cf(session, id.to_be_bytes())?.is_some())
+        })?;
+
+        let inflight = Inflight::new(key, PacketState::Publish);
+        inner
+            .db
+            .put_cf(session, packet_id.to_be_bytes(), inflight.to_bytes())?;
+        if inner.db.get_pinned_cf(cf, key.to_bytes())?.is_none() {
+            inner.db.delete_cf(session, packet_id.to_be_bytes())?;
+            return Err(StorageError::NotQueued(key));
         }
+
+        window.publish(packet_id, key);
+        Ok(packet_id)
     }
 
-    fn remove(&self, name: &str, key: Key) {
-        if let Some(cf) = self.db.cf_handle(name) {
-            let key = key.to_string();
-            self.db.delete_cf(cf, key).unwrap();
+    // the message, its lease and the record change in one write batch
+    fn pubrec(&self, name: &str, packet_id: u16) -> Result<(), StorageError> {
+        let inner = self.read();
+        let queue = inner.queue(name)?;
+        let _window = queue.window();
+        let inflight = inner.inflight(name, packet_id)?;
+        if inflight.state == PacketState::Pubrel {
+            return Ok(());
+        }
+
+        let released = Inflight::new(inflight.key, PacketState::Pubrel);
+        let mut batch = WriteBatch::default();
+        inner.delete(&mut batch, name, Some(inflight.key))?;
+        batch.put_cf(
+            inner.session(name)?,
+            packet_id.to_be_bytes(),
+            released.to_bytes(),
+        );
+        inner.db.write(batch)?;
+
+        queue.tally().remove(inflight.key);
+        Ok(())
+    }
+
+    fn complete(&self, name: &str, packet_id: u16) -> Result<(), StorageError> {
+        let inner = self.read();
+        let queue = inner.queue(name)?;
+        let mut window = queue.window();
+        let inflight = inner.inflight(name, packet_id)?;
+
+        let mut batch = WriteBatch::default();
+        inner.delete(&mut batch, name, Some(inflight.key))?;
+        batch.delete_cf(inner.session(name)?, packet_id.to_be_bytes());
+        inner.db.write(batch)?;
+
+        window.complete(inflight.key);
+        queue.tally().remove(inflight.key);
+        Ok(())
+    }
+
+    fn inflight(&self, name: &str) -> Result<Vec<(u16, Inflight)>, StorageError> {
+        let inner = self.read();
+        let mut iter = inner
+            .db
+            .iterator_cf(inner.session(name)?, IteratorMode::Start);
+        let mut inflight = iter
+            .by_ref()
+            .map(|(packet_id, record)| {
+                Ok((
+                    session::packet_id(&packet_id)?,
+                    Inflight::try_from(&*record)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        iter.status()?;
+
+        inflight.sort_by_key(|(_, inflight)| inflight.key);
+        Ok(inflight)
+    }
+}
+
+// changes are written while holding the routes, so that the column family
+// and the routes see them in the same order
+impl SubscriptionStore for Rocksdb {
+    fn add_subscription(
+        &self,
+        client_id: &str,
+        filter: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), StorageError> {
+        subscription::validate(client_id, filter)?;
+
+        let inner = self.read();
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        inner.db.put_cf(
+            inner.subscriptions()?,
+            subscription::record_key(client_id, filter),
+            [options.to_byte()],
+        )?;
+        routes.add(client_id, filter, options);
+        Ok(())
+    }
+
+    fn remove_subscription(&self, client_id: &str, filter: &str) -> Result<(), StorageError> {
+        let inner = self.read();
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        inner.db.delete_cf(
+            inner.subscriptions()?,
+            subscription::record_key(client_id, filter),
+        )?;
+        routes.remove(client_id, filter);
+        Ok(())
+    }
+
+    // the range ends right after the zero byte ending the client prefix
+    fn remove_client(&self, client_id: &str) -> Result<(), StorageError> {
+        let inner = self.read();
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        let start = subscription::client_prefix(client_id);
+        let mut end = start.clone();
+        *end.last_mut().expect("prefix ends with a zero byte") = 1;
+
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(inner.subscriptions()?, start, end);
+        inner.db.write(batch)?;
+        routes.remove_client(client_id);
+        Ok(())
+    }
+
+    fn subscriptions(&self, client_id: &str) -> Vec<(String, SubscriptionOptions)> {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        routes.of(client_id)
+    }
+
+    fn route(&self, topic: &str) -> Vec<(String, QoS)> {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        routes.route(topic)
+    }
+}
+
+// decodes up to `size` messages from `iter`, skipping `after` where a seek
+// landed on it; fails on the first undecodable key or iterator error
+fn read(
+    mut iter: DBIterator<'_>,
+    after: Option<Key>,
+    size: usize,
+) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+    let batch = iter
+        .by_ref()
+        .map(|(k, v)| Ok((Key::try_from(&*k)?, Vec::from(v).into())))
+        .filter(|item| !matches!(item, Ok((key, _)) if Some(*key) == after))
+        .take(size)
+        .collect::<Result<_, StorageError>>()?;
+    iter.status()?;
+
+    Ok(batch)
+}
+
+fn leases_cf(name: &str) -> String {
+    format!("{}leases.{}", SYSTEM_PREFIX, name)
+}
+
+fn expiry_cf(name: &str) -> String {
+    format!("{}expiry.{}", SYSTEM_PREFIX, name)
+}
+
+fn session_cf(name: &str) -> String {
+    format!("{}session.{}", SYSTEM_PREFIX, name)
+}
+
+fn meta_cf(name: &str) -> String {
+    format!("{}meta.{}", SYSTEM_PREFIX, name)
+}
+
+// queues written before message records existed get theirs from one scan of
+// the payloads, as pushed at that moment; the expired counter marks a queue
+// whose records are complete
+fn record_messages(db: &DB, cf: &ColumnFamily, meta: &ColumnFamily) -> Result<(), StorageError> {
+    if db.get_pinned_cf(meta, stats::EXPIRED)?.is_some() {
+        return Ok(());
+    }
+
+    let now = lease::now();
+    let mut batch = WriteBatch::default();
+    let mut iter = db.iterator_cf(cf, IteratorMode::Start);
+    for (key, value) in iter.by_ref() {
+        batch.put_cf(meta, key, stats::record(value.len(), now));
+    }
+    iter.status()?;
+    batch.put_cf(meta, stats::EXPIRED, 0u64.to_be_bytes());
+
+    db.write(batch)?;
+    Ok(())
+}
+
+// the high-water offset mark of a queue lives in the default column family,
+// see `offset::Offsets`
+fn offset_key(name: &str) -> String {
+    format!("{}offset.{}", SYSTEM_PREFIX, name)
+}
+
+// so are the limits of a queue created with other than the default ones
+fn limits_key(name: &str) -> String {
+    format!("{}limits.{}", SYSTEM_PREFIX, name)
+}
+
+// hands the expiry of every stored message to the tally and drops records of
+// messages that are gone already, returns the earliest deadline left
+fn load_expiry(db: &DB, expiry: &ColumnFamily, tally: &mut Tally) -> Result<u64, StorageError> {
+    let mut next_due = u64::MAX;
+    let mut stale = WriteBatch::default();
+    let mut iter = db.iterator_cf(expiry, IteratorMode::Start);
+    for (record, _) in iter.by_ref() {
+        let (deadline, key) = expiry::decode(&record)?;
+        if tally.expire(key, deadline) {
+            next_due = next_due.min(deadline);
         } else {
-            panic!("no cf: {}", name)
+            stale.delete_cf(expiry, record);
+        }
+    }
+    iter.status()?;
+
+    db.write(stale)?;
+    Ok(next_due)
+}
+
+fn first_deadline(db: &DB, expiry: &ColumnFamily) -> Result<u64, StorageError> {
+    let mut iter = db.iterator_cf(expiry, IteratorMode::Start);
+    let first = iter.next();
+    iter.status()?;
+
+    match first {
+        Some((record, _)) => Ok(expiry::decode(&record)?.0),
+        None => Ok(u64::MAX),
+    }
+}
+
+// drops lease records of messages that are gone already
+fn prune_leases(db: &DB, cf: &ColumnFamily, leases: &ColumnFamily) -> Result<(), StorageError> {
+    let mut batch = WriteBatch::default();
+    let mut iter = db.iterator_cf(leases, IteratorMode::Start);
+    for (key, _) in iter.by_ref() {
+        if db.get_pinned_cf(cf, &key)?.is_none() {
+            batch.delete_cf(leases, key);
+        }
+    }
+    iter.status()?;
+
+    db.write(batch)?;
+    Ok(())
+}
+
+// offsets are shared by all priorities, so the next one follows the largest
+// offset among the last keys of every priority group; the persisted mark is
+// ahead of that unless the column family predates marks
+fn next_offset(db: &DB, cf: &ColumnFamily) -> Result<u64, StorageError> {
+    let mut offset = 0;
+    let mut iter = db.raw_iterator_cf(cf);
+    iter.seek_to_last();
+
+    while let Some(key) = iter.key() {
+        let key = Key::try_from(key)?;
+        offset = offset.max(key.1.saturating_add(1));
+
+        let start = Key::with_priority(key.priority(), 0).to_bytes();
+        iter.seek_for_prev(start);
+        if iter.key() == Some(&start[..]) {
+            iter.prev();
+        }
+    }
+    iter.status()?;
+
+    Ok(offset)
+}
+
+// column families written before keys were binary encoded store them as
+// strings, they are rewritten in one atomic batch the first time such a
+// column family is opened
+fn migrate_legacy_keys(db: &DB, cf: &ColumnFamily) -> Result<(), StorageError> {
+    match db.iterator_cf(cf, IteratorMode::Start).next() {
+        Some((key, _)) if key.len() != Key::ENCODED_LEN => {}
+        _ => return Ok(()),
+    }
+
+    let mut batch = WriteBatch::default();
+    let mut iter = db.iterator_cf(cf, IteratorMode::Start);
+    for (key, value) in iter.by_ref() {
+        if key.len() != Key::ENCODED_LEN {
+            batch.put_cf(cf, Key::from_legacy(&key)?.to_bytes(), value);
+            batch.delete_cf(cf, key);
         }
     }
+    iter.status()?;
+
+    db.write(batch)?;
+    Ok(())
+}
+
+// the status code of a rocksdb error, `rocksdb::Error` of this version only
+// carries the text of `Status::ToString`, which starts with the code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorKind {
+    NoSpace,
+    IoError,
+    Corruption,
+    Other,
+}
+
+impl ErrorKind {
+    fn of(e: &rocksdb::Error) -> Self {
+        let (code, state) = e.as_ref().split_once(": ").unwrap_or((e.as_ref(), ""));
+        match code {
+            // `Status::NoSpace` is an io error with its own subcode
+            "IO error" if state.starts_with("No space left on device") => Self::NoSpace,
+            "IO error" => Self::IoError,
+            "Corruption" => Self::Corruption,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl From<rocksdb::Error> for StorageError {
+    fn from(e: rocksdb::Error) -> Self {
+        match ErrorKind::of(&e) {
+            ErrorKind::NoSpace => Self::Full,
+            ErrorKind::IoError => Self::Io(io::Error::new(io::ErrorKind::Other, e.into_string())),
+            ErrorKind::Corruption => Self::Corruption(e.into_string()),
+            ErrorKind::Other => Self::Backend(e.into_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Overflow;
+
+    use super::*;
+
+    fn records(storage: &Rocksdb, cf: &str) -> usize {
+        let inner = storage.read();
+        let cf = inner.db.cf_handle(cf).unwrap();
+        inner.db.iterator_cf(cf, IteratorMode::Start).count()
+    }
+
+    #[test]
+    fn it_creates_and_drops_queues() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Rocksdb::new(path.as_ref().join("rocksdb"), "q", 0).unwrap();
+
+        storage.create_queue("session").unwrap();
+        let key = storage.push("session", Payload::from_static(b"1")).unwrap();
+
+        assert!(storage.contains("session"));
+        assert_eq!(storage.names(), vec!["session".to_string()]);
+        assert_eq!(storage.batch("session", 10).unwrap()[0].0, key);
+
+        storage.drop_queue("session").unwrap();
+
+        assert!(!storage.contains("session"));
+        assert!(storage.read().db.cf_handle("session").is_none());
+        assert!(matches!(
+            storage.push("session", Payload::from_static(b"1")),
+            Err(StorageError::UnknownQueue(_))
+        ));
+    }
+
+    #[test]
+    fn it_recovers_queues_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 2).unwrap();
+        storage.push("q0", Payload::from_static(b"1")).unwrap();
+        let last = storage.push("q0", Payload::from_static(b"2")).unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        let mut names = storage.names();
+        names.sort();
+        assert_eq!(names, vec!["q0".to_string(), "q1".to_string()]);
+
+        let next = storage.push("q0", Payload::from_static(b"3")).unwrap();
+        // offsets reserved before the restart are skipped
+        assert!(next > last);
+        assert_eq!(storage.batch("q0", 10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn it_keeps_offsets_of_drained_queues() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        let last = storage.push("q0", Payload::from_static(b"1")).unwrap();
+        storage.remove("q0", last).unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        let next = storage
+            .push_with_priority("q0", 2, Payload::from_static(b"2"))
+            .unwrap();
+        assert!(next.1 > last.1);
+    }
+
+    #[test]
+    fn it_delivers_higher_priority_first() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        storage.push("q0", Payload::from_static(b"1")).unwrap();
+        storage
+            .push_with_priority("q0", 7, Payload::from_static(b"2"))
+            .unwrap();
+        let last = storage
+            .push_with_priority("q0", 3, Payload::from_static(b"3"))
+            .unwrap();
+
+        let payloads: Vec<_> = storage
+            .batch("q0", 10)
+            .unwrap()
+            .into_iter()
+            .map(|(_, payload)| payload.to_vec())
+            .collect();
+        assert_eq!(payloads, vec![b"2", b"3", b"1"]);
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        let next = storage.push("q0", Payload::from_static(b"4")).unwrap();
+        assert!(next.1 > last.1);
+    }
+
+    #[test]
+    fn it_migrates_legacy_string_keys() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        {
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            let mut db = DB::open(&opts, &path).unwrap();
+            db.create_cf("q0", &Options::default()).unwrap();
+            let cf = db.cf_handle("q0").unwrap();
+            db.put_cf(cf, "0000_000000000007", b"1").unwrap();
+            db.put_cf(cf, "0004_000000000009", b"2").unwrap();
+        }
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert_eq!(
+            storage.batch("q0", 10).unwrap(),
+            vec![
+                (Key::with_priority(4, 9), Payload::from_static(b"2")),
+                (Key::with_priority(0, 7), Payload::from_static(b"1")),
+            ]
+        );
+        assert_eq!(
+            storage.push("q0", Payload::from_static(b"3")).unwrap(),
+            Key::with_offset(10)
+        );
+    }
+
+    #[test]
+    fn it_keeps_leases_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        let keys: Vec<_> = (0..3)
+            .map(|i| storage.push("q0", Payload::from(vec![i])).unwrap())
+            .collect();
+
+        storage.checkout("q0", 2, Duration::from_secs(60)).unwrap();
+        storage.remove("q0", keys[0]).unwrap();
+        assert_eq!(records(&storage, &leases_cf("q0")), 1);
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert_eq!(
+            storage.checkout("q0", 10, Duration::from_secs(60)).unwrap(),
+            vec![(keys[2], Payload::from(vec![2]))]
+        );
+        assert!(storage
+            .checkout("q0", 10, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_counts_queued_messages_across_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 2).unwrap();
+        let keys = storage
+            .push_many(vec![
+                ("q0", Payload::from_static(b"a")),
+                ("q1", Payload::from_static(b"bb")),
+                ("q0", Payload::from_static(b"ccc")),
+            ])
+            .unwrap();
+        storage.remove("q0", keys[0]).unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert_eq!(storage.len("q0").unwrap(), 1);
+        assert_eq!(storage.size_bytes("q0").unwrap(), 3);
+        assert_eq!(storage.size_bytes("q1").unwrap(), 2);
+
+        storage.remove_up_to("q0", keys[2]).unwrap();
+        assert_eq!(storage.len("q0").unwrap(), 0);
+        assert_eq!(storage.oldest_key("q0").unwrap(), None);
+    }
+
+    #[test]
+    fn it_keeps_push_times_and_expired_counts_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_millis(1))
+            .unwrap();
+        let key = storage.push("q0", Payload::from_static(b"bb")).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(storage.purge_expired("q0").unwrap(), 1);
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        let stats = storage.stats("q0").unwrap();
+        assert_eq!((stats.len, stats.size_bytes, stats.expired), (1, 2, 1));
+        assert_eq!(stats.oldest, Some(key));
+        assert!(stats.oldest_age.unwrap() >= Duration::from_millis(20));
+        assert_eq!(records(&storage, &meta_cf("q0")), 2);
+    }
+
+    #[test]
+    fn it_removes_up_to_the_last_offset_of_a_priority() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Rocksdb::new(path.as_ref().join("rocksdb"), "q", 1).unwrap();
+
+        storage
+            .push_with_priority("q0", 1, Payload::from_static(b"1"))
+            .unwrap();
+        let low = storage.push("q0", Payload::from_static(b"2")).unwrap();
+
+        storage
+            .remove_up_to("q0", Key::with_priority(1, u64::MAX))
+            .unwrap();
+        assert_eq!(
+            storage.batch("q0", 10).unwrap(),
+            vec![(low, Payload::from_static(b"2"))]
+        );
+        assert_eq!(storage.len("q0").unwrap(), 1);
+    }
+
+    #[test]
+    fn it_keeps_limits_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let limits = Limits {
+            max_len: Some(1),
+            max_bytes: None,
+            overflow: Overflow::DropOldest,
+            max_deliveries: Some(5),
+        };
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        storage.create_queue_with("limited", limits).unwrap();
+        let first = storage.push("limited", Payload::from_static(b"a")).unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert_eq!(storage.limits("limited").unwrap(), limits);
+        assert_eq!(storage.limits("q0").unwrap(), Limits::default());
+
+        let pushed = storage
+            .push_with(
+                "limited",
+                Payload::from_static(b"b"),
+                PushOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(pushed.dropped, vec![first]);
+    }
+
+    #[test]
+    fn it_keeps_expiry_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_secs(0))
+            .unwrap();
+        let kept = storage
+            .push_with_expiry("q0", Payload::from_static(b"b"), Duration::from_secs(3600))
+            .unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        let keys: Vec<_> = storage
+            .batch("q0", 10)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![kept]);
+        assert_eq!(storage.expired("q0").unwrap(), 1);
+        assert_eq!(records(&storage, &expiry_cf("q0")), 1);
+    }
+
+    #[test]
+    fn it_moves_messages_between_queues() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 2).unwrap();
+        let options = PushOptions {
+            priority: 3,
+            ttl: Some(Duration::from_secs(3600)),
+        };
+        let key = storage
+            .push_with("q0", Payload::from_static(b"a"), options)
+            .unwrap()
+            .key;
+        storage.checkout("q0", 1, Duration::from_secs(60)).unwrap();
+
+        let moved = storage.move_to("q0", key, "q1").unwrap().unwrap();
+        assert_eq!(moved.priority(), 3);
+        assert_eq!(storage.move_to("q0", key, "q1").unwrap(), None);
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert!(storage.batch("q0", 10).unwrap().is_empty());
+        assert_eq!(records(&storage, &leases_cf("q0")), 0);
+        assert_eq!(
+            storage.batch("q1", 10).unwrap(),
+            vec![(moved, Payload::from_static(b"a"))]
+        );
+        assert_eq!(records(&storage, &expiry_cf("q1")), 1);
+    }
+
+    #[test]
+    fn it_keeps_retained_messages_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        for topic in &[
+            "devices/1",
+            "devices/1/battery",
+            "devices/2/battery",
+            "devices/1/fw",
+        ] {
+            let message = StoredMessage {
+                topic: topic.to_string(),
+                retain: true,
+                received_at: lease::now(),
+                payload: Payload::from_static(b"1"),
+                ..StoredMessage::default()
+            };
+            storage.set_retained(&message).unwrap();
+        }
+        storage.clear_retained("devices/1/fw").unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert!(storage.retained("devices/1/fw").unwrap().is_none());
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+
+        let topics = |filter| {
+            let mut topics: Vec<_> = storage
+                .matching(filter)
+                .unwrap()
+                .into_iter()
+                .map(|message| message.topic)
+                .collect();
+            topics.sort();
+            topics
+        };
+        assert_eq!(
+            topics("devices/+/battery"),
+            ["devices/1/battery", "devices/2/battery"]
+        );
+        assert_eq!(topics("devices/1/#"), ["devices/1", "devices/1/battery"]);
+        assert!(matches!(
+            storage.set_retained(&StoredMessage {
+                topic: "devices/#".into(),
+                payload: Payload::from_static(b"1"),
+                ..StoredMessage::default()
+            }),
+            Err(StorageError::InvalidTopic(_))
+        ));
+    }
+
+    #[test]
+    fn it_recovers_inflight_window_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let storage = Rocksdb::new(path.clone(), "q", 1).unwrap();
+        let keys: Vec<_> = (0..3u8)
+            .map(|i| storage.push("q0", Payload::from(vec![i])).unwrap())
+            .collect();
+        let ids: Vec<_> = keys
+            .iter()
+            .map(|key| storage.publish("q0", *key).unwrap())
+            .collect();
+        assert_eq!(ids, [1, 2, 3]);
+
+        storage.pubrec("q0", ids[0]).unwrap();
+        storage.complete("q0", ids[1]).unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert_eq!(
+            storage.inflight("q0").unwrap(),
+            [
+                (1, Inflight::new(keys[0], PacketState::Pubrel)),
+                (3, Inflight::new(keys[2], PacketState::Publish)),
+            ]
+        );
+        assert_eq!(storage.len("q0").unwrap(), 1);
+        assert!(matches!(
+            storage.publish("q0", keys[2]),
+            Err(StorageError::AlreadyInflight(3))
+        ));
+        assert!(matches!(
+            storage.publish("q0", keys[1]),
+            Err(StorageError::NotQueued(key)) if key == keys[1]
+        ));
+        let key = storage.push("q0", Payload::from_static(b"4")).unwrap();
+        assert_eq!(storage.publish("q0", key).unwrap(), 4);
+    }
+
+    #[test]
+    fn it_restores_subscriptions_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("rocksdb");
+
+        let options = |qos| SubscriptionOptions {
+            qos,
+            ..SubscriptionOptions::default()
+        };
+        let storage = Rocksdb::new(path.clone(), "q", 0).unwrap();
+        storage
+            .add_subscription("a", "devices/+/battery", options(QoS::AtLeastOnce))
+            .unwrap();
+        storage
+            .add_subscription("ab", "devices/#", options(QoS::ExactlyOnce))
+            .unwrap();
+        storage
+            .add_subscription("b", "devices/#", options(QoS::AtMostOnce))
+            .unwrap();
+        storage.remove_client("b").unwrap();
+        drop(storage);
+
+        // subscriptions are read before the queues are opened
+        let storage = Rocksdb::open(path.clone()).unwrap();
+        storage.create_queue("q0").unwrap();
+        drop(storage);
+
+        let storage = Rocksdb::open(path).unwrap();
+        assert_eq!(
+            storage.route("devices/7/battery"),
+            [
+                ("a".to_string(), QoS::AtLeastOnce),
+                ("ab".to_string(), QoS::ExactlyOnce)
+            ]
+        );
+        assert!(storage.subscriptions("b").is_empty());
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+    }
+
+    #[test]
+    fn it_reads_batches_after_key() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Rocksdb::new(path.as_ref().join("rocksdb"), "q", 1).unwrap();
+        crate::tests::it_reads_batches_after_key(&storage);
+    }
+
+    #[test]
+    fn it_leases_nothing_when_dead_lettering_fails() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Rocksdb::new(path.as_ref().join("rocksdb"), "q", 0).unwrap();
+        crate::tests::it_leases_nothing_when_dead_lettering_fails(&storage);
+    }
 }
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, convert::TryFrom};
+
+use crate::{Key, StorageError};
+
+// the outbound QoS 1 and 2 inflight window of a session, kept next to its
+// queue so that exchanges resume after a restart. Every inflight message is
+// known by the packet id it was published with. State changes that retire a
+// message remove it from the queue in the same write, so a crash never
+// leaves a released message queued or a queued message without its exchange
+pub trait SessionStateStore {
+    // allocates a packet id unused by the window and records the message at
+    // `key` as published; the message has to be queued and not inflight
+    // already, see `StorageError::NotQueued` and `StorageError::AlreadyInflight`
+    fn publish(&self, name: &str, key: Key) -> Result<u16, StorageError>;
+
+    // PUBREC: the message leaves the queue and PUBREL is what is resent from
+    // now on; a repeated PUBREC changes nothing
+    fn pubrec(&self, name: &str, packet_id: u16) -> Result<(), StorageError>;
+
+    // PUBACK or PUBCOMP end the exchange and free the packet id, a message
+    // still queued is removed with it
+    fn complete(&self, name: &str, packet_id: u16) -> Result<(), StorageError>;
+
+    // the window in queue order, which is the order to resend it in
+    fn inflight(&self, name: &str) -> Result<Vec<(u16, Inflight)>, StorageError>;
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Inflight {
+    pub key: Key,
+    pub state: PacketState,
+}
+
+// what to resend for an exchange when the session resumes
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PacketState {
+    Publish,
+    Pubrel,
+}
+
+pub(crate) const RECORD_LEN: usize = 1 + Key::ENCODED_LEN;
+
+impl Inflight {
+    pub(crate) fn new(key: Key, state: PacketState) -> Self {
+        Self { key, state }
+    }
+
+    // records are keyed by the packet id in big endian
+    pub(crate) fn to_bytes(self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0; RECORD_LEN];
+        bytes[0] = match self.state {
+            PacketState::Publish => 0,
+            PacketState::Pubrel => 1,
+        };
+        bytes[1..].copy_from_slice(&self.key.to_bytes());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for Inflight {
+    type Error = StorageError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let invalid = || StorageError::Corruption(format!("invalid inflight record: {:?}", bytes));
+        if bytes.len() != RECORD_LEN {
+            return Err(invalid());
+        }
+
+        let state = match bytes[0] {
+            0 => PacketState::Publish,
+            1 => PacketState::Pubrel,
+            _ => return Err(invalid()),
+        };
+        Ok(Self::new(Key::try_from(&bytes[1..])?, state))
+    }
+}
+
+pub(crate) fn packet_id(bytes: &[u8]) -> Result<u16, StorageError> {
+    match *bytes {
+        [high, low] => Ok(u16::from_be_bytes([high, low])),
+        _ => Err(StorageError::Corruption(format!(
+            "invalid packet id: {:?}",
+            bytes
+        ))),
+    }
+}
+
+// the inflight window of a queue as far as allocation needs it: the id
+// handed out last and the ids of the messages inflight. State changes of the
+// window hold it, so it always agrees with the stored records
+#[derive(Debug, Default)]
+pub(crate) struct Window {
+    last: u16,
+    ids: HashMap<Key, u16>,
+}
+
+impl Window {
+    // allocation goes on after the highest id inflight
+    pub(crate) fn load(
+        records: impl IntoIterator<Item = Result<(u16, Inflight), StorageError>>,
+    ) -> Result<Self, StorageError> {
+        let mut window = Self::default();
+        for record in records {
+            let (packet_id, inflight) = record?;
+            window.last = window.last.max(packet_id);
+            window.ids.insert(inflight.key, packet_id);
+        }
+        Ok(window)
+    }
+
+    pub(crate) fn allocate(
+        &self,
+        name: &str,
+        key: Key,
+        in_use: impl FnMut(u16) -> Result<bool, StorageError>,
+    ) -> Result<u16, StorageError> {
+        if let Some(packet_id) = self.ids.get(&key) {
+            return Err(StorageError::AlreadyInflight(*packet_id));
+        }
+
+        allocate(self.last, in_use)?.ok_or_else(|| StorageError::InflightFull(name.into()))
+    }
+
+    pub(crate) fn publish(&mut self, packet_id: u16, key: Key) {
+        self.last = packet_id;
+        self.ids.insert(key, packet_id);
+    }
+
+    pub(crate) fn complete(&mut self, key: Key) {
+        self.ids.remove(&key);
+    }
+
+    // an exchange restored from a dump replaces the one under its id,
+    // allocation goes on after it like after a loaded record
+    pub(crate) fn restore(&mut self, packet_id: u16, key: Key) {
+        self.last = self.last.max(packet_id);
+        self.ids.retain(|_, id| *id != packet_id);
+        self.ids.insert(key, packet_id);
+    }
+}
+
+// the next id after `last` that is not in use, ids wrap around and skip 0
+// like on the wire; `None` when all of them are inflight
+pub(crate) fn allocate(
+    last: u16,
+    mut in_use: impl FnMut(u16) -> Result<bool, StorageError>,
+) -> Result<Option<u16>, StorageError> {
+    let mut id = last;
+    for _ in 0..u16::MAX {
+        id = id.checked_add(1).unwrap_or(1);
+        if !in_use(id)? {
+            return Ok(Some(id));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_ids_skip_zero_and_ids_in_use() {
+        assert_eq!(allocate(0, |_| Ok(false)).unwrap(), Some(1));
+        assert_eq!(allocate(u16::MAX, |_| Ok(false)).unwrap(), Some(1));
+        assert_eq!(allocate(4, |id| Ok(id < 7)).unwrap(), Some(7));
+        assert_eq!(allocate(9, |id| Ok(id != 3)).unwrap(), Some(3));
+        assert_eq!(allocate(9, |_| Ok(true)).unwrap(), None);
+
+        let inflight = Inflight::new(Key::with_priority(2, 40), PacketState::Pubrel);
+        assert_eq!(
+            Inflight::try_from(&inflight.to_bytes()[..]).unwrap(),
+            inflight
+        );
+    }
+}
@@ -1,18 +1,54 @@
 use std::{
     collections::{HashMap, VecDeque},
+    convert::TryFrom,
     fmt::Display,
+    ops::Bound,
     path::Path,
-    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
+    time::Duration,
 };
 
-use sled::{Db, Tree};
+use dashmap::{mapref::one::Ref, DashMap};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError, Transactional},
+    Db, IVec, Tree,
+};
+use tokio::sync::Notify;
 
-use crate::{Key, Payload, Storage};
+use crate::{
+    expiry,
+    lease::{self, Lease},
+    offset::{self, Offsets},
+    retained,
+    session::{self, Inflight, PacketState, Window},
+    stats::{self, Entry, Tally},
+    subscription::{self, Subscriptions},
+    topic, Budget, Checkout, Delivered, Delivery, Key, Limits, MessageState, Payload, PushOptions,
+    Pushed, QoS, QueueStats, ReadySet, RetainedStore, SessionStateStore, Storage, StorageError,
+    StoredMessage, SubscriptionOptions, SubscriptionStore, SYSTEM_PREFIX,
+};
 
 pub struct Sled {
     _path: Box<dyn AsRef<Path> + Send + Sync>,
-    _db: Db,
-    queues: HashMap<String, Queue>,
+    db: Db,
+    queues: DashMap<String, Queue>,
+    ready: ReadySet,
+    budget: Arc<Budget>,
+    // limits of every queue keyed by its name
+    limits: Tree,
+    // high-water offset marks of every queue keyed by its name, see
+    // `offset::Offsets`
+    offsets: Tree,
+    // retained messages keyed by topic
+    retained: Tree,
+    // subscription options keyed by client id and filter, see
+    // `subscription::record_key`
+    subscriptions: Tree,
+    // everything in `subscriptions` arranged for routing
+    routes: RwLock<Subscriptions>,
 }
 
 impl Sled {
@@ -20,105 +56,1881 @@ impl Sled {
         path: impl AsRef<Path> + Send + Sync + 'static,
         prefix: impl Display,
         count: u16,
-    ) -> Self {
+    ) -> Result<Self, StorageError> {
         if path.as_ref().exists() {
-            std::fs::remove_dir_all(&path).unwrap();
+            std::fs::remove_dir_all(&path)?;
         }
 
-        let db = sled::open(&path).unwrap();
-        let mut queues = HashMap::new();
-
+        let storage = Self::open(path)?;
         for i in 0..count {
-            let name = format!("{}{}", prefix, i);
-            let tree = db.open_tree(&name).unwrap();
-            queues.insert(name, Queue::new(tree));
+            storage.create_queue(&format!("{}{}", prefix, i))?;
         }
 
-        Self {
-            _path: Box::new(path),
-            _db: db,
-            queues,
+        Ok(storage)
+    }
+
+    pub fn open(path: impl AsRef<Path> + Send + Sync + 'static) -> Result<Self, StorageError> {
+        let db = sled::open(&path)?;
+        Self::with_db(Box::new(path), db)
+    }
+
+    // everything besides the trees is rebuilt from them
+    fn with_db(path: Box<dyn AsRef<Path> + Send + Sync>, db: Db) -> Result<Self, StorageError> {
+        let limits = db.open_tree(format!("{}limits", SYSTEM_PREFIX))?;
+        let offsets = db.open_tree(format!("{}offsets", SYSTEM_PREFIX))?;
+        let retained = db.open_tree(format!("{}retained", SYSTEM_PREFIX))?;
+        let subscriptions = db.open_tree(format!("{}subscriptions", SYSTEM_PREFIX))?;
+        let mut routes = Subscriptions::default();
+        for item in subscriptions.iter() {
+            let (key, value) = item?;
+            let (client_id, filter, options) = subscription::decode(&key, &value)?;
+            routes.add(&client_id, &filter, options);
         }
+        let queues = DashMap::new();
+        let ready = ReadySet::default();
+        let budget = Arc::new(Budget::default());
+
+        for name in db.tree_names() {
+            let name = String::from_utf8_lossy(&name).into_owned();
+            if name.starts_with(SYSTEM_PREFIX) {
+                continue;
+            }
+
+            let queue_limits = match limits.get(&name)? {
+                Some(bytes) => Limits::try_from(&*bytes)?,
+                None => Limits::default(),
+            };
+            let queue = Queue::open(&db, &name, queue_limits, &offsets, &budget)?;
+            ready.mark(&name);
+            queues.insert(name, queue);
+        }
+
+        Ok(Self {
+            _path: path,
+            db,
+            queues,
+            ready,
+            budget,
+            limits,
+            offsets,
+            retained,
+            subscriptions,
+            routes: RwLock::new(routes),
+        })
+    }
+
+    fn queue(&self, name: &str) -> Result<Ref<'_, String, Queue>, StorageError> {
+        self.queues
+            .get(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))
     }
 }
 
 impl Storage for Sled {
     fn names(&self) -> Vec<String> {
-        self.queues.keys().cloned().collect()
+        self.queues.iter().map(|i| i.key().clone()).collect()
     }
 
-    fn push(&self, name: &str, payload: Payload) -> Key {
-        if let Some(queue) = self.queues.get(name) {
-            queue.push(payload)
-        } else {
-            panic!("no tree: {}", name)
+    fn contains(&self, name: &str) -> bool {
+        self.queues.contains_key(name)
+    }
+
+    fn create_queue_with(&self, name: &str, limits: Limits) -> Result<(), StorageError> {
+        crate::check_queue_name(name)?;
+        if !self.queues.contains_key(name) {
+            self.limits.insert(name, &limits.to_bytes()[..])?;
+            let queue = Queue::open(&self.db, name, limits, &self.offsets, &self.budget)?;
+            self.queues.entry(name.into()).or_insert(queue);
         }
+
+        Ok(())
     }
 
-    fn batch(&self, name: &str, size: usize) -> VecDeque<(Key, Payload)> {
-        if let Some(queue) = self.queues.get(name) {
-            queue.batch(size)
-        } else {
-            panic!("no tree: {}", name)
+    fn limits(&self, name: &str) -> Result<Limits, StorageError> {
+        Ok(self.queue(name)?.limits)
+    }
+
+    // the map entry is removed first: it waits for in-flight operations on
+    // the queue and makes any later push fail with `UnknownQueue` instead of
+    // writing into a dropped tree
+    fn drop_queue(&self, name: &str) -> Result<(), StorageError> {
+        let (_, queue) = self
+            .queues
+            .remove(name)
+            .ok_or_else(|| StorageError::UnknownQueue(name.into()))?;
+        queue.notify.notify_one();
+
+        self.db.drop_tree(name)?;
+        self.db.drop_tree(leases_tree(name))?;
+        self.db.drop_tree(expiry_tree(name))?;
+        self.db.drop_tree(session_tree(name))?;
+        self.db.drop_tree(meta_tree(name))?;
+        self.limits.remove(name)?;
+        self.offsets.remove(name)?;
+        Ok(())
+    }
+
+    fn subscribe(&self, name: &str) -> Result<Arc<Notify>, StorageError> {
+        Ok(self.queue(name)?.notify.clone())
+    }
+
+    fn ready(&self) -> &ReadySet {
+        &self.ready
+    }
+
+    fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    fn push_with(
+        &self,
+        name: &str,
+        payload: Payload,
+        options: PushOptions,
+    ) -> Result<Pushed, StorageError> {
+        let pushed = self.queue(name)?.push(name, options, payload)?;
+        self.ready.mark(name);
+        Ok(pushed)
+    }
+
+    fn restore(
+        &self,
+        name: &str,
+        key: Key,
+        payload: Payload,
+        state: MessageState,
+    ) -> Result<Key, StorageError> {
+        self.queue(name)?.restore(name, key, payload, state)?;
+        self.ready.mark(name);
+        Ok(key)
+    }
+
+    // a burst into one queue is a single tree batch, anything that spans
+    // several queues needs a transaction over all of their trees
+    fn push_many_with_priority(
+        &self,
+        items: Vec<(&str, u16, Payload)>,
+    ) -> Result<Vec<Key>, StorageError> {
+        let mut groups: Vec<Group<'_>> = Vec::new();
+        let mut indexes = HashMap::new();
+        let mut entries = Vec::with_capacity(items.len());
+
+        for (name, priority, payload) in items {
+            let queue = self.queue(name)?;
+            let index = *indexes.entry(name).or_insert_with(|| {
+                groups.push(Group::new(name, &queue));
+                groups.len() - 1
+            });
+
+            let key = queue.next_key(name, priority)?;
+            groups[index].incoming.push((key, payload.len()));
+            entries.push((index, key, IVec::from(&*payload)));
+        }
+
+        // counted before the write like a single push, see `Queue::push`
+        let now = lease::now();
+        for index in 0..groups.len() {
+            let group = &groups[index];
+            let dropped =
+                lock(&group.tally).reserve(group.name, &group.limits, &group.incoming, now);
+            match dropped {
+                Ok(dropped) => groups[index].dropped = dropped,
+                Err(e) => {
+                    groups[..index].iter_mut().for_each(Group::unreserve);
+                    return Err(e);
+                }
+            }
+        }
+
+        // every queue comes with its lease and record trees, the lease tree
+        // only needs a write for messages dropped to make room
+        let trees: Vec<_> = groups
+            .iter()
+            .flat_map(|group| vec![group.tree.clone(), group.leases.clone(), group.meta.clone()])
+            .collect();
+        let written = trees[..]
+            .transaction(|trees| {
+                for (index, key, payload) in &entries {
+                    let record = stats::record(payload.len(), now);
+                    trees[3 * index].insert(&key.to_bytes()[..], payload.clone())?;
+                    trees[3 * index + 2].insert(&key.to_bytes()[..], &record[..])?;
+                }
+                for (trees, group) in trees.chunks(3).zip(&groups) {
+                    for (key, _) in &group.dropped {
+                        for tree in trees {
+                            tree.remove(&key.to_bytes()[..])?;
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(StorageError::from);
+
+        if let Err(e) = written {
+            groups.iter_mut().for_each(Group::unreserve);
+            return Err(e);
+        }
+
+        for group in &groups {
+            group.notify.notify_one();
+            self.ready.mark(group.name);
         }
+        Ok(entries.into_iter().map(|(_, key, _)| key).collect())
     }
 
-    fn remove(&self, name: &str, key: Key) {
-        if let Some(queue) = self.queues.get(name) {
-            queue.remove(key);
-        } else {
-            panic!("no tree: {}", name)
+    fn batch(&self, name: &str, size: usize) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let queue = self.queue(name)?;
+        queue.purge(lease::now())?;
+        queue.batch(size)
+    }
+
+    fn batch_after(
+        &self,
+        name: &str,
+        after: Key,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let queue = self.queue(name)?;
+        queue.purge(lease::now())?;
+        queue.batch_after(after, size)
+    }
+
+    fn peek(
+        &self,
+        name: &str,
+        after: Option<Key>,
+        size: usize,
+    ) -> Result<VecDeque<(Key, Payload, MessageState)>, StorageError> {
+        self.queue(name)?.peek(after, size)
+    }
+
+    fn remove(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        self.queue(name)?.remove(key)
+    }
+
+    fn remove_many(&self, name: &str, keys: &[Key]) -> Result<(), StorageError> {
+        self.queue(name)?.remove_many(keys.iter().copied())
+    }
+
+    fn remove_up_to(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        let queue = self.queue(name)?;
+        let keys = queue
+            .tree
+            .range(..=key.to_bytes())
+            .keys()
+            .map(|key| Key::try_from(&*key?))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        queue.remove_many(keys)
+    }
+
+    fn checkout(
+        &self,
+        name: &str,
+        size: usize,
+        lease: Duration,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        crate::checkout(self, name, || {
+            let queue = self.queue(name)?;
+            queue.purge(lease::now())?;
+            queue.checkout(size, lease)
+        })
+    }
+
+    fn batch_with_delivery(
+        &self,
+        name: &str,
+        size: usize,
+    ) -> Result<VecDeque<Delivered>, StorageError> {
+        let queue = self.queue(name)?;
+        queue.purge(lease::now())?;
+        queue.batch_with_delivery(size)
+    }
+
+    fn delivery(&self, name: &str, key: Key) -> Result<Delivery, StorageError> {
+        let queue = self.queue(name)?;
+        match queue.leases.get(key.to_bytes())? {
+            Some(lease) => Ok(Delivery::of(Some(Lease::try_from(&*lease)?))),
+            None => Ok(Delivery::default()),
+        }
+    }
+
+    fn mark_delivered(&self, name: &str, key: Key) -> Result<(), StorageError> {
+        self.queue(name)?.mark_delivered(key)
+    }
+
+    // one transaction takes the message and its lease out of `from` and
+    // writes it into `to`, room in `to` is reserved like for a push
+    fn move_to(&self, from: &str, key: Key, to: &str) -> Result<Option<Key>, StorageError> {
+        let (tree, leases, meta, tally) = {
+            let source = self.queue(from)?;
+            source.purge(lease::now())?;
+            (
+                source.tree.clone(),
+                source.leases.clone(),
+                source.meta.clone(),
+                source.tally.clone(),
+            )
+        };
+        let payload = match tree.get(key.to_bytes())? {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+        if from == to {
+            return Ok(Some(key));
+        }
+
+        let deadline = lock(&tally).get(key).and_then(|entry| entry.expires_at);
+        let target = self.queue(to)?;
+        let moved = target.next_key(to, key.priority())?;
+        let incoming = [(moved, payload.len())];
+        let now = lease::now();
+        let dropped = lock(&target.tally).reserve(to, &target.limits, &incoming, now)?;
+
+        let record = stats::record(payload.len(), now);
+        let trees = (
+            &tree,
+            &leases,
+            &meta,
+            &target.tree,
+            &target.leases,
+            &target.meta,
+            &target.expiry,
+        );
+        let written = trees
+            .transaction(
+                |(tree, leases, meta, target_tree, target_leases, target_meta, expiry)| {
+                    if tree.remove(&key.to_bytes()[..])?.is_none() {
+                        return Ok(false);
+                    }
+                    leases.remove(&key.to_bytes()[..])?;
+                    meta.remove(&key.to_bytes()[..])?;
+                    target_tree.insert(&moved.to_bytes()[..], payload.clone())?;
+                    target_meta.insert(&moved.to_bytes()[..], &record[..])?;
+                    for (key, _) in &dropped {
+                        target_tree.remove(&key.to_bytes()[..])?;
+                        target_leases.remove(&key.to_bytes()[..])?;
+                        target_meta.remove(&key.to_bytes()[..])?;
+                    }
+                    if let Some(deadline) = deadline {
+                        expiry.insert(&expiry::record(deadline, moved)[..], &[][..])?;
+                    }
+                    Ok(true)
+                },
+            )
+            .map_err(StorageError::from);
+        match written {
+            Ok(true) => {}
+            Ok(false) => {
+                lock(&target.tally).unreserve(&incoming, dropped);
+                return Ok(None);
+            }
+            Err(e) => {
+                lock(&target.tally).unreserve(&incoming, dropped);
+                return Err(e);
+            }
+        }
+
+        lock(&tally).remove(key);
+        if let Some(deadline) = deadline {
+            lock(&target.tally).expire(moved, deadline);
+            target.next_due.fetch_min(deadline, Ordering::SeqCst);
+        }
+        target.notify.notify_one();
+        drop(target);
+
+        self.ready.mark(to);
+        Ok(Some(moved))
+    }
+
+    fn available_at(&self, name: &str) -> Result<Option<u64>, StorageError> {
+        let now = lease::now();
+        let queue = self.queue(name)?;
+        queue.purge(now)?;
+        let leases = queue
+            .leases
+            .iter()
+            .values()
+            .map(|lease| Lease::try_from(&*lease?));
+        let len = lock(&queue.tally).len();
+        lease::available_at(len, leases, now)
+    }
+
+    fn purge_expired(&self, name: &str) -> Result<usize, StorageError> {
+        self.queue(name)?.purge(lease::now())
+    }
+
+    fn stats(&self, name: &str) -> Result<QueueStats, StorageError> {
+        Ok(lock(&self.queue(name)?.tally).stats(lease::now()))
+    }
+
+    fn retained_messages(&self) -> Result<Vec<StoredMessage>, StorageError> {
+        self.retained
+            .iter()
+            .values()
+            .map(|bytes| StoredMessage::try_from(Payload::copy_from_slice(&bytes?)))
+            .collect()
+    }
+
+    fn restore_retained(&self, message: &StoredMessage) -> Result<(), StorageError> {
+        self.set_retained(message)
+    }
+
+    fn all_subscriptions(
+        &self,
+    ) -> Result<Vec<(String, String, SubscriptionOptions)>, StorageError> {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(routes.all())
+    }
+
+    fn restore_subscription(
+        &self,
+        client_id: &str,
+        filter: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), StorageError> {
+        self.add_subscription(client_id, filter, options)
+    }
+
+    fn inflight_window(&self, name: &str) -> Result<Vec<(u16, Inflight)>, StorageError> {
+        self.inflight(name)
+    }
+
+    fn restore_inflight(
+        &self,
+        name: &str,
+        packet_id: u16,
+        inflight: Inflight,
+    ) -> Result<(), StorageError> {
+        self.queue(name)?.restore_inflight(packet_id, inflight)
+    }
+}
+
+impl RetainedStore for Sled {
+    fn set_retained(&self, message: &StoredMessage) -> Result<(), StorageError> {
+        topic::validate_name(&message.topic)?;
+        if message.payload.is_empty() {
+            return self.clear_retained(&message.topic);
+        }
+
+        self.retained
+            .insert(message.topic.as_bytes(), &message.to_bytes()?[..])?;
+        Ok(())
+    }
+
+    fn clear_retained(&self, topic: &str) -> Result<(), StorageError> {
+        self.retained.remove(topic)?;
+        Ok(())
+    }
+
+    fn retained(&self, topic: &str) -> Result<Option<StoredMessage>, StorageError> {
+        let message = match self.retained.get(topic)? {
+            Some(bytes) => StoredMessage::try_from(Payload::copy_from_slice(&bytes))?,
+            None => return Ok(None),
+        };
+
+        Ok(Some(message).filter(|message| retained::is_live(message, lease::now())))
+    }
+
+    // only the range of topics starting with the literal part of the filter
+    // is scanned
+    fn matching(&self, filter: &str) -> Result<Vec<StoredMessage>, StorageError> {
+        topic::validate(filter)?;
+
+        let now = lease::now();
+        let mut found = Vec::new();
+        for item in self.retained.scan_prefix(topic::literal_prefix(filter)) {
+            let (topic, bytes) = item?;
+            if !topic::matches(filter, &String::from_utf8_lossy(&topic)) {
+                continue;
+            }
+
+            let message = StoredMessage::try_from(Payload::copy_from_slice(&bytes))?;
+            if retained::is_live(&message, now) {
+                found.push(message);
+            }
+        }
+
+        Ok(found)
+    }
+}
+
+impl SessionStateStore for Sled {
+    fn publish(&self, name: &str, key: Key) -> Result<u16, StorageError> {
+        self.queue(name)?.publish(name, key)
+    }
+
+    fn pubrec(&self, name: &str, packet_id: u16) -> Result<(), StorageError> {
+        self.queue(name)?.pubrec(packet_id)
+    }
+
+    fn complete(&self, name: &str, packet_id: u16) -> Result<(), StorageError> {
+        self.queue(name)?.complete(packet_id)
+    }
+
+    fn inflight(&self, name: &str) -> Result<Vec<(u16, Inflight)>, StorageError> {
+        let mut inflight = self
+            .queue(name)?
+            .session
+            .iter()
+            .map(|item| {
+                let (packet_id, record) = item?;
+                Ok((
+                    session::packet_id(&packet_id)?,
+                    Inflight::try_from(&*record)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+
+        inflight.sort_by_key(|(_, inflight)| inflight.key);
+        Ok(inflight)
+    }
+}
+
+// changes are written while holding the routes, so that the tree and the
+// routes see them in the same order
+impl SubscriptionStore for Sled {
+    fn add_subscription(
+        &self,
+        client_id: &str,
+        filter: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), StorageError> {
+        subscription::validate(client_id, filter)?;
+
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        self.subscriptions.insert(
+            subscription::record_key(client_id, filter),
+            &[options.to_byte()],
+        )?;
+        routes.add(client_id, filter, options);
+        Ok(())
+    }
+
+    fn remove_subscription(&self, client_id: &str, filter: &str) -> Result<(), StorageError> {
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        self.subscriptions
+            .remove(subscription::record_key(client_id, filter))?;
+        routes.remove(client_id, filter);
+        Ok(())
+    }
+
+    fn remove_client(&self, client_id: &str) -> Result<(), StorageError> {
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        let mut batch = sled::Batch::default();
+        for key in self
+            .subscriptions
+            .scan_prefix(subscription::client_prefix(client_id))
+            .keys()
+        {
+            batch.remove(key?);
+        }
+        self.subscriptions.apply_batch(batch)?;
+        routes.remove_client(client_id);
+        Ok(())
+    }
+
+    fn subscriptions(&self, client_id: &str) -> Vec<(String, SubscriptionOptions)> {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        routes.of(client_id)
+    }
+
+    fn route(&self, topic: &str) -> Vec<(String, QoS)> {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        routes.route(topic)
+    }
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        match e {
+            sled::Error::CollectionNotFound(name) => {
+                Self::UnknownQueue(String::from_utf8_lossy(&name).into_owned())
+            }
+            sled::Error::Io(e) => Self::Io(e),
+            e @ sled::Error::Corruption { .. } => Self::Corruption(e.to_string()),
+            e => Self::Backend(e.to_string()),
+        }
+    }
+}
+
+impl From<TransactionError<StorageError>> for StorageError {
+    fn from(e: TransactionError<StorageError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
         }
     }
 }
 
 struct Queue {
+    #[allow(dead_code)]
     writes: AtomicUsize,
     tree: Tree,
-    offset: AtomicU64,
+    offsets: Offsets,
+    // the `offsets` tree of the storage, which keeps the mark of this queue
+    marks: Tree,
+    // lease records keyed the same way as messages in `tree`
+    leases: Tree,
+    // size and push time of every message in `tree`, written with it, and
+    // the expired counter under `stats::EXPIRED`; see `stats::record`
+    meta: Tree,
+    // expiry records of messages pushed with a ttl, see `expiry::record`
+    expiry: Tree,
+    // earliest deadline in `expiry`, `u64::MAX` without records
+    next_due: AtomicU64,
+    // inflight records keyed by packet id, see `session::Inflight`
+    session: Tree,
+    // it also serializes state changes of the inflight window
+    window: Mutex<Window>,
+    checkout: Mutex<()>,
+    notify: Arc<Notify>,
+    tally: Arc<Mutex<Tally>>,
+    limits: Limits,
 }
 
 impl Queue {
-    fn new(tree: Tree) -> Self {
-        Self {
+    fn open(
+        db: &Db,
+        name: &str,
+        limits: Limits,
+        marks: &Tree,
+        budget: &Arc<Budget>,
+    ) -> Result<Self, StorageError> {
+        let tree = db.open_tree(name)?;
+        let leases = db.open_tree(leases_tree(name))?;
+        let expiry = db.open_tree(expiry_tree(name))?;
+        let session = db.open_tree(session_tree(name))?;
+        let meta = db.open_tree(meta_tree(name))?;
+
+        migrate_legacy_keys(&tree)?;
+        prune_leases(&tree, &leases)?;
+        record_messages(&tree, &meta)?;
+
+        // offsets are shared by all priorities, so the next one follows the
+        // largest offset among the last keys of every priority group; the
+        // mark is ahead of that unless the tree predates marks
+        let mut offset = match marks.get(name)? {
+            Some(mark) => offset::decode(&mark)?,
+            None => 0,
+        };
+        let mut last = tree.last()?;
+        while let Some((key, _)) = last {
+            let key = Key::try_from(&*key)?;
+            offset = offset.max(key.1.saturating_add(1));
+            last = tree.get_lt(Key::with_priority(key.priority(), 0).to_bytes())?;
+        }
+
+        let mut tally = Tally::new(budget.clone());
+        for item in meta.iter() {
+            let (key, record) = item?;
+            if key == stats::EXPIRED {
+                tally.set_expired(stats::decode_expired(&record)?);
+                continue;
+            }
+
+            let (size, pushed_at) = stats::decode_record(&record)?;
+            tally.push(Key::try_from(&*key)?, size, pushed_at);
+        }
+
+        // records of messages that are gone already are dropped
+        let mut stale = sled::Batch::default();
+        let mut next_due = u64::MAX;
+        for record in expiry.iter().keys() {
+            let record = record?;
+            let (deadline, key) = expiry::decode(&record)?;
+            if tally.expire(key, deadline) {
+                next_due = next_due.min(deadline);
+            } else {
+                stale.remove(record);
+            }
+        }
+        expiry.apply_batch(stale)?;
+
+        let window = Window::load(session.iter().map(|item| {
+            let (packet_id, record) = item?;
+            Ok((
+                session::packet_id(&packet_id)?,
+                Inflight::try_from(&*record)?,
+            ))
+        }))?;
+
+        Ok(Self {
             writes: AtomicUsize::default(),
             tree,
-            offset: AtomicU64::default(),
+            offsets: Offsets::new(offset),
+            marks: marks.clone(),
+            leases,
+            meta,
+            expiry,
+            next_due: AtomicU64::new(next_due),
+            session,
+            window: Mutex::new(window),
+            checkout: Mutex::default(),
+            notify: Arc::default(),
+            tally: Arc::new(Mutex::new(tally)),
+            limits,
+        })
+    }
+
+    fn next_key(&self, name: &str, priority: u16) -> Result<Key, StorageError> {
+        let offset = self.offsets.next(|mark| self.persist(name, mark))?;
+        Ok(Key::with_priority(priority, offset))
+    }
+
+    fn persist(&self, name: &str, mark: u64) -> Result<(), StorageError> {
+        self.marks.insert(name, &mark.to_be_bytes())?;
+        Ok(())
+    }
+
+    // the message is counted before it is written, so that an ack racing
+    // with the push cannot be counted first and leave it in the tally;
+    // messages dropped to make room go in the same tree batch. The expiry
+    // record goes first and the tally learns about the expiry last, so a
+    // purge never sees a deadline before the message is written
+    fn push(
+        &self,
+        name: &str,
+        options: PushOptions,
+        item: Payload,
+    ) -> Result<Pushed, StorageError> {
+        let now = lease::now();
+        let current_key = self.next_key(name, options.priority)?;
+        let incoming = [(current_key, item.len())];
+        let dropped = lock(&self.tally).reserve(name, &self.limits, &incoming, now)?;
+
+        let deadline = options.ttl.map(|ttl| expiry::deadline(now, ttl));
+        if let Some(deadline) = deadline {
+            if let Err(e) = self
+                .expiry
+                .insert(expiry::record(deadline, current_key), &[])
+            {
+                lock(&self.tally).unreserve(&incoming, dropped);
+                return Err(e.into());
+            }
+        }
+
+        let written = self.with_flush(|| self.replace(current_key, &item, now, &dropped));
+        if let Err(e) = written {
+            lock(&self.tally).unreserve(&incoming, dropped);
+            return Err(e);
+        }
+        if let Some(deadline) = deadline {
+            lock(&self.tally).expire(current_key, deadline);
+            self.next_due.fetch_min(deadline, Ordering::SeqCst);
+        }
+
+        self.notify.notify_one();
+
+        let dropped = dropped.into_iter().map(|(key, _)| key).collect();
+        Ok(Pushed::new(current_key, &self.limits, dropped))
+    }
+
+    // counted before the write like a push; the offset moves past the key
+    // first, so that later pushes follow it. Expiry records go first and the
+    // tally learns about the expiry last, like for a push
+    fn restore(
+        &self,
+        name: &str,
+        key: Key,
+        item: Payload,
+        state: MessageState,
+    ) -> Result<(), StorageError> {
+        self.offsets
+            .advance(key.1, |mark| self.persist(name, mark))?;
+        let mut tally = lock(&self.tally);
+        let previous = tally.remove(key);
+        tally.push(key, item.len(), state.pushed_at);
+        drop(tally);
+
+        let written = self.write_restored(key, &item, state, previous);
+        if let Err(e) = written {
+            lock(&self.tally).remove(key);
+            return Err(e);
         }
+        if let Some(deadline) = state.expires_at {
+            lock(&self.tally).expire(key, deadline);
+            self.next_due.fetch_min(deadline, Ordering::SeqCst);
+        }
+        self.notify.notify_one();
+        Ok(())
     }
 
-    fn push(&self, item: Vec<u8>) -> Key {
-        let offset = self.offset.fetch_add(1, Ordering::SeqCst);
-        let current_key = Key::with_offset(offset);
+    // the expiry record of a message replaced by the restored one goes, so
+    // that it is not loaded in place of the new one on open
+    fn write_restored(
+        &self,
+        key: Key,
+        item: &[u8],
+        state: MessageState,
+        previous: Option<Entry>,
+    ) -> Result<(), StorageError> {
+        if let Some(deadline) = previous.and_then(|entry| entry.expires_at) {
+            self.expiry.remove(expiry::record(deadline, key))?;
+        }
+        if let Some(deadline) = state.expires_at {
+            self.expiry.insert(expiry::record(deadline, key), &[])?;
+        }
 
-        self.with_flush(move |tree| {
-            tree.insert(current_key.to_string(), item).unwrap();
-        });
+        let record = stats::record(item.len(), state.pushed_at);
+        (&self.tree, &self.leases, &self.meta)
+            .transaction(|(tree, leases, meta)| {
+                tree.insert(&key.to_bytes()[..], item)?;
+                meta.insert(&key.to_bytes()[..], &record[..])?;
+                match state.lease {
+                    Some(lease) => leases.insert(&key.to_bytes()[..], &lease.to_bytes()[..])?,
+                    None => leases.remove(&key.to_bytes()[..])?,
+                };
+                Ok(())
+            })
+            .map_err(StorageError::from)
+    }
 
-        current_key
+    fn remove(&self, key: Key) -> Result<(), StorageError> {
+        self.remove_many(Some(key))
     }
 
-    fn remove(&self, key: Key) {
-        let key = key.to_string();
-        self.with_flush(|tree| {
-            tree.remove(key).unwrap();
-        });
+    fn remove_many(&self, keys: impl IntoIterator<Item = Key>) -> Result<(), StorageError> {
+        let keys: Vec<_> = keys.into_iter().collect();
+        self.delete(&keys, false)?;
+
+        let mut tally = lock(&self.tally);
+        for key in keys {
+            tally.remove(key);
+        }
+        Ok(())
     }
 
-    fn batch(&self, count: usize) -> VecDeque<(Key, Vec<u8>)> {
-        self.tree
-            .iter()
-            .take(count)
-            .filter_map(|i| i.map_or_else(|_| None, |(k, v)| Some((k.into(), v.to_vec()))))
+    // messages go together with their leases and records, `expired` ones
+    // are counted in the same transaction
+    fn delete(&self, keys: &[Key], expired: bool) -> Result<(), StorageError> {
+        (&self.tree, &self.leases, &self.meta)
+            .transaction(|(tree, leases, meta)| {
+                let mut removed = 0;
+                for key in keys {
+                    if tree.remove(&key.to_bytes()[..])?.is_some() {
+                        removed += 1;
+                    }
+                    leases.remove(&key.to_bytes()[..])?;
+                    meta.remove(&key.to_bytes()[..])?;
+                }
+
+                if expired && removed > 0 {
+                    let count = match meta.get(stats::EXPIRED)? {
+                        Some(count) => stats::decode_expired(&count)
+                            .map_err(ConflictableTransactionError::Abort)?,
+                        None => 0,
+                    };
+                    meta.insert(stats::EXPIRED, &(count + removed).to_be_bytes()[..])?;
+                }
+                Ok(())
+            })
+            .map_err(StorageError::from)
+    }
+
+    // writes a message in place of the ones dropped to make room for it
+    fn replace(
+        &self,
+        key: Key,
+        item: &[u8],
+        pushed_at: u64,
+        dropped: &[(Key, Entry)],
+    ) -> Result<(), StorageError> {
+        let record = stats::record(item.len(), pushed_at);
+        (&self.tree, &self.leases, &self.meta)
+            .transaction(|(tree, leases, meta)| {
+                tree.insert(&key.to_bytes()[..], item)?;
+                meta.insert(&key.to_bytes()[..], &record[..])?;
+                for (key, _) in dropped {
+                    tree.remove(&key.to_bytes()[..])?;
+                    leases.remove(&key.to_bytes()[..])?;
+                    meta.remove(&key.to_bytes()[..])?;
+                }
+                Ok(())
+            })
+            .map_err(StorageError::from)
+    }
+
+    // expired messages go like acknowledged ones; records that came due are
+    // dropped afterwards, including those of messages acked before expiry
+    fn purge(&self, now: u64) -> Result<usize, StorageError> {
+        let expired = lock(&self.tally).due(now);
+        if !expired.is_empty() {
+            self.delete(&expired, true)?;
+            lock(&self.tally).purge(&expired);
+        }
+
+        if self.next_due.load(Ordering::SeqCst) <= now {
+            let mut batch = sled::Batch::default();
+            for record in self
+                .expiry
+                .range(..now.saturating_add(1).to_be_bytes())
+                .keys()
+            {
+                batch.remove(record?);
+            }
+            self.expiry.apply_batch(batch)?;
+
+            let next_due = match self.expiry.first()? {
+                Some((record, _)) => expiry::decode(&record)?.0,
+                None => u64::MAX,
+            };
+            self.next_due.store(next_due, Ordering::SeqCst);
+        }
+
+        Ok(expired.len())
+    }
+
+    // the message is looked up in the transaction that writes the record,
+    // so that it cannot be removed in between
+    fn publish(&self, name: &str, key: Key) -> Result<u16, StorageError> {
+        let mut window = lock(&self.window);
+        let packet_id = window.allocate(name, key, |id| {
+            Ok(self.session.contains_key(id.to_be_bytes())?)
+        })?;
+
+        let queued = key.to_bytes();
+        let inflight = Inflight::new(key, PacketState::Publish).to_bytes();
+        (&self.tree, &self.session)
+            .transaction(|(tree, session)| {
+                if tree.get(&queued[..])?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(
+                        StorageError::NotQueued(key),
+                    ));
+                }
+                session.insert(&packet_id.to_be_bytes()[..], &inflight[..])?;
+                Ok(())
+            })
+            .map_err(StorageError::from)?;
+
+        window.publish(packet_id, key);
+        Ok(packet_id)
+    }
+
+    // the message, its lease and the record change in one transaction
+    fn pubrec(&self, packet_id: u16) -> Result<(), StorageError> {
+        let _window = lock(&self.window);
+        let inflight = self.inflight(packet_id)?;
+        if inflight.state == PacketState::Pubrel {
+            return Ok(());
+        }
+
+        let key = inflight.key.to_bytes();
+        let released = Inflight::new(inflight.key, PacketState::Pubrel).to_bytes();
+        (&self.tree, &self.leases, &self.meta, &self.session)
+            .transaction(|(tree, leases, meta, session)| {
+                tree.remove(&key[..])?;
+                leases.remove(&key[..])?;
+                meta.remove(&key[..])?;
+                session.insert(&packet_id.to_be_bytes()[..], &released[..])?;
+                Ok(())
+            })
+            .map_err(StorageError::from)?;
+
+        lock(&self.tally).remove(inflight.key);
+        Ok(())
+    }
+
+    fn complete(&self, packet_id: u16) -> Result<(), StorageError> {
+        let mut window = lock(&self.window);
+        let inflight = self.inflight(packet_id)?;
+
+        let key = inflight.key.to_bytes();
+        (&self.tree, &self.leases, &self.meta, &self.session)
+            .transaction(|(tree, leases, meta, session)| {
+                tree.remove(&key[..])?;
+                leases.remove(&key[..])?;
+                meta.remove(&key[..])?;
+                session.remove(&packet_id.to_be_bytes()[..])?;
+                Ok(())
+            })
+            .map_err(StorageError::from)?;
+
+        window.complete(inflight.key);
+        lock(&self.tally).remove(inflight.key);
+        Ok(())
+    }
+
+    fn restore_inflight(&self, packet_id: u16, inflight: Inflight) -> Result<(), StorageError> {
+        let mut window = lock(&self.window);
+        self.session
+            .insert(packet_id.to_be_bytes(), &inflight.to_bytes()[..])?;
+        window.restore(packet_id, inflight.key);
+        Ok(())
+    }
+
+    fn inflight(&self, packet_id: u16) -> Result<Inflight, StorageError> {
+        match self.session.get(packet_id.to_be_bytes())? {
+            Some(record) => Inflight::try_from(&*record),
+            None => Err(StorageError::UnknownPacketId(packet_id)),
+        }
+    }
+
+    // checkouts are serialized, so that two consumers never lease the same
+    // message; messages out of deliveries are returned instead of a batch
+    // for the dead letter queue
+    fn checkout(&self, count: usize, duration: Duration) -> Result<Checkout, StorageError> {
+        let _guard = self.checkout.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = lease::now();
+        let mut batch = VecDeque::new();
+        let mut exhausted = Vec::new();
+        let mut leases = sled::Batch::default();
+
+        for item in self.tree.iter() {
+            if batch.len() >= count {
+                break;
+            }
+
+            let (key, value) = item?;
+            let previous = match self.leases.get(&key)? {
+                Some(lease) => Some(Lease::try_from(&*lease)?),
+                None => None,
+            };
+            if matches!(previous, Some(lease) if lease.is_active(now)) {
+                continue;
+            }
+            if self.limits.is_exhausted(previous) {
+                exhausted.push(Key::try_from(&*key)?);
+                continue;
+            }
+
+            let lease = Lease::renew(previous, now, duration);
+            leases.insert(&*key, &lease.to_bytes()[..]);
+            batch.push_back((Key::try_from(&*key)?, Payload::copy_from_slice(&value)));
+        }
+        if !exhausted.is_empty() {
+            return Ok((VecDeque::new(), exhausted));
+        }
+
+        self.leases.apply_batch(leases)?;
+        Ok((batch, exhausted))
+    }
+
+    // serialized with checkouts, which update the same lease record
+    fn mark_delivered(&self, key: Key) -> Result<(), StorageError> {
+        let _guard = self.checkout.lock().unwrap_or_else(PoisonError::into_inner);
+        if !self.tree.contains_key(key.to_bytes())? {
+            return Ok(());
+        }
+
+        let previous = match self.leases.get(key.to_bytes())? {
+            Some(lease) => Some(Lease::try_from(&*lease)?),
+            None => None,
+        };
+        self.leases
+            .insert(key.to_bytes(), &Lease::delivered(previous).to_bytes()[..])?;
+        Ok(())
+    }
+
+    fn batch(&self, count: usize) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        read(self.tree.iter(), count)
+    }
+
+    // serialized with checkouts and marks like `batch_with_delivery`
+    fn peek(
+        &self,
+        after: Option<Key>,
+        count: usize,
+    ) -> Result<VecDeque<(Key, Payload, MessageState)>, StorageError> {
+        let _guard = self.checkout.lock().unwrap_or_else(PoisonError::into_inner);
+        let page = match after {
+            Some(after) => self.batch_after(after, count)?,
+            None => self.batch(count)?,
+        };
+        page.into_iter()
+            .map(|(key, payload)| {
+                let lease = match self.leases.get(key.to_bytes())? {
+                    Some(lease) => Some(Lease::try_from(&*lease)?),
+                    None => None,
+                };
+                let state = MessageState::new(lock(&self.tally).get(key), lease);
+                Ok((key, payload, state))
+            })
             .collect()
     }
 
-    fn with_flush(&self, f: impl FnOnce(&Tree)) {
-        f(&self.tree);
+    // serialized with checkouts and marks, the only writers of leases
+    fn batch_with_delivery(&self, count: usize) -> Result<VecDeque<Delivered>, StorageError> {
+        let _guard = self.checkout.lock().unwrap_or_else(PoisonError::into_inner);
+        self.batch(count)?
+            .into_iter()
+            .map(|(key, payload)| {
+                let lease = match self.leases.get(key.to_bytes())? {
+                    Some(lease) => Some(Lease::try_from(&*lease)?),
+                    None => None,
+                };
+                Ok((key, payload, Delivery::of(lease)))
+            })
+            .collect()
+    }
+
+    fn batch_after(
+        &self,
+        after: Key,
+        count: usize,
+    ) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+        let start = Bound::Excluded(after.to_bytes());
+        read(self.tree.range((start, Bound::Unbounded)), count)
+    }
+
+    fn with_flush(&self, f: impl FnOnce() -> Result<(), StorageError>) -> Result<(), StorageError> {
+        f()?;
 
         // if self.writes.fetch_add(1, Ordering::SeqCst) > 1 {
-        //     self.tree.flush().unwrap();
+        //     self.tree.flush()?;
         //     self.writes.store(0, Ordering::SeqCst);
         // }
+
+        Ok(())
+    }
+}
+
+// what a `push_many` writes into one queue
+struct Group<'a> {
+    name: &'a str,
+    tree: Tree,
+    leases: Tree,
+    meta: Tree,
+    notify: Arc<Notify>,
+    tally: Arc<Mutex<Tally>>,
+    limits: Limits,
+    incoming: Vec<(Key, usize)>,
+    dropped: Vec<(Key, Entry)>,
+}
+
+impl<'a> Group<'a> {
+    fn new(name: &'a str, queue: &Queue) -> Self {
+        Self {
+            name,
+            tree: queue.tree.clone(),
+            leases: queue.leases.clone(),
+            meta: queue.meta.clone(),
+            notify: queue.notify.clone(),
+            tally: queue.tally.clone(),
+            limits: queue.limits,
+            incoming: Vec::new(),
+            dropped: Vec::new(),
+        }
+    }
+
+    fn unreserve(&mut self) {
+        lock(&self.tally).unreserve(&self.incoming, std::mem::take(&mut self.dropped));
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// `IVec` cannot hand its buffer over, so reads copy each value once
+fn read(iter: sled::Iter, count: usize) -> Result<VecDeque<(Key, Payload)>, StorageError> {
+    iter.take(count)
+        .map(|i| {
+            let (k, v) = i?;
+            Ok((Key::try_from(&*k)?, Payload::copy_from_slice(&v)))
+        })
+        .collect()
+}
+
+fn leases_tree(name: &str) -> String {
+    format!("{}leases.{}", SYSTEM_PREFIX, name)
+}
+
+fn expiry_tree(name: &str) -> String {
+    format!("{}expiry.{}", SYSTEM_PREFIX, name)
+}
+
+fn session_tree(name: &str) -> String {
+    format!("{}session.{}", SYSTEM_PREFIX, name)
+}
+
+fn meta_tree(name: &str) -> String {
+    format!("{}meta.{}", SYSTEM_PREFIX, name)
+}
+
+// queues written before message records existed get theirs from one scan of
+// the payloads, as pushed at that moment; the expired counter marks a queue
+// whose records are complete
+fn record_messages(tree: &Tree, meta: &Tree) -> Result<(), StorageError> {
+    if meta.contains_key(stats::EXPIRED)? {
+        return Ok(());
+    }
+
+    let now = lease::now();
+    let mut batch = sled::Batch::default();
+    for item in tree.iter() {
+        let (key, value) = item?;
+        batch.insert(key, &stats::record(value.len(), now)[..]);
+    }
+    batch.insert(stats::EXPIRED, &0u64.to_be_bytes()[..]);
+
+    meta.apply_batch(batch)?;
+    Ok(())
+}
+
+// drops lease records of messages that are gone already
+fn prune_leases(tree: &Tree, leases: &Tree) -> Result<(), StorageError> {
+    let mut batch = sled::Batch::default();
+    for key in leases.iter().keys() {
+        let key = key?;
+        if !tree.contains_key(&key)? {
+            batch.remove(key);
+        }
+    }
+
+    leases.apply_batch(batch)?;
+    Ok(())
+}
+
+// trees written before keys were binary encoded store them as strings, they
+// are rewritten in one atomic batch the first time such a tree is opened
+fn migrate_legacy_keys(tree: &Tree) -> Result<(), StorageError> {
+    match tree.first()? {
+        Some((key, _)) if key.len() != Key::ENCODED_LEN => {}
+        _ => return Ok(()),
+    }
+
+    let mut batch = sled::Batch::default();
+    for item in tree.iter() {
+        let (key, value) = item?;
+        if key.len() != Key::ENCODED_LEN {
+            batch.insert(&Key::from_legacy(&key)?.to_bytes()[..], value);
+            batch.remove(key);
+        }
+    }
+
+    tree.apply_batch(batch)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Overflow, Properties, StoredMessage};
+
+    use super::*;
+
+    // sled releases its file lock only once its background writers are
+    // done, so the database is handed over instead of being opened again
+    fn reopen(storage: Sled) -> Sled {
+        let Sled { _path, db, .. } = storage;
+        Sled::with_db(_path, db).unwrap()
+    }
+
+    #[test]
+    fn it_creates_and_drops_queues() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 0).unwrap();
+
+        storage.create_queue("session").unwrap();
+        let key = storage.push("session", Payload::from_static(b"1")).unwrap();
+
+        assert!(storage.contains("session"));
+        assert_eq!(storage.names(), vec!["session".to_string()]);
+        assert_eq!(storage.batch("session", 10).unwrap()[0].0, key);
+
+        storage.drop_queue("session").unwrap();
+
+        assert!(!storage.contains("session"));
+        assert!(!storage
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name == "session".as_bytes()));
+        assert!(matches!(
+            storage.push("session", Payload::from_static(b"1")),
+            Err(StorageError::UnknownQueue(_))
+        ));
+    }
+
+    #[test]
+    fn it_recovers_queues_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 2).unwrap();
+        storage.push("q0", Payload::from_static(b"1")).unwrap();
+        let last = storage.push("q0", Payload::from_static(b"2")).unwrap();
+        let storage = reopen(storage);
+        let mut names = storage.names();
+        names.sort();
+        assert_eq!(names, vec!["q0".to_string(), "q1".to_string()]);
+
+        let next = storage.push("q0", Payload::from_static(b"3")).unwrap();
+        // offsets reserved before the restart are skipped
+        assert!(next > last);
+        assert_eq!(storage.batch("q0", 10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn it_keeps_offsets_of_drained_queues() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        let last = storage.push("q0", Payload::from_static(b"1")).unwrap();
+        storage.remove("q0", last).unwrap();
+        let storage = reopen(storage);
+        let next = storage
+            .push_with_priority("q0", 2, Payload::from_static(b"2"))
+            .unwrap();
+        assert!(next.1 > last.1);
+    }
+
+    #[test]
+    fn it_delivers_higher_priority_first() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        storage.push("q0", Payload::from_static(b"1")).unwrap();
+        storage
+            .push_with_priority("q0", 7, Payload::from_static(b"2"))
+            .unwrap();
+        storage
+            .push_with_priority("q0", 3, Payload::from_static(b"3"))
+            .unwrap();
+        storage
+            .push_with_priority("q0", 7, Payload::from_static(b"4"))
+            .unwrap();
+        let last = storage.push("q0", Payload::from_static(b"5")).unwrap();
+
+        let payloads = |storage: &Sled, size| -> Vec<Vec<u8>> {
+            let batch = storage.batch("q0", size).unwrap();
+            batch
+                .into_iter()
+                .map(|(_, payload)| payload.to_vec())
+                .collect()
+        };
+        assert_eq!(payloads(&storage, 10), vec![b"2", b"4", b"3", b"1", b"5"]);
+        assert_eq!(payloads(&storage, 3), vec![b"2", b"4", b"3"]);
+        let storage = reopen(storage);
+        let next = storage
+            .push_with_priority("q0", 3, Payload::from_static(b"6"))
+            .unwrap();
+        assert_eq!(next.priority(), 3);
+        assert!(next.1 > last.1);
+    }
+
+    #[test]
+    fn it_migrates_legacy_string_keys() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("sled");
+
+        let db = sled::open(&path).unwrap();
+        let tree = db.open_tree("q0").unwrap();
+        tree.insert("0000_000000000007", b"1".to_vec()).unwrap();
+        tree.insert("0000_000000000008", b"2".to_vec()).unwrap();
+        tree.insert("0004_000000000009", b"3".to_vec()).unwrap();
+
+        let storage = Sled::with_db(Box::new(path), db).unwrap();
+        let batch = storage.batch("q0", 10).unwrap();
+        assert_eq!(
+            batch,
+            vec![
+                (Key::with_priority(4, 9), Payload::from_static(b"3")),
+                (Key::with_priority(0, 7), Payload::from_static(b"1")),
+                (Key::with_priority(0, 8), Payload::from_static(b"2")),
+            ]
+        );
+        assert_eq!(
+            storage.push("q0", Payload::from_static(b"4")).unwrap(),
+            Key::with_offset(10)
+        );
+    }
+
+    #[test]
+    fn it_rejects_reserved_queue_names() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 0).unwrap();
+
+        assert!(matches!(
+            storage.create_queue("__limits"),
+            Err(StorageError::InvalidQueueName(_))
+        ));
+        storage.create_queue("_q").unwrap();
+
+        let storage = reopen(storage);
+        assert_eq!(storage.names(), vec!["_q".to_string()]);
+    }
+
+    #[test]
+    fn it_pushes_many_with_priorities() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 2).unwrap();
+
+        let keys = storage
+            .push_many_with_priority(vec![
+                ("q0", 0, Payload::from_static(b"1")),
+                ("q0", 2, Payload::from_static(b"2")),
+                ("q1", 1, Payload::from_static(b"3")),
+            ])
+            .unwrap();
+        assert_eq!(keys[1].priority(), 2);
+        assert_eq!(keys[2].priority(), 1);
+
+        let batch = storage.batch("q0", 10).unwrap();
+        assert_eq!(
+            batch,
+            vec![
+                (keys[1], Payload::from_static(b"2")),
+                (keys[0], Payload::from_static(b"1"))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_pushes_many_atomically() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 2).unwrap();
+
+        let keys = storage
+            .push_many(vec![
+                ("q0", Payload::from_static(b"1")),
+                ("q0", Payload::from_static(b"2")),
+            ])
+            .unwrap();
+        assert_eq!(keys, vec![Key::with_offset(0), Key::with_offset(1)]);
+
+        storage
+            .push_many(vec![
+                ("q0", Payload::from_static(b"3")),
+                ("q1", Payload::from_static(b"3")),
+            ])
+            .unwrap();
+        assert!(matches!(
+            storage.push_many(vec![("q1", Payload::from_static(b"4")), ("q2", Payload::from_static(b"4"))]),
+            Err(StorageError::UnknownQueue(name)) if name == "q2"
+        ));
+
+        assert_eq!(storage.batch("q0", 10).unwrap().len(), 3);
+        assert_eq!(
+            storage.batch("q1", 10).unwrap(),
+            vec![(Key::with_offset(0), Payload::from_static(b"3"))]
+        );
+    }
+
+    #[test]
+    fn it_removes_acknowledged_ranges() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+
+        let keys: Vec<_> = (0..6)
+            .map(|i| {
+                storage
+                    .push_with_priority("q0", i % 2, Payload::from(vec![i as u8]))
+                    .unwrap()
+            })
+            .collect();
+
+        storage.remove_many("q0", &[keys[1], keys[4]]).unwrap();
+        // delivery order is 1, 3, 5, 0, 2, 4
+        storage.remove_up_to("q0", keys[0]).unwrap();
+
+        let batch = storage.batch("q0", 10).unwrap();
+        assert_eq!(batch, vec![(keys[2], Payload::from(vec![2]))]);
+    }
+
+    #[test]
+    fn it_keeps_leases_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        let keys: Vec<_> = (0..3)
+            .map(|i| storage.push("q0", Payload::from(vec![i])).unwrap())
+            .collect();
+
+        let leased = storage.checkout("q0", 2, Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            leased,
+            vec![
+                (keys[0], Payload::from(vec![0])),
+                (keys[1], Payload::from(vec![1]))
+            ]
+        );
+        storage.remove("q0", keys[0]).unwrap();
+        // an expired lease is handed out again right away
+        storage.checkout("q0", 1, Duration::from_secs(0)).unwrap();
+        let storage = reopen(storage);
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+        assert_eq!(
+            storage.checkout("q0", 10, Duration::from_secs(60)).unwrap(),
+            vec![(keys[2], Payload::from(vec![2]))]
+        );
+        assert!(storage
+            .checkout("q0", 10, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_reads_batches_after_key() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        crate::tests::it_reads_batches_after_key(&storage);
+    }
+
+    #[test]
+    fn it_leases_nothing_when_dead_lettering_fails() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 0).unwrap();
+        crate::tests::it_leases_nothing_when_dead_lettering_fails(&storage);
+    }
+
+    #[test]
+    fn it_counts_queued_messages_across_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 2).unwrap();
+        let keys = storage
+            .push_many(vec![
+                ("q0", Payload::from_static(b"a")),
+                ("q1", Payload::from_static(b"bb")),
+                ("q0", Payload::from_static(b"ccc")),
+            ])
+            .unwrap();
+        let last = storage
+            .push_with_priority("q0", 1, Payload::from_static(b"dddd"))
+            .unwrap();
+        storage.remove("q0", keys[0]).unwrap();
+
+        let stats = storage.stats("q0").unwrap();
+        assert_eq!((stats.len, stats.size_bytes), (2, 7));
+        assert_eq!(stats.oldest, Some(keys[2]));
+        assert_eq!(stats.newest, Some(last));
+        let storage = reopen(storage);
+        assert_eq!(storage.len("q0").unwrap(), 2);
+        assert_eq!(storage.size_bytes("q1").unwrap(), 2);
+
+        storage.remove_up_to("q0", keys[2]).unwrap();
+        assert_eq!(storage.len("q0").unwrap(), 0);
+        assert_eq!(storage.oldest_key("q0").unwrap(), None);
+        assert_eq!(storage.oldest_age("q0").unwrap(), None);
+    }
+
+    #[test]
+    fn it_keeps_push_times_and_expired_counts_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_millis(1))
+            .unwrap();
+        let key = storage.push("q0", Payload::from_static(b"bb")).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(storage.purge_expired("q0").unwrap(), 1);
+
+        let storage = reopen(storage);
+        let stats = storage.stats("q0").unwrap();
+        assert_eq!((stats.len, stats.size_bytes, stats.expired), (1, 2, 1));
+        assert_eq!(stats.oldest, Some(key));
+        assert!(stats.oldest_age.unwrap() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn it_keeps_limits_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("sled");
+
+        let limits = Limits {
+            max_len: None,
+            max_bytes: Some(4),
+            overflow: Overflow::DropOldest,
+            max_deliveries: Some(5),
+        };
+        let storage = Sled::new(path.clone(), "q", 1).unwrap();
+        storage.create_queue_with("limited", limits).unwrap();
+        let keys = storage
+            .push_many(vec![
+                ("limited", Payload::from_static(b"ab")),
+                ("q0", Payload::from_static(b"abcdef")),
+                ("limited", Payload::from_static(b"cd")),
+            ])
+            .unwrap();
+        let storage = reopen(storage);
+        assert_eq!(storage.limits("limited").unwrap(), limits);
+        assert_eq!(storage.limits("q0").unwrap(), Limits::default());
+
+        let pushed = storage
+            .push_with(
+                "limited",
+                Payload::from_static(b"e"),
+                PushOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(pushed.dropped, vec![keys[0]]);
+        assert_eq!(
+            storage.batch("limited", 10).unwrap(),
+            vec![
+                (keys[2], Payload::from_static(b"cd")),
+                (pushed.key, Payload::from_static(b"e"))
+            ]
+        );
+
+        // dropping to make room for the batch happens in the same write
+        storage
+            .push_many(vec![
+                ("limited", Payload::from_static(b"fgh")),
+                ("q0", Payload::from_static(b"i")),
+            ])
+            .unwrap();
+        assert_eq!(storage.size_bytes("limited").unwrap(), 4);
+        assert_eq!(storage.len("limited").unwrap(), 2);
+    }
+
+    #[test]
+    fn it_keeps_expiry_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        storage
+            .push_with_expiry("q0", Payload::from_static(b"a"), Duration::from_secs(0))
+            .unwrap();
+        let kept = storage
+            .push_with_expiry("q0", Payload::from_static(b"b"), Duration::from_secs(3600))
+            .unwrap();
+        let plain = storage.push("q0", Payload::from_static(b"c")).unwrap();
+        let storage = reopen(storage);
+        assert_eq!(storage.len("q0").unwrap(), 3);
+
+        let keys: Vec<_> = storage
+            .batch("q0", 10)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![kept, plain]);
+        assert_eq!(storage.expired("q0").unwrap(), 1);
+        assert_eq!(storage.len("q0").unwrap(), 2);
+    }
+
+    #[test]
+    fn it_keeps_delivery_counts_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        let keys = storage
+            .push_many(vec![
+                ("q0", Payload::from_static(b"a")),
+                ("q0", Payload::from_static(b"b")),
+            ])
+            .unwrap();
+        storage.mark_delivered("q0", keys[0]).unwrap();
+        storage.mark_delivered("q0", keys[0]).unwrap();
+        storage.remove("q0", keys[1]).unwrap();
+        storage.mark_delivered("q0", keys[1]).unwrap();
+        let storage = reopen(storage);
+        let batch = storage.batch_with_delivery("q0", 10).unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!((batch[0].0, batch[0].2.count), (keys[0], 2));
+        assert_eq!(storage.queue("q0").unwrap().leases.len(), 1);
+
+        // counting a delivery takes no lease
+        assert_eq!(
+            storage
+                .checkout("q0", 1, Duration::from_secs(60))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(storage.delivery("q0", keys[0]).unwrap().count, 3);
+    }
+
+    #[test]
+    fn it_moves_messages_between_queues() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 2).unwrap();
+        let options = PushOptions {
+            priority: 3,
+            ttl: Some(Duration::from_secs(3600)),
+        };
+        let key = storage
+            .push_with("q0", Payload::from_static(b"a"), options)
+            .unwrap()
+            .key;
+        storage.checkout("q0", 1, Duration::from_secs(60)).unwrap();
+
+        let moved = storage.move_to("q0", key, "q1").unwrap().unwrap();
+        assert_eq!(moved.priority(), 3);
+        assert_eq!(storage.move_to("q0", key, "q1").unwrap(), None);
+
+        // the lease went with the message and the expiry came along
+        let storage = reopen(storage);
+        assert!(storage.batch("q0", 10).unwrap().is_empty());
+        assert!(storage.queue("q0").unwrap().leases.is_empty());
+        assert_eq!(
+            storage.batch("q1", 10).unwrap(),
+            vec![(moved, Payload::from_static(b"a"))]
+        );
+        let queue = storage.queue("q1").unwrap();
+        assert_eq!(queue.expiry.len(), 1);
+        assert!(lock(&queue.tally).get(moved).unwrap().expires_at.is_some());
+    }
+
+    #[test]
+    fn it_keeps_stored_messages_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("sled");
+
+        let message = StoredMessage {
+            topic: "devices/1/battery".into(),
+            qos: QoS::AtLeastOnce,
+            properties: Properties {
+                message_expiry_interval: Some(3600),
+                user_properties: vec![("unit".into(), "percent".into())],
+                ..Properties::default()
+            },
+            received_at: lease::now(),
+            payload: Payload::from_static(b"87"),
+            ..StoredMessage::default()
+        };
+        let storage = Sled::new(path.clone(), "q", 1).unwrap();
+        let pushed = storage
+            .push_message("q0", &message, PushOptions::default())
+            .unwrap();
+        let storage = reopen(storage);
+        assert_eq!(
+            storage.batch_messages("q0", 10).unwrap(),
+            vec![(pushed.key, message)]
+        );
+        // the expiry interval was stored as ttl
+        assert_eq!(storage.queue("q0").unwrap().expiry.len(), 1);
+    }
+
+    #[test]
+    fn it_keeps_retained_messages_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        for topic in &[
+            "devices/1",
+            "devices/1/battery",
+            "devices/2/battery",
+            "devices/1/fw",
+            "$SYS/load",
+        ] {
+            let message = StoredMessage {
+                topic: topic.to_string(),
+                retain: true,
+                received_at: lease::now(),
+                payload: Payload::from_static(b"1"),
+                ..StoredMessage::default()
+            };
+            storage.set_retained(&message).unwrap();
+        }
+        // an empty payload clears the topic
+        storage
+            .set_retained(&StoredMessage {
+                topic: "devices/1/fw".into(),
+                ..StoredMessage::default()
+            })
+            .unwrap();
+        let storage = reopen(storage);
+        assert!(storage.retained("devices/1/fw").unwrap().is_none());
+        assert_eq!(storage.names(), vec!["q0".to_string()]);
+
+        let topics = |filter| {
+            let mut topics: Vec<_> = storage
+                .matching(filter)
+                .unwrap()
+                .into_iter()
+                .map(|message| message.topic)
+                .collect();
+            topics.sort();
+            topics
+        };
+        assert_eq!(
+            topics("devices/+/battery"),
+            ["devices/1/battery", "devices/2/battery"]
+        );
+        assert_eq!(topics("devices/1/#"), ["devices/1", "devices/1/battery"]);
+        assert_eq!(topics("#").len(), 3);
+        assert!(matches!(
+            storage.matching("devices/#/battery"),
+            Err(StorageError::InvalidTopic(_))
+        ));
+        assert!(matches!(
+            storage.set_retained(&StoredMessage {
+                topic: "devices/+/battery".into(),
+                payload: Payload::from_static(b"1"),
+                ..StoredMessage::default()
+            }),
+            Err(StorageError::InvalidTopic(_))
+        ));
+    }
+
+    #[test]
+    fn it_recovers_inflight_window_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let storage = Sled::new(path.as_ref().join("sled"), "q", 1).unwrap();
+        let keys: Vec<_> = (0..3u8)
+            .map(|i| storage.push("q0", Payload::from(vec![i])).unwrap())
+            .collect();
+        let ids: Vec<_> = keys
+            .iter()
+            .map(|key| storage.publish("q0", *key).unwrap())
+            .collect();
+        assert_eq!(ids, [1, 2, 3]);
+
+        storage.pubrec("q0", ids[0]).unwrap();
+        storage.pubrec("q0", ids[0]).unwrap();
+        storage.complete("q0", ids[1]).unwrap();
+        assert!(matches!(
+            storage.complete("q0", ids[1]),
+            Err(StorageError::UnknownPacketId(2))
+        ));
+        let storage = reopen(storage);
+        assert_eq!(
+            storage.inflight("q0").unwrap(),
+            [
+                (1, Inflight::new(keys[0], PacketState::Pubrel)),
+                (3, Inflight::new(keys[2], PacketState::Publish)),
+            ]
+        );
+        // released and completed messages left the queue with their state
+        assert_eq!(storage.len("q0").unwrap(), 1);
+        assert_eq!(storage.batch("q0", 10).unwrap()[0].0, keys[2]);
+        assert!(matches!(
+            storage.publish("q0", keys[2]),
+            Err(StorageError::AlreadyInflight(3))
+        ));
+        assert!(matches!(
+            storage.publish("q0", keys[1]),
+            Err(StorageError::NotQueued(key)) if key == keys[1]
+        ));
+        let key = storage.push("q0", Payload::from_static(b"4")).unwrap();
+        assert_eq!(storage.publish("q0", key).unwrap(), 4);
+    }
+
+    #[test]
+    fn it_restores_subscriptions_after_reopen() {
+        let path = tempfile::TempDir::new().unwrap();
+        let path = path.as_ref().join("sled");
+
+        let options = |qos| SubscriptionOptions {
+            qos,
+            ..SubscriptionOptions::default()
+        };
+        let storage = Sled::new(path.clone(), "q", 0).unwrap();
+        storage
+            .add_subscription("a", "devices/+/battery", options(QoS::AtLeastOnce))
+            .unwrap();
+        storage
+            .add_subscription("a", "devices/7/#", options(QoS::AtMostOnce))
+            .unwrap();
+        storage
+            .add_subscription("ab", "devices/#", options(QoS::ExactlyOnce))
+            .unwrap();
+        storage
+            .add_subscription("b", "devices/#", options(QoS::AtMostOnce))
+            .unwrap();
+        storage.remove_subscription("a", "devices/7/#").unwrap();
+        storage.remove_client("b").unwrap();
+        assert!(storage
+            .add_subscription("c", "devices/#/battery", options(QoS::AtMostOnce))
+            .is_err());
+        let storage = reopen(storage);
+        assert_eq!(
+            storage.route("devices/7/battery"),
+            [
+                ("a".to_string(), QoS::AtLeastOnce),
+                ("ab".to_string(), QoS::ExactlyOnce)
+            ]
+        );
+        assert_eq!(
+            storage.subscriptions("a"),
+            [("devices/+/battery".to_string(), options(QoS::AtLeastOnce))]
+        );
+        assert!(storage.subscriptions("b").is_empty());
+        assert!(storage.names().is_empty());
     }
 }
@@ -0,0 +1,500 @@
+use std::{
+    collections::{btree_map::Range, BTreeMap, BTreeSet},
+    convert::TryInto,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{Budget, Key, Limits, Overflow, StorageError};
+
+// depth of a queue as seen by `Storage::stats`
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct QueueStats {
+    pub len: usize,
+    pub size_bytes: u64,
+    // first and last message still queued in the order they were pushed
+    pub oldest: Option<Key>,
+    pub newest: Option<Key>,
+    pub oldest_age: Option<Duration>,
+    // messages purged because their expiry passed, since the queue was
+    // created
+    pub expired: u64,
+}
+
+// per queue index of message sizes and push times, kept up to date by every
+// write so that stats never have to scan the stored messages. Persistent
+// backends keep a `record` of every message and the expired counter next to
+// it, written together with the message, and load the tally from those on
+// open instead of reading payloads. Every byte counted is charged to the
+// storage budget until it is removed or the tally dropped
+#[derive(Debug, Default)]
+pub(crate) struct Tally {
+    messages: BTreeMap<Key, Entry>,
+    size_bytes: u64,
+    budget: Arc<Budget>,
+    // messages with an expiry by deadline, the due ones come first
+    deadlines: BTreeSet<(u64, Key)>,
+    expired: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Entry {
+    pub(crate) size: usize,
+    pub(crate) pushed_at: u64,
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl Tally {
+    pub(crate) fn new(budget: Arc<Budget>) -> Self {
+        Self {
+            messages: BTreeMap::new(),
+            size_bytes: 0,
+            budget,
+            deadlines: BTreeSet::new(),
+            expired: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, key: Key, size: usize, pushed_at: u64) {
+        let entry = Entry {
+            size,
+            pushed_at,
+            expires_at: None,
+        };
+        self.insert(key, entry);
+    }
+
+    // returns whether the message is counted at all
+    pub(crate) fn expire(&mut self, key: Key, deadline: u64) -> bool {
+        let entry = match self.messages.get_mut(&key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if let Some(previous) = entry.expires_at.replace(deadline) {
+            self.deadlines.remove(&(previous, key));
+        }
+        self.deadlines.insert((deadline, key));
+        true
+    }
+
+    // the count persisted by the backend, see `QueueStats::expired`
+    pub(crate) fn set_expired(&mut self, expired: u64) {
+        self.expired = expired;
+    }
+
+    pub(crate) fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    // deadlines of the messages with an expiry, due ones first
+    pub(crate) fn deadlines(&self) -> &BTreeSet<(u64, Key)> {
+        &self.deadlines
+    }
+
+    pub(crate) fn get(&self, key: Key) -> Option<&Entry> {
+        self.messages.get(&key)
+    }
+
+    pub(crate) fn remove(&mut self, key: Key) -> Option<Entry> {
+        let entry = self.messages.remove(&key)?;
+        self.forget(key, &entry);
+        Some(entry)
+    }
+
+    pub(crate) fn remove_up_to(&mut self, key: Key) {
+        let mut rest = self.messages.split_off(&key);
+        let last = rest.remove_entry(&key);
+        let removed = std::mem::replace(&mut self.messages, rest);
+        let removed = removed.into_iter().chain(last);
+        for (key, entry) in removed {
+            self.forget(key, &entry);
+        }
+    }
+
+    // messages whose deadline is at or before `now`
+    pub(crate) fn due(&self, now: u64) -> Vec<Key> {
+        self.deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .map(|(_, key)| *key)
+            .collect()
+    }
+
+    // removes purged messages and counts them as expired
+    pub(crate) fn purge(&mut self, keys: &[Key]) {
+        for key in keys {
+            if self.remove(*key).is_some() {
+                self.expired += 1;
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Key, entry: Entry) {
+        if let Some(previous) = self.messages.insert(key, entry) {
+            self.forget(key, &previous);
+        }
+        if let Some(deadline) = entry.expires_at {
+            self.deadlines.insert((deadline, key));
+        }
+        self.size_bytes += entry.size as u64;
+        self.budget.charge(entry.size as u64);
+    }
+
+    fn forget(&mut self, key: Key, entry: &Entry) {
+        if let Some(deadline) = entry.expires_at {
+            self.deadlines.remove(&(deadline, key));
+        }
+        self.size_bytes -= entry.size as u64;
+        self.budget.release(entry.size as u64);
+    }
+
+    // offsets grow with every push within a priority, so the oldest and
+    // newest messages are among the first and last keys of each priority
+    pub(crate) fn stats(&self, now: u64) -> QueueStats {
+        let mut oldest: Option<(&Key, &Entry)> = None;
+        let mut newest: Option<(&Key, &Entry)> = None;
+
+        for mut group in self.groups() {
+            let head = group.next();
+            let tail = group.next_back().or(head);
+            if let (Some(head), Some(tail)) = (head, tail) {
+                if oldest.map_or(true, |oldest| order(head) < order(oldest)) {
+                    oldest = Some(head);
+                }
+                if newest.map_or(true, |newest| order(tail) > order(newest)) {
+                    newest = Some(tail);
+                }
+            }
+        }
+
+        QueueStats {
+            len: self.messages.len(),
+            size_bytes: self.size_bytes,
+            oldest: oldest.map(|(key, _)| *key),
+            newest: newest.map(|(key, _)| *key),
+            oldest_age: oldest
+                .map(|(_, entry)| Duration::from_millis(now.saturating_sub(entry.pushed_at))),
+            expired: self.expired,
+        }
+    }
+
+    // messages to drop so that `incoming` messages of the given priorities
+    // and sizes fit into `limits` of queue `name`, fails with `QueueFull` if
+    // the push has to be rejected and with `Full` if what is left after the
+    // drops does not fit into the storage budget
+    pub(crate) fn make_room(
+        &self,
+        name: &str,
+        limits: &Limits,
+        incoming: &[(u16, usize)],
+    ) -> Result<Vec<Key>, StorageError> {
+        let added: u64 = incoming.iter().map(|(_, size)| *size as u64).sum();
+        let dropped = self
+            .victims(limits, incoming, added)
+            .ok_or_else(|| StorageError::QueueFull(name.into()))?;
+
+        let freed: u64 = dropped
+            .iter()
+            .filter_map(|key| self.messages.get(key))
+            .map(|entry| entry.size as u64)
+            .sum();
+        if !self.budget.admits(added.saturating_sub(freed)) {
+            return Err(StorageError::Full);
+        }
+
+        Ok(dropped)
+    }
+
+    fn victims(&self, limits: &Limits, incoming: &[(u16, usize)], added: u64) -> Option<Vec<Key>> {
+        let mut len = self.messages.len() + incoming.len();
+        let mut bytes = self.size_bytes + added;
+
+        if limits.fits(len, bytes) {
+            return Some(Vec::new());
+        }
+        if !limits.fits(incoming.len(), added) {
+            return None;
+        }
+
+        let victims: Box<dyn Iterator<Item = (&Key, &Entry)>> = match limits.overflow {
+            Overflow::RejectNew => return None,
+            Overflow::DropOldest => Box::new(self.oldest_first()),
+            Overflow::DropLowestPriority => {
+                let lowest = incoming.iter().map(|(priority, _)| *priority).min();
+                let groups = self.groups().into_iter().rev().flatten();
+                Box::new(groups.take_while(move |(key, _)| Some(key.priority()) <= lowest))
+            }
+        };
+
+        let mut dropped = Vec::new();
+        for (key, entry) in victims {
+            dropped.push(*key);
+            len -= 1;
+            bytes -= entry.size as u64;
+            if limits.fits(len, bytes) {
+                return Some(dropped);
+            }
+        }
+
+        None
+    }
+
+    // makes room for `incoming` and counts it right away, for backends that
+    // write outside of the tally lock; the dropped entries are handed back
+    // to undo the reservation if the write fails
+    pub(crate) fn reserve(
+        &mut self,
+        name: &str,
+        limits: &Limits,
+        incoming: &[(Key, usize)],
+        now: u64,
+    ) -> Result<Vec<(Key, Entry)>, StorageError> {
+        let sizes: Vec<_> = incoming
+            .iter()
+            .map(|(key, size)| (key.priority(), *size))
+            .collect();
+        let dropped = self.make_room(name, limits, &sizes)?;
+
+        let dropped = dropped
+            .into_iter()
+            .filter_map(|key| Some((key, self.remove(key)?)))
+            .collect();
+        for (key, size) in incoming {
+            self.push(*key, *size, now);
+        }
+
+        Ok(dropped)
+    }
+
+    pub(crate) fn unreserve(&mut self, incoming: &[(Key, usize)], dropped: Vec<(Key, Entry)>) {
+        for (key, _) in incoming {
+            self.remove(*key);
+        }
+        for (key, entry) in dropped {
+            self.insert(key, entry);
+        }
+    }
+
+    // every priority is a run of keys in push order, from the highest
+    // priority down
+    fn groups(&self) -> Vec<Range<'_, Key, Entry>> {
+        let mut groups = Vec::new();
+        let mut first = self.messages.keys().next();
+        while let Some(key) = first {
+            let priority = key.priority();
+            groups.push(
+                self.messages
+                    .range(key..=&Key::with_priority(priority, u64::MAX)),
+            );
+
+            first = match priority.checked_sub(1) {
+                Some(lower) => self.messages.range(Key::with_priority(lower, 0)..).next(),
+                None => None,
+            }
+            .map(|(key, _)| key);
+        }
+
+        groups
+    }
+
+    // merges the priorities back into a single push order
+    fn oldest_first(&self) -> impl Iterator<Item = (&Key, &Entry)> {
+        let mut groups: Vec<_> = self.groups().into_iter().map(Iterator::peekable).collect();
+        std::iter::from_fn(move || {
+            let next = groups
+                .iter_mut()
+                .filter_map(|group| Some(order(*group.peek()?)))
+                .enumerate()
+                .min_by_key(|(_, order)| *order)?;
+            groups[next.0].next()
+        })
+    }
+}
+
+impl Drop for Tally {
+    // a dropped queue gives its bytes back to the budget
+    fn drop(&mut self) {
+        self.budget.release(self.size_bytes);
+    }
+}
+
+pub(crate) const RECORD_LEN: usize = 12;
+
+// the key of the expired counter among the records, no message key is empty
+pub(crate) const EXPIRED: &[u8] = b"";
+
+// the size and push time of a message as persistent backends keep them
+pub(crate) fn record(size: usize, pushed_at: u64) -> [u8; RECORD_LEN] {
+    let mut bytes = [0; RECORD_LEN];
+    bytes[..4].copy_from_slice(&(size as u32).to_be_bytes());
+    bytes[4..].copy_from_slice(&pushed_at.to_be_bytes());
+    bytes
+}
+
+pub(crate) fn decode_record(bytes: &[u8]) -> Result<(usize, u64), StorageError> {
+    if bytes.len() != RECORD_LEN {
+        return Err(StorageError::Corruption(format!(
+            "invalid message record: {:?}",
+            bytes
+        )));
+    }
+
+    let size = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+    let pushed_at = u64::from_be_bytes(bytes[4..].try_into().unwrap());
+    Ok((size as usize, pushed_at))
+}
+
+pub(crate) fn decode_expired(bytes: &[u8]) -> Result<u64, StorageError> {
+    bytes
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| StorageError::Corruption(format!("invalid expired counter: {:?}", bytes)))
+}
+
+// push time first, offsets break ties between messages of the same
+// millisecond
+fn order((key, entry): (&Key, &Entry)) -> (u64, u64) {
+    (entry.pushed_at, key.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tally_tracks_depth_across_priorities() {
+        let mut tally = Tally::default();
+        tally.push(Key::with_priority(0, 0), 10, 100);
+        tally.push(Key::with_priority(2, 1), 20, 100);
+        tally.push(Key::with_priority(1, 2), 30, 150);
+        tally.push(Key::with_priority(0, 3), 40, 200);
+
+        let stats = tally.stats(300);
+        assert_eq!(stats.len, 4);
+        assert_eq!(stats.size_bytes, 100);
+        assert_eq!(stats.oldest, Some(Key::with_priority(0, 0)));
+        assert_eq!(stats.newest, Some(Key::with_priority(0, 3)));
+        assert_eq!(stats.oldest_age, Some(Duration::from_millis(200)));
+
+        tally.remove(Key::with_priority(0, 0));
+        tally.remove(Key::with_priority(0, 0));
+        let stats = tally.stats(300);
+        assert_eq!(stats.len, 3);
+        assert_eq!(stats.size_bytes, 90);
+        assert_eq!(stats.oldest, Some(Key::with_priority(2, 1)));
+
+        // everything of higher priority is delivered before (1, 2)
+        tally.remove_up_to(Key::with_priority(1, 2));
+        let stats = tally.stats(300);
+        assert_eq!(stats.len, 1);
+        assert_eq!(stats.size_bytes, 40);
+        assert_eq!(stats.oldest, stats.newest);
+
+        tally.remove_up_to(Key::with_priority(0, 3));
+        assert_eq!(tally.stats(300), QueueStats::default());
+    }
+
+    #[test]
+    fn tally_picks_messages_to_drop_by_policy() {
+        let mut tally = Tally::default();
+        tally.push(Key::with_priority(1, 0), 10, 100);
+        tally.push(Key::with_priority(0, 1), 10, 100);
+        tally.push(Key::with_priority(1, 2), 10, 100);
+        tally.push(Key::with_priority(0, 3), 10, 100);
+
+        let limits = |max_len, overflow| Limits {
+            max_len: Some(max_len),
+            max_bytes: Some(45),
+            overflow,
+            max_deliveries: None,
+        };
+
+        assert_eq!(
+            tally
+                .make_room("q", &limits(5, Overflow::RejectNew), &[(0, 1)])
+                .ok(),
+            Some(vec![])
+        );
+        assert_eq!(
+            tally
+                .make_room("q", &limits(4, Overflow::RejectNew), &[(0, 1)])
+                .ok(),
+            None
+        );
+        assert_eq!(
+            tally
+                .make_room("q", &limits(3, Overflow::DropOldest), &[(0, 1)])
+                .ok(),
+            Some(vec![Key::with_priority(1, 0), Key::with_priority(0, 1)])
+        );
+        // only the byte limit is hit
+        assert_eq!(
+            tally
+                .make_room("q", &limits(5, Overflow::DropLowestPriority), &[(1, 10)])
+                .ok(),
+            Some(vec![Key::with_priority(0, 1)])
+        );
+        assert_eq!(
+            tally
+                .make_room("q", &limits(2, Overflow::DropLowestPriority), &[(1, 1)])
+                .ok(),
+            Some(vec![
+                Key::with_priority(0, 1),
+                Key::with_priority(0, 3),
+                Key::with_priority(1, 0)
+            ])
+        );
+        // nothing of a lower priority than the push can be dropped
+        assert_eq!(
+            tally
+                .make_room("q", &limits(1, Overflow::DropLowestPriority), &[(0, 1)])
+                .ok(),
+            None
+        );
+        assert_eq!(
+            tally
+                .make_room("q", &limits(10, Overflow::DropOldest), &[(0, 46)])
+                .ok(),
+            None
+        );
+    }
+
+    #[test]
+    fn tallies_share_the_storage_budget() {
+        let budget = Arc::new(Budget::default());
+        budget.set_limit(Some(50));
+        let mut first = Tally::new(budget.clone());
+        let mut second = Tally::new(budget.clone());
+        first.push(Key::with_priority(0, 0), 20, 100);
+        second.push(Key::with_priority(0, 0), 20, 100);
+        assert_eq!(budget.used(), 40);
+
+        let unlimited = Limits::default();
+        assert!(second.make_room("q", &unlimited, &[(0, 10)]).is_ok());
+        assert!(matches!(
+            second.make_room("q", &unlimited, &[(0, 11)]),
+            Err(StorageError::Full)
+        ));
+
+        // room made by the overflow policy counts against the budget
+        let drop_oldest = Limits {
+            max_len: Some(1),
+            max_bytes: None,
+            overflow: Overflow::DropOldest,
+            max_deliveries: None,
+        };
+        assert_eq!(
+            second.make_room("q", &drop_oldest, &[(0, 30)]).ok(),
+            Some(vec![Key::with_priority(0, 0)])
+        );
+
+        first.remove(Key::with_priority(0, 0));
+        assert_eq!(budget.used(), 20);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+}
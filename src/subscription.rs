@@ -0,0 +1,319 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+};
+
+use crate::{topic, QoS, StorageError};
+
+// subscriptions of persistent sessions, restored when the storage opens. A
+// client's session queue is named by its client id, so routing a topic yields
+// the queues to push a publish into. Routing is answered by an in-memory trie
+// of all filters, only changes are written through
+pub trait SubscriptionStore {
+    // subscribing to a filter again replaces its options
+    fn add_subscription(
+        &self,
+        client_id: &str,
+        filter: &str,
+        options: SubscriptionOptions,
+    ) -> Result<(), StorageError>;
+    fn remove_subscription(&self, client_id: &str, filter: &str) -> Result<(), StorageError>;
+    fn remove_client(&self, client_id: &str) -> Result<(), StorageError>;
+    fn subscriptions(&self, client_id: &str) -> Vec<(String, SubscriptionOptions)>;
+
+    // every client with a matching subscription once, with the highest qos
+    // among its matching subscriptions, ordered by client id
+    fn route(&self, topic: &str) -> Vec<(String, QoS)>;
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SubscriptionOptions {
+    pub qos: QoS,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RetainHandling {
+    OnSubscribe,
+    OnNewSubscription,
+    Never,
+}
+
+impl Default for RetainHandling {
+    fn default() -> Self {
+        Self::OnSubscribe
+    }
+}
+
+// stored as the subscription options byte of a MQTT 5 SUBSCRIBE
+impl SubscriptionOptions {
+    pub(crate) fn to_byte(self) -> u8 {
+        let mut byte = self.qos as u8;
+        if self.no_local {
+            byte |= 1 << 2;
+        }
+        if self.retain_as_published {
+            byte |= 1 << 3;
+        }
+        byte | (self.retain_handling as u8) << 4
+    }
+}
+
+impl TryFrom<u8> for SubscriptionOptions {
+    type Error = StorageError;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        let invalid =
+            || StorageError::Corruption(format!("invalid subscription options: {}", byte));
+        let qos = match byte & 0b11 {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            _ => return Err(invalid()),
+        };
+        let retain_handling = match (byte >> 4) & 0b11 {
+            0 => RetainHandling::OnSubscribe,
+            1 => RetainHandling::OnNewSubscription,
+            2 => RetainHandling::Never,
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            qos,
+            no_local: byte & 1 << 2 != 0,
+            retain_as_published: byte & 1 << 3 != 0,
+            retain_handling,
+        })
+    }
+}
+
+// records are keyed by client id and filter separated by a zero byte, which
+// neither may contain, so the subscriptions of a client are a key prefix
+pub(crate) fn record_key(client_id: &str, filter: &str) -> Vec<u8> {
+    let mut key = client_prefix(client_id);
+    key.extend_from_slice(filter.as_bytes());
+    key
+}
+
+pub(crate) fn client_prefix(client_id: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(client_id.len() + 1);
+    prefix.extend_from_slice(client_id.as_bytes());
+    prefix.push(0);
+    prefix
+}
+
+pub(crate) fn decode(key: &[u8], value: &[u8]) -> Result<Subscription, StorageError> {
+    let invalid = || StorageError::Corruption(format!("invalid subscription: {:?}", key));
+    let split = key.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+    let client_id = String::from_utf8(key[..split].to_vec()).map_err(|_| invalid())?;
+    let filter = String::from_utf8(key[split + 1..].to_vec()).map_err(|_| invalid())?;
+    let options = match *value {
+        [byte] => SubscriptionOptions::try_from(byte)?,
+        _ => return Err(invalid()),
+    };
+
+    Ok((client_id, filter, options))
+}
+
+pub(crate) fn validate(client_id: &str, filter: &str) -> Result<(), StorageError> {
+    if client_id.contains('\0') {
+        return Err(StorageError::InvalidMessage(format!(
+            "client id contains a null character: {:?}",
+            client_id
+        )));
+    }
+    topic::validate(filter)
+}
+
+pub(crate) type Subscription = (String, String, SubscriptionOptions);
+
+// all subscriptions by client and as a trie of filter levels for routing
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions {
+    clients: HashMap<String, BTreeMap<String, SubscriptionOptions>>,
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    clients: HashMap<String, SubscriptionOptions>,
+}
+
+impl Subscriptions {
+    pub(crate) fn add(&mut self, client_id: &str, filter: &str, options: SubscriptionOptions) {
+        let mut node = &mut self.root;
+        for level in filter.split('/') {
+            node = node.children.entry(level.into()).or_default();
+        }
+        node.clients.insert(client_id.into(), options);
+
+        self.clients
+            .entry(client_id.into())
+            .or_default()
+            .insert(filter.into(), options);
+    }
+
+    pub(crate) fn remove(&mut self, client_id: &str, filter: &str) {
+        let levels: Vec<_> = filter.split('/').collect();
+        self.root.remove(client_id, &levels);
+
+        if let Some(filters) = self.clients.get_mut(client_id) {
+            filters.remove(filter);
+            if filters.is_empty() {
+                self.clients.remove(client_id);
+            }
+        }
+    }
+
+    pub(crate) fn remove_client(&mut self, client_id: &str) {
+        for filter in self.filters(client_id) {
+            self.remove(client_id, &filter);
+        }
+    }
+
+    pub(crate) fn filters(&self, client_id: &str) -> Vec<String> {
+        self.clients
+            .get(client_id)
+            .map(|filters| filters.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // subscriptions of all clients by client id and filter
+    pub(crate) fn all(&self) -> Vec<Subscription> {
+        let mut all: Vec<_> = self
+            .clients
+            .iter()
+            .flat_map(|(client_id, filters)| {
+                filters
+                    .iter()
+                    .map(move |(filter, options)| (client_id.clone(), filter.clone(), *options))
+            })
+            .collect();
+        all.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        all
+    }
+
+    pub(crate) fn of(&self, client_id: &str) -> Vec<(String, SubscriptionOptions)> {
+        self.clients
+            .get(client_id)
+            .map(|filters| filters.iter().map(|(f, o)| (f.clone(), *o)).collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn route(&self, topic: &str) -> Vec<(String, QoS)> {
+        let levels: Vec<_> = topic.split('/').collect();
+        let mut found = BTreeMap::new();
+        self.root.route(&levels, topic.starts_with('$'), &mut found);
+
+        found
+            .into_iter()
+            .map(|(client_id, qos)| (client_id.to_owned(), qos))
+            .collect()
+    }
+}
+
+impl Node {
+    // empty branches are pruned on the way back up
+    fn remove(&mut self, client_id: &str, levels: &[&str]) -> bool {
+        match levels.split_first() {
+            None => {
+                self.clients.remove(client_id);
+            }
+            Some((level, rest)) => {
+                if let Some(child) = self.children.get_mut(*level) {
+                    if child.remove(client_id, rest) {
+                        self.children.remove(*level);
+                    }
+                }
+            }
+        }
+
+        self.clients.is_empty() && self.children.is_empty()
+    }
+
+    // wildcards at the first level never match `$` topics, `#` matches the
+    // parent level as well
+    fn route<'a>(&'a self, levels: &[&str], dollar: bool, found: &mut BTreeMap<&'a str, QoS>) {
+        if !dollar {
+            if let Some(all) = self.children.get("#") {
+                all.collect(found);
+            }
+        }
+
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => return self.collect(found),
+        };
+        if !dollar {
+            if let Some(any) = self.children.get("+") {
+                any.route(rest, false, found);
+            }
+        }
+        if let Some(child) = self.children.get(*level) {
+            child.route(rest, false, found);
+        }
+    }
+
+    fn collect<'a>(&'a self, found: &mut BTreeMap<&'a str, QoS>) {
+        for (client_id, options) in &self.clients {
+            let qos = found.entry(client_id).or_insert(options.qos);
+            *qos = (*qos).max(options.qos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_route_to_matching_clients() {
+        let qos = |qos| SubscriptionOptions {
+            qos,
+            ..SubscriptionOptions::default()
+        };
+
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.add("a", "devices/+/battery", qos(QoS::AtLeastOnce));
+        subscriptions.add("a", "devices/#", qos(QoS::AtMostOnce));
+        subscriptions.add("b", "devices/7/battery", qos(QoS::ExactlyOnce));
+        subscriptions.add("c", "#", qos(QoS::AtMostOnce));
+        subscriptions.add("d", "$SYS/#", qos(QoS::AtMostOnce));
+
+        assert_eq!(
+            subscriptions.route("devices/7/battery"),
+            [
+                ("a".to_string(), QoS::AtLeastOnce),
+                ("b".to_string(), QoS::ExactlyOnce),
+                ("c".to_string(), QoS::AtMostOnce),
+            ]
+        );
+        assert_eq!(subscriptions.route("devices").len(), 2);
+        assert_eq!(
+            subscriptions.route("$SYS/uptime"),
+            [("d".to_string(), QoS::AtMostOnce)]
+        );
+
+        subscriptions.remove_client("a");
+        subscriptions.remove("c", "#");
+        assert_eq!(
+            subscriptions.route("devices/7/battery"),
+            [("b".to_string(), QoS::ExactlyOnce)]
+        );
+        assert!(subscriptions.of("a").is_empty());
+        assert_eq!(subscriptions.root.children.len(), 2);
+
+        let options = SubscriptionOptions {
+            qos: QoS::ExactlyOnce,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: RetainHandling::Never,
+        };
+        let key = record_key("b", "devices/+");
+        let decoded = decode(&key, &[options.to_byte()]).unwrap();
+        assert_eq!(decoded, ("b".into(), "devices/+".into(), options));
+    }
+}
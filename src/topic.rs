@@ -0,0 +1,93 @@
+use crate::StorageError;
+
+const WILDCARDS: [char; 2] = ['+', '#'];
+
+// the part of the filter before the first wildcard, every matching topic
+// starts with it; backends ordered by topic only scan that range and still
+// test what they find with `matches`. A trailing `#` matches its parent
+// level too, so the separator before it is not part of the prefix
+pub(crate) fn literal_prefix(filter: &str) -> &str {
+    let end = filter.find(WILDCARDS).unwrap_or(filter.len());
+    let prefix = &filter[..end];
+    if filter[end..] == *"#" {
+        prefix.strip_suffix('/').unwrap_or(prefix)
+    } else {
+        prefix
+    }
+}
+
+// topics messages are published to, unlike filters, carry no wildcards
+pub(crate) fn validate_name(topic: &str) -> Result<(), StorageError> {
+    if topic.is_empty() || topic.contains('\0') || topic.contains(WILDCARDS) {
+        return Err(StorageError::InvalidTopic(topic.into()));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate(filter: &str) -> Result<(), StorageError> {
+    let invalid = || Err(StorageError::InvalidTopic(filter.into()));
+    if filter.is_empty() || filter.contains('\0') {
+        return invalid();
+    }
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let wildcard = level.contains(WILDCARDS);
+        let last = levels.peek().is_none();
+        if wildcard && level != "+" && !(level == "#" && last) {
+            return invalid();
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(WILDCARDS) {
+        return false;
+    }
+
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(part)) if level == part => {}
+            _ => return false,
+        }
+    }
+
+    topic.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_match_like_mqtt() {
+        assert!(matches("a/+/c", "a/b/c"));
+        assert!(!matches("a/+/c", "a/b/d/c"));
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(matches("#", "a/b"));
+        assert!(!matches("#", "$SYS/uptime"));
+        assert!(matches("$SYS/#", "$SYS/uptime"));
+        assert!(matches("+/+", "/a"));
+        assert!(!matches("a/b", "a/b/c"));
+
+        assert!(validate("a/+/#").is_ok());
+        assert!(validate("a/#/b").is_err());
+        assert!(validate("a/b+").is_err());
+        assert!(validate("").is_err());
+        assert_eq!(literal_prefix("a/b/+/c"), "a/b/");
+        assert_eq!(literal_prefix("a/b/#"), "a/b");
+        assert_eq!(literal_prefix("#"), "");
+
+        assert!(validate_name("a/b").is_ok());
+        assert!(validate_name("a/+").is_err());
+        assert!(validate_name("a/#").is_err());
+        assert!(validate_name("").is_err());
+    }
+}